@@ -3,13 +3,97 @@
 // SPDX-License-Identifier: MIT
 
 use core::net;
-
-use thiserror;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 /// Describes allocated node.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
 pub struct Node {
-    pub addr: net::Ipv4Addr,
+    /// Name of the instance.
+    pub name: String,
+    /// Spread system.
+    pub system: String,
+    pub addr: net::IpAddr,
     pub ssh_port: u32,
+    /// Start of the lease, RFC 3339. Unknown for nodes allocated by older
+    /// versions.
+    pub allocated_at: Option<String>,
+}
+
+impl Node {
+    /// Returns the SSH address in the form expected by spread, ie.
+    /// <ipv4>:<port> or [<ipv6>]:<port>.
+    pub fn ssh_address(&self) -> String {
+        match self.addr {
+            net::IpAddr::V4(addr) => format!("{}:{}", addr, self.ssh_port),
+            net::IpAddr::V6(addr) => format!("[{}]:{}", addr, self.ssh_port),
+        }
+    }
+}
+
+/// Reference to a node, as given by the user or spread.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeRef {
+    /// Address, given as <ip>, <ipv4>:<port> or [<ipv6>]:<port>. The port
+    /// tells apart nodes reached through the same host address.
+    Addr(net::IpAddr, Option<u16>),
+    /// Instance name, optionally qualified with a remote, ie. <remote>:<name>.
+    Name(String),
+}
+
+impl FromStr for NodeRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<net::IpAddr>() {
+            return Ok(NodeRef::Addr(addr, None));
+        }
+        if let Ok(addr) = s.parse::<net::SocketAddr>() {
+            return Ok(NodeRef::Addr(addr.ip(), Some(addr.port())));
+        }
+        if s.starts_with('[') {
+            return Err(format!(
+                "invalid address \"{}\", expected [<ipv6>]:<port>",
+                s
+            ));
+        }
+
+        let (remote, name) = match s.split_once(':') {
+            Some((remote, name)) => (Some(remote), name),
+            None => (None, s),
+        };
+        let valid_name = |n: &str| {
+            !n.is_empty()
+                && n.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && n.starts_with(|c: char| c.is_ascii_alphabetic())
+        };
+        // an address with an invalid port is not a remote
+        let valid_remote = |r: &str| {
+            !r.is_empty()
+                && r.parse::<net::IpAddr>().is_err()
+                && r.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        };
+        if !valid_name(name) || !remote.is_none_or(valid_remote) {
+            return Err(format!(
+                "invalid node \"{}\", expected an instance name, <ip>, <ipv4>:<port> or [<ipv6>]:<port>",
+                s
+            ));
+        }
+        Ok(NodeRef::Name(s.to_string()))
+    }
+}
+
+impl fmt::Display for NodeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeRef::Addr(addr, None) => write!(f, "{}", addr),
+            NodeRef::Addr(addr, Some(port)) => write!(f, "{}", net::SocketAddr::new(*addr, *port)),
+            NodeRef::Name(name) => write!(f, "{}", name),
+        }
+    }
 }
 
 /// Carries details for confugration of remote user access.
@@ -18,14 +102,57 @@ pub struct RemoteUserAccessConfig<'a> {
     pub password: &'a str,
 }
 
-#[derive(thiserror::Error, Debug)]
+/// Describes a node which is currently allocated.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct NodeInfo {
+    /// Name of the instance.
+    pub name: String,
+    /// Details of the node, if it is known to the allocator.
+    pub node: Option<Node>,
+    /// Number of CPUs assigned to the node.
+    pub cpu: u32,
+    /// Memory assigned to the node, in bytes.
+    pub memory: u64,
+}
+
+#[derive(thiserror::Error, serde::Serialize, serde::Deserialize, Debug)]
+#[serde(tag = "kind", content = "message", rename_all = "kebab-case")]
 pub enum Error {
     #[error("cannot execute operation: {0}")]
     Operation(String),
+    /// Configuration is missing or invalid.
+    #[error("{0}")]
+    Config(String),
+    /// Backend is unreachable or failed to carry out the operation.
+    #[error("{0}")]
+    Backend(String),
+    /// Operation did not complete in time.
+    #[error("{0}")]
+    Timeout(String),
+    /// Resources needed by the node are not available.
+    #[error("{0}")]
+    Capacity(String),
+    /// System or node was not found.
     #[error("{0}")]
     NotFound(String),
 }
 
+impl Error {
+    /// Short name of the kind of error, suitable for use in logs and
+    /// metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Operation(_) => "operation",
+            Error::Config(_) => "config",
+            Error::Backend(_) => "backend",
+            Error::Timeout(_) => "timeout",
+            Error::Capacity(_) => "capacity",
+            Error::NotFound(_) => "not-found",
+        }
+    }
+}
+
 pub trait NodeAllocator {
     /// Allocate a node using a system name.
     fn allocate_by_name(
@@ -33,8 +160,100 @@ pub trait NodeAllocator {
         name: &str,
         user_config: RemoteUserAccessConfig,
     ) -> Result<Node, Error>;
-    /// Discard a node with given address.
-    fn discard_by_addr(&mut self, addr: &str) -> Result<(), Error>;
+    /// Discard a node with given address, optionally with the SSH port, in
+    /// any of the forms accepted by NodeRef. Returns the details of the node
+    /// if it was known to the allocator.
+    fn discard_by_addr(&mut self, addr: &str) -> Result<Option<Node>, Error>;
+    /// Discard a node with given instance name. Returns the details of the
+    /// node if it was known to the allocator.
+    fn discard_by_name(&mut self, name: &str) -> Result<Option<Node>, Error>;
     /// Discard all nodes.
     fn discard_all(&mut self) -> Result<(), Error>;
+    /// Collect diagnostics from a node into a local directory, which is
+    /// returned.
+    fn collect_diagnostics(&mut self, node: &NodeRef) -> Result<PathBuf, Error>;
+    /// List allocated nodes.
+    fn list(&mut self) -> Result<Vec<NodeInfo>, Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_ref() {
+        for (s, expected) in [
+            (
+                "10.22.100.124",
+                NodeRef::Addr("10.22.100.124".parse().unwrap(), None),
+            ),
+            (
+                "10.22.100.124:22",
+                NodeRef::Addr("10.22.100.124".parse().unwrap(), Some(22)),
+            ),
+            ("fd42::1", NodeRef::Addr("fd42::1".parse().unwrap(), None)),
+            (
+                "[fd42::1]:20022",
+                NodeRef::Addr("fd42::1".parse().unwrap(), Some(20022)),
+            ),
+            (
+                "ubuntu-24-04-64-1744396627",
+                NodeRef::Name("ubuntu-24-04-64-1744396627".to_string()),
+            ),
+            (
+                "cluster:ubuntu-24-04-64-1744396627",
+                NodeRef::Name("cluster:ubuntu-24-04-64-1744396627".to_string()),
+            ),
+        ] {
+            assert_eq!(expected.to_string(), s);
+            assert_eq!(s.parse::<NodeRef>(), Ok(expected), "{}", s);
+        }
+
+        for s in [
+            "",
+            "[fd42::1]",
+            "[fd42::1]:port",
+            "10.22.100.124:port",
+            "10.22.100.124:22:22",
+            "1node",
+            "ubuntu_24",
+            ":ubuntu",
+            "remote:",
+        ] {
+            assert!(s.parse::<NodeRef>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_node_json() {
+        let node = Node {
+            name: "ubuntu-24-04-64-1744396627".to_string(),
+            system: "ubuntu-24.04-64".to_string(),
+            addr: net::Ipv4Addr::new(10, 22, 100, 124).into(),
+            ssh_port: 22,
+            allocated_at: Some("2025-01-12T16:56:01.093Z".to_string()),
+        };
+        assert_eq!(
+            serde_json::to_string(&node).expect("cannot serialize"),
+            r#"{"name":"ubuntu-24-04-64-1744396627","system":"ubuntu-24.04-64","addr":"10.22.100.124","ssh-port":22,"allocated-at":"2025-01-12T16:56:01.093Z"}"#
+        );
+    }
+
+    #[test]
+    fn test_node_ssh_address() {
+        let mut node = Node {
+            name: "ubuntu-24-04-64-1744396627".to_string(),
+            system: "ubuntu-24.04-64".to_string(),
+            addr: net::Ipv4Addr::new(10, 22, 100, 124).into(),
+            ssh_port: 22,
+            allocated_at: None,
+        };
+        assert_eq!(node.ssh_address(), "10.22.100.124:22");
+        node.addr = "fd42:2245:81ae:90da::1".parse().unwrap();
+        assert_eq!(node.ssh_address(), "[fd42:2245:81ae:90da::1]:22");
+        assert_eq!(
+            node.ssh_address().parse::<NodeRef>(),
+            Ok(NodeRef::Addr(node.addr, Some(22)))
+        );
+    }
 }
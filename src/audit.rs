@@ -0,0 +1,390 @@
+// SPDX-FileCopyrightText: 2025 Maciej Borzecki <maciek.borzecki@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::allocator;
+use crate::logging;
+
+/// Operation described by an event.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    Allocate,
+    Discard,
+    Cleanup,
+    /// Deletion of a node kept for post-mortem, beyond the retention limit.
+    Reap,
+}
+
+/// Outcome of an operation.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// Duration of a phase of an operation.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Phase {
+    pub name: String,
+    pub duration_ms: u64,
+}
+
+/// Record of an operation on nodes.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Event {
+    pub operation: Operation,
+    /// Start time, RFC 3339.
+    pub started: String,
+    /// Completion time, RFC 3339.
+    pub finished: String,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub phases: Vec<Phase>,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Kind of the error, see allocator::Error::kind().
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+}
+
+impl Event {
+    /// Creates an event for an operation which started at a given time and
+    /// completed now with a given result.
+    pub fn new<T>(
+        operation: Operation,
+        started: SystemTime,
+        res: &Result<T, allocator::Error>,
+    ) -> Self {
+        let finished = SystemTime::now();
+        Event {
+            operation,
+            started: logging::timestamp(started),
+            finished: logging::timestamp(finished),
+            duration_ms: millis(finished.duration_since(started).unwrap_or_default()),
+            system: None,
+            instance: None,
+            address: None,
+            phases: vec![],
+            outcome: if res.is_ok() {
+                Outcome::Success
+            } else {
+                Outcome::Failure
+            },
+            error: res.as_ref().err().map(|e| e.to_string()),
+            error_kind: res.as_ref().err().map(|e| e.kind().to_string()),
+        }
+    }
+
+    pub fn with_system(mut self, system: &str) -> Self {
+        self.system = Some(system.to_string());
+        self
+    }
+
+    pub fn with_instance(mut self, instance: Option<&str>) -> Self {
+        self.instance = instance.map(str::to_string);
+        self
+    }
+
+    pub fn with_address(mut self, address: Option<&str>) -> Self {
+        self.address = address.map(str::to_string);
+        self
+    }
+
+    pub fn with_phases(mut self, phases: &[(String, Duration)]) -> Self {
+        self.phases = phases
+            .iter()
+            .map(|(name, duration)| Phase {
+                name: name.clone(),
+                duration_ms: millis(*duration),
+            })
+            .collect();
+        self
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Destination of events.
+pub trait EventSink: Send {
+    /// Record an event.
+    fn record(&mut self, event: &Event);
+}
+
+/// Sink which drops all events.
+pub struct NullSink;
+
+impl EventSink for NullSink {
+    fn record(&mut self, _event: &Event) {}
+}
+
+/// Sink appending events to a file, one JSON object per line.
+pub struct JsonlSink {
+    path: PathBuf,
+}
+
+impl JsonlSink {
+    pub fn new(path: &Path) -> Self {
+        JsonlSink {
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn append(&self, event: &Event) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        // a single write to a file opened for appending, such that lines from
+        // concurrent writers do not interleave
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)
+    }
+}
+
+impl EventSink for JsonlSink {
+    fn record(&mut self, event: &Event) {
+        if let Err(err) = self.append(event) {
+            log::warn!(
+                "cannot write event to audit log {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Name of the audit log file in the state directory.
+pub const AUDIT_LOG_FILE_NAME: &str = "audit.jsonl";
+
+/// Read events from an audit log. Lines which cannot be parsed are skipped.
+pub fn read_events(path: &Path) -> io::Result<Vec<Event>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    Ok(data
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(i, line)| {
+            serde_json::from_str(line)
+                .inspect_err(|err| {
+                    log::warn!("skipping line {} of {}: {}", i + 1, path.display(), err)
+                })
+                .ok()
+        })
+        .collect())
+}
+
+/// Percentiles of durations, in milliseconds.
+#[derive(Debug, PartialEq)]
+pub struct Percentiles {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Percentiles {
+    /// Compute percentiles using the nearest rank method. Returns None when
+    /// there are no samples.
+    fn from_samples(mut samples: Vec<u64>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let rank = |p: usize| samples[(p * samples.len()).div_ceil(100).max(1) - 1];
+        Some(Percentiles {
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: samples[samples.len() - 1],
+        })
+    }
+}
+
+/// Summary of allocation times of a system.
+#[derive(Debug, PartialEq)]
+pub struct SystemStats {
+    pub system: String,
+    /// Number of successful allocations.
+    pub allocations: usize,
+    /// Number of failed allocations.
+    pub failures: usize,
+    /// Duration of the whole allocation.
+    pub total: Percentiles,
+    /// Duration of each phase, in order of appearance.
+    pub phases: Vec<(String, Percentiles)>,
+}
+
+/// Summarize the times of successful allocations per system. Systems are
+/// sorted by name, those with no successful allocations are omitted.
+pub fn allocation_stats(events: &[Event]) -> Vec<SystemStats> {
+    let mut systems: Vec<&str> = events
+        .iter()
+        .filter(|e| e.operation == Operation::Allocate)
+        .filter_map(|e| e.system.as_deref())
+        .collect();
+    systems.sort_unstable();
+    systems.dedup();
+
+    systems
+        .into_iter()
+        .filter_map(|system| {
+            let (ok, failed): (Vec<&Event>, Vec<&Event>) = events
+                .iter()
+                .filter(|e| {
+                    e.operation == Operation::Allocate && e.system.as_deref() == Some(system)
+                })
+                .partition(|e| e.outcome == Outcome::Success);
+
+            let mut phases: Vec<(String, Vec<u64>)> = vec![];
+            for phase in ok.iter().flat_map(|e| e.phases.iter()) {
+                match phases.iter_mut().find(|(name, _)| *name == phase.name) {
+                    Some((_, samples)) => samples.push(phase.duration_ms),
+                    None => phases.push((phase.name.clone(), vec![phase.duration_ms])),
+                }
+            }
+
+            Some(SystemStats {
+                system: system.to_string(),
+                allocations: ok.len(),
+                failures: failed.len(),
+                total: Percentiles::from_samples(ok.iter().map(|e| e.duration_ms).collect())?,
+                phases: phases
+                    .into_iter()
+                    .filter_map(|(name, samples)| {
+                        Percentiles::from_samples(samples).map(|p| (name, p))
+                    })
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_json() {
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(1736700938);
+        let mut event = Event::new::<()>(
+            Operation::Allocate,
+            started,
+            &Err(allocator::Error::Operation("boom".to_string())),
+        )
+        .with_system("ubuntu-24.04-64")
+        .with_instance(Some("ubuntu-24-04-64-1744396627"))
+        .with_phases(&[("launch".to_string(), Duration::from_millis(1500))]);
+        // fix up the times which depend on the current time
+        event.finished = "2025-01-12T16:55:40.000Z".to_string();
+        event.duration_ms = 2000;
+
+        assert_eq!(
+            serde_json::to_string(&event).expect("cannot serialize"),
+            r#"{"operation":"allocate","started":"2025-01-12T16:55:38.000Z","finished":"2025-01-12T16:55:40.000Z","duration-ms":2000,"system":"ubuntu-24.04-64","instance":"ubuntu-24-04-64-1744396627","phases":[{"name":"launch","duration-ms":1500}],"outcome":"failure","error":"cannot execute operation: boom","error-kind":"operation"}"#
+        );
+    }
+
+    fn allocation(system: &str, outcome: Outcome, duration_ms: u64, launch_ms: u64) -> Event {
+        Event {
+            operation: Operation::Allocate,
+            started: "2025-01-12T16:55:38.000Z".to_string(),
+            finished: "2025-01-12T16:55:40.000Z".to_string(),
+            duration_ms,
+            system: Some(system.to_string()),
+            instance: None,
+            address: None,
+            phases: vec![Phase {
+                name: "launch".to_string(),
+                duration_ms: launch_ms,
+            }],
+            outcome,
+            error: None,
+            error_kind: None,
+        }
+    }
+
+    #[test]
+    fn test_allocation_stats() {
+        let mut events: Vec<Event> = (1..=10)
+            .map(|i| allocation("ubuntu", Outcome::Success, i * 1000, i * 100))
+            .collect();
+        events.push(allocation("ubuntu", Outcome::Failure, 60000, 0));
+        events.push(allocation("fedora", Outcome::Failure, 1000, 0));
+        events.push(allocation("arch", Outcome::Success, 5000, 2000));
+
+        let stats = allocation_stats(&events);
+        assert_eq!(
+            stats,
+            vec![
+                SystemStats {
+                    system: "arch".to_string(),
+                    allocations: 1,
+                    failures: 0,
+                    total: Percentiles {
+                        p50: 5000,
+                        p90: 5000,
+                        p99: 5000,
+                        max: 5000
+                    },
+                    phases: vec![(
+                        "launch".to_string(),
+                        Percentiles {
+                            p50: 2000,
+                            p90: 2000,
+                            p99: 2000,
+                            max: 2000
+                        }
+                    )],
+                },
+                SystemStats {
+                    system: "ubuntu".to_string(),
+                    allocations: 10,
+                    failures: 1,
+                    total: Percentiles {
+                        p50: 5000,
+                        p90: 9000,
+                        p99: 10000,
+                        max: 10000
+                    },
+                    phases: vec![(
+                        "launch".to_string(),
+                        Percentiles {
+                            p50: 500,
+                            p90: 900,
+                            p99: 1000,
+                            max: 1000
+                        }
+                    )],
+                },
+            ]
+        );
+    }
+}
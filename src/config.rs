@@ -7,15 +7,12 @@ use std::fs;
 use std::io::Error;
 use std::path::{Path, PathBuf};
 
-use directories;
-use log;
-
-const SPREAD_CONF_NAME: &'static str = "spread.yaml";
+const SPREAD_CONF_NAME: &str = "spread.yaml";
 
 /// Locates the configuration file with a given which is assumed to exist in the
 /// same directory as spread.yaml.
 pub fn locate(name: &str) -> Result<PathBuf, Error> {
-    let start_dir = &env::current_dir().and_then(|p| fs::canonicalize(p))?;
+    let start_dir = &env::current_dir().and_then(fs::canonicalize)?;
     let mut dir = Some(Path::new(start_dir));
 
     while let Some(curdir) = dir {
@@ -38,14 +35,20 @@ pub fn locate(name: &str) -> Result<PathBuf, Error> {
             dir = curdir.parent();
         }
     }
-    return Err(Error::other(format!("cannot find {SPREAD_CONF_NAME}")));
+    Err(Error::other(format!("cannot find {SPREAD_CONF_NAME}")))
 }
 
 /// Returns path to user configuration.
 pub fn user_config() -> Option<PathBuf> {
-    if let Some(d) = directories::ProjectDirs::from("", "", "spread-adhoc-allocator") {
-        Some(d.config_dir().to_path_buf().join("config.yaml"))
-    } else {
-        None
-    }
+    directories::ProjectDirs::from("", "", "spread-adhoc-allocator")
+        .map(|d| d.config_dir().to_path_buf().join("config.yaml"))
+}
+
+/// Returns path to the directory for keeping state.
+pub fn state_dir() -> Option<PathBuf> {
+    directories::ProjectDirs::from("", "", "spread-adhoc-allocator").map(|d| {
+        d.state_dir()
+            .unwrap_or_else(|| d.data_local_dir())
+            .to_path_buf()
+    })
 }
@@ -0,0 +1,978 @@
+// SPDX-FileCopyrightText: 2025 Maciej Borzecki <maciek.borzecki@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+use crate::allocator::{self, Node, NodeAllocator, NodeInfo, NodeRef, RemoteUserAccessConfig};
+
+/// Name of the daemon socket in the state directory.
+pub const SOCKET_FILE_NAME: &str = "daemon.sock";
+
+/// Request sent to the daemon, one JSON object per line.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Allocate {
+        system: String,
+        user: String,
+        password: String,
+        /// Backend configuration file.
+        #[serde(default)]
+        config: Option<PathBuf>,
+    },
+    Discard {
+        addr: String,
+        /// Post-mortem mode requested by the client.
+        #[serde(default)]
+        postmortem: Option<String>,
+    },
+    DiscardByName {
+        name: String,
+        /// Post-mortem mode requested by the client.
+        #[serde(default)]
+        postmortem: Option<String>,
+    },
+    List,
+    Cleanup,
+    /// Collect diagnostics from a node, given in any of the forms accepted by
+    /// NodeRef.
+    Collect {
+        node: String,
+        /// Backend configuration file.
+        #[serde(default)]
+        config: Option<PathBuf>,
+    },
+}
+
+/// Response of the daemon, one JSON object per line.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    Allocated(Node),
+    Discarded(Option<Node>),
+    Nodes(Vec<NodeInfo>),
+    CleanedUp,
+    Collected(PathBuf),
+    Error(allocator::Error),
+}
+
+/// Admits requests in the order of arrival, with an optional limit of how
+/// many are handled at once.
+struct Queue {
+    limit: Option<usize>,
+    state: Mutex<QueueState>,
+    cv: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Next ticket to hand out.
+    next: u64,
+    /// Ticket which is admitted next.
+    serving: u64,
+    /// Number of admitted requests.
+    active: usize,
+}
+
+struct QueueGuard<'a> {
+    queue: &'a Queue,
+}
+
+impl Queue {
+    fn new(limit: Option<usize>) -> Self {
+        Queue {
+            limit,
+            state: Mutex::new(Default::default()),
+            cv: Condvar::new(),
+        }
+    }
+
+    /// Wait for a turn. The request is handled until the guard is dropped.
+    fn enter(&self) -> QueueGuard<'_> {
+        let mut st = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let ticket = st.next;
+        st.next += 1;
+        if ticket != st.serving {
+            log::info!("request queued behind {} others", ticket - st.serving);
+        }
+
+        let mut st = self
+            .cv
+            .wait_while(st, |st| {
+                st.serving != ticket || self.limit.is_some_and(|limit| st.active >= limit)
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        st.serving += 1;
+        st.active += 1;
+        // the next request may be admitted too if the limit allows
+        self.cv.notify_all();
+        QueueGuard { queue: self }
+    }
+}
+
+impl Drop for QueueGuard<'_> {
+    fn drop(&mut self) {
+        let mut st = self.queue.state.lock().unwrap_or_else(|e| e.into_inner());
+        st.active -= 1;
+        self.queue.cv.notify_all();
+    }
+}
+
+struct CachedConfig {
+    modified: SystemTime,
+    data: Arc<Vec<u8>>,
+}
+
+/// Contents of configuration files, reloaded when modified.
+#[derive(Default)]
+struct ConfigCache {
+    entries: Mutex<HashMap<PathBuf, CachedConfig>>,
+}
+
+impl ConfigCache {
+    fn get(&self, path: &Path) -> io::Result<Arc<Vec<u8>>> {
+        let modified = fs::metadata(path)?.modified()?;
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cached) = entries.get(path) {
+            if cached.modified == modified {
+                return Ok(cached.data.clone());
+            }
+        }
+
+        log::debug!("loading config from {}", path.display());
+        let data = Arc::new(fs::read(path)?);
+        entries.insert(
+            path.to_path_buf(),
+            CachedConfig {
+                modified,
+                data: data.clone(),
+            },
+        );
+        Ok(data)
+    }
+}
+
+/// Path and contents of a configuration file.
+type LoadedConfig = (PathBuf, Arc<Vec<u8>>);
+
+/// Modification times of watched files.
+type Stamps = Vec<Option<SystemTime>>;
+
+struct IdleAllocator {
+    /// Contents of the configuration file the allocator was obtained with.
+    config: Option<Arc<Vec<u8>>>,
+    stamps: Stamps,
+    allocator: Box<dyn NodeAllocator + Send>,
+}
+
+/// Path of the configuration file and post-mortem mode an allocator was
+/// obtained with.
+type AllocatorKey = (Option<PathBuf>, Option<String>);
+
+/// Allocators kept between requests, such that state is not rebuilt for each
+/// request. An allocator is only reused as long as neither its configuration
+/// file nor any of the watched files were modified.
+struct AllocatorPool {
+    watched: Vec<PathBuf>,
+    idle: Mutex<HashMap<AllocatorKey, Vec<IdleAllocator>>>,
+}
+
+impl AllocatorPool {
+    fn new(watched: Vec<PathBuf>) -> Self {
+        AllocatorPool {
+            watched,
+            idle: Default::default(),
+        }
+    }
+
+    fn stamps(&self) -> Stamps {
+        self.watched
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    /// Take an idle allocator matching the configuration, dropping any which
+    /// are out of date.
+    fn take(
+        &self,
+        config: Option<&LoadedConfig>,
+        postmortem: Option<&str>,
+        stamps: &Stamps,
+    ) -> Option<Box<dyn NodeAllocator + Send>> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        for allocators in idle.values_mut() {
+            allocators.retain(|a| a.stamps == *stamps);
+        }
+        let key = (
+            config.map(|(path, _)| path.clone()),
+            postmortem.map(str::to_string),
+        );
+        let allocators = idle.get_mut(&key)?;
+        allocators.retain(|a| match (&a.config, config) {
+            (Some(data), Some((_, current))) => Arc::ptr_eq(data, current),
+            (None, None) => true,
+            _ => false,
+        });
+        allocators.pop().map(|a| a.allocator)
+    }
+
+    fn put(
+        &self,
+        config: Option<LoadedConfig>,
+        postmortem: Option<&str>,
+        stamps: Stamps,
+        allocator: Box<dyn NodeAllocator + Send>,
+    ) {
+        let (path, config) = config.map_or((None, None), |(p, c)| (Some(p), Some(c)));
+        self.idle
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry((path, postmortem.map(str::to_string)))
+            .or_default()
+            .push(IdleAllocator {
+                config,
+                stamps,
+                allocator,
+            });
+    }
+}
+
+struct Daemon<F> {
+    new_allocator: F,
+    queue: Queue,
+    configs: ConfigCache,
+    pool: AllocatorPool,
+}
+
+impl<F> Daemon<F>
+where
+    F: Fn(
+        Option<(&Path, &[u8])>,
+        Option<&str>,
+    ) -> Result<Box<dyn NodeAllocator + Send>, allocator::Error>,
+{
+    /// Returns the path and contents of a configuration file.
+    fn load_config(
+        &self,
+        config: Option<PathBuf>,
+    ) -> Result<Option<LoadedConfig>, allocator::Error> {
+        config
+            .map(|path| {
+                self.configs
+                    .get(&path)
+                    .map(|data| (path.clone(), data))
+                    .map_err(|err| {
+                        allocator::Error::Config(format!(
+                            "cannot load config file {}: {}",
+                            path.display(),
+                            err
+                        ))
+                    })
+            })
+            .transpose()
+    }
+
+    /// Run an operation with an idle allocator, or a new one if none can be
+    /// reused, optionally with the post-mortem mode requested by the client.
+    /// The allocator is kept for later requests unless the operation failed.
+    fn with_allocator<T>(
+        &self,
+        config: Option<LoadedConfig>,
+        postmortem: Option<&str>,
+        op: impl FnOnce(&mut dyn NodeAllocator) -> Result<T, allocator::Error>,
+    ) -> Result<T, allocator::Error> {
+        let stamps = self.pool.stamps();
+        let mut allocator = match self.pool.take(config.as_ref(), postmortem, &stamps) {
+            Some(allocator) => allocator,
+            None => {
+                log::debug!("obtaining new allocator");
+                (self.new_allocator)(
+                    config.as_ref().map(|(p, c)| (p.as_path(), c.as_slice())),
+                    postmortem,
+                )?
+            }
+        };
+        let res = op(allocator.as_mut());
+        if res.is_ok() {
+            self.pool.put(config, postmortem, stamps, allocator);
+        }
+        res
+    }
+
+    fn handle(&self, req: Request) -> Result<Response, allocator::Error> {
+        match req {
+            Request::Allocate {
+                system,
+                user,
+                password,
+                config,
+            } => {
+                let _turn = self.queue.enter();
+                let config = self.load_config(config)?;
+                self.with_allocator(config, None, |a| {
+                    a.allocate_by_name(
+                        &system,
+                        RemoteUserAccessConfig {
+                            user: &user,
+                            password: &password,
+                        },
+                    )
+                })
+                .map(Response::Allocated)
+            }
+            Request::Discard { addr, postmortem } => self
+                .with_allocator(None, postmortem.as_deref(), |a| a.discard_by_addr(&addr))
+                .map(Response::Discarded),
+            Request::DiscardByName { name, postmortem } => self
+                .with_allocator(None, postmortem.as_deref(), |a| a.discard_by_name(&name))
+                .map(Response::Discarded),
+            Request::List => self
+                .with_allocator(None, None, |a| a.list())
+                .map(Response::Nodes),
+            Request::Cleanup => self
+                .with_allocator(None, None, |a| a.discard_all())
+                .map(|_| Response::CleanedUp),
+            Request::Collect { node, config } => {
+                let node = node
+                    .parse::<NodeRef>()
+                    .map_err(allocator::Error::Operation)?;
+                let config = self.load_config(config)?;
+                self.with_allocator(config, None, |a| a.collect_diagnostics(&node))
+                    .map(Response::Collected)
+            }
+        }
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let resp = match serde_json::from_str::<Request>(&line) {
+                Ok(req) => {
+                    log::info!("handling {} request", command_name(&req));
+                    self.handle(req).unwrap_or_else(Response::Error)
+                }
+                Err(err) => Response::Error(allocator::Error::Operation(format!(
+                    "invalid request: {}",
+                    err
+                ))),
+            };
+
+            let mut data = serde_json::to_vec(&resp)?;
+            data.push(b'\n');
+            writer.write_all(&data)?;
+        }
+        Ok(())
+    }
+}
+
+fn command_name(req: &Request) -> &'static str {
+    match req {
+        Request::Allocate { .. } => "allocate",
+        Request::Discard { .. } => "discard",
+        Request::DiscardByName { .. } => "discard-by-name",
+        Request::List => "list",
+        Request::Cleanup => "cleanup",
+        Request::Collect { .. } => "collect",
+    }
+}
+
+/// Bind the daemon socket at a given path, replacing a stale socket. The
+/// socket is only accessible by the current user.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("daemon already running at {}", path.display()),
+            ));
+        }
+        log::debug!("removing stale socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Serve requests, each connection in a separate thread. Allocations are
+/// handled in the order they arrive, with at most a given number at once.
+/// Allocators are obtained from the path and contents of the configuration
+/// file if one was provided, and the post-mortem mode requested by the client
+/// for discards, and are kept for later requests until either the
+/// configuration file or any of the watched files is modified.
+pub fn serve<F>(
+    listener: UnixListener,
+    max_concurrent: Option<usize>,
+    watched: Vec<PathBuf>,
+    new_allocator: F,
+) -> io::Result<()>
+where
+    F: Fn(
+            Option<(&Path, &[u8])>,
+            Option<&str>,
+        ) -> Result<Box<dyn NodeAllocator + Send>, allocator::Error>
+        + Send
+        + Sync
+        + 'static,
+{
+    let daemon = Arc::new(Daemon {
+        new_allocator,
+        queue: Queue::new(max_concurrent),
+        configs: Default::default(),
+        pool: AllocatorPool::new(watched),
+    });
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let daemon = daemon.clone();
+                thread::spawn(move || {
+                    if let Err(err) = daemon.handle_connection(stream) {
+                        log::warn!("cannot handle connection: {}", err);
+                    }
+                });
+            }
+            Err(err) => log::warn!("cannot accept connection: {}", err),
+        }
+    }
+    Ok(())
+}
+
+/// Node allocator forwarding requests to the daemon.
+pub struct Client {
+    socket: PathBuf,
+    config: Option<PathBuf>,
+    postmortem: Option<String>,
+}
+
+impl Client {
+    /// Returns a client if the daemon is listening on a given socket.
+    pub fn connect(socket: &Path) -> Option<Client> {
+        match UnixStream::connect(socket) {
+            Ok(_) => Some(Client {
+                socket: socket.to_path_buf(),
+                config: None,
+                postmortem: None,
+            }),
+            Err(err) => {
+                log::debug!("daemon not available at {}: {}", socket.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Use a given backend configuration file for allocations. The daemon
+    /// runs in a directory of its own, thus the path is made absolute.
+    pub fn with_config(mut self, config: Option<PathBuf>) -> Self {
+        self.config = config.map(|path| std::path::absolute(&path).unwrap_or(path));
+        self
+    }
+
+    /// Request a given post-mortem mode for discards. The daemon has an
+    /// environment of its own, thus the mode of the client is passed along.
+    pub fn with_postmortem_mode(mut self, mode: Option<String>) -> Self {
+        self.postmortem = mode;
+        self
+    }
+
+    fn request(&self, req: &Request) -> Result<Response, allocator::Error> {
+        let send = || -> io::Result<Response> {
+            let mut stream = UnixStream::connect(&self.socket)?;
+            let mut data = serde_json::to_vec(req)?;
+            data.push(b'\n');
+            stream.write_all(&data)?;
+
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line)?;
+            Ok(serde_json::from_str(&line)?)
+        };
+
+        match send() {
+            Ok(Response::Error(err)) => Err(err),
+            Ok(resp) => Ok(resp),
+            Err(err) => Err(allocator::Error::Operation(format!(
+                "cannot communicate with daemon at {}: {}",
+                self.socket.display(),
+                err
+            ))),
+        }
+    }
+}
+
+fn unexpected(resp: Response) -> allocator::Error {
+    allocator::Error::Operation(format!("unexpected response from daemon: {:?}", resp))
+}
+
+impl NodeAllocator for Client {
+    fn allocate_by_name(
+        &mut self,
+        name: &str,
+        user_config: RemoteUserAccessConfig,
+    ) -> Result<Node, allocator::Error> {
+        match self.request(&Request::Allocate {
+            system: name.to_string(),
+            user: user_config.user.to_string(),
+            password: user_config.password.to_string(),
+            config: self.config.clone(),
+        })? {
+            Response::Allocated(node) => Ok(node),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    fn discard_by_addr(&mut self, addr: &str) -> Result<Option<Node>, allocator::Error> {
+        match self.request(&Request::Discard {
+            addr: addr.to_string(),
+            postmortem: self.postmortem.clone(),
+        })? {
+            Response::Discarded(node) => Ok(node),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    fn discard_by_name(&mut self, name: &str) -> Result<Option<Node>, allocator::Error> {
+        match self.request(&Request::DiscardByName {
+            name: name.to_string(),
+            postmortem: self.postmortem.clone(),
+        })? {
+            Response::Discarded(node) => Ok(node),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    fn discard_all(&mut self) -> Result<(), allocator::Error> {
+        match self.request(&Request::Cleanup)? {
+            Response::CleanedUp => Ok(()),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<NodeInfo>, allocator::Error> {
+        match self.request(&Request::List)? {
+            Response::Nodes(nodes) => Ok(nodes),
+            resp => Err(unexpected(resp)),
+        }
+    }
+
+    fn collect_diagnostics(&mut self, node: &NodeRef) -> Result<PathBuf, allocator::Error> {
+        match self.request(&Request::Collect {
+            node: node.to_string(),
+            config: self.config.clone(),
+        })? {
+            Response::Collected(dir) => Ok(dir),
+            resp => Err(unexpected(resp)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Allocator keeping nodes in memory shared across instances.
+    struct FakeAllocator {
+        nodes: Arc<Mutex<Vec<NodeInfo>>>,
+        config: Option<String>,
+    }
+
+    impl NodeAllocator for FakeAllocator {
+        fn allocate_by_name(
+            &mut self,
+            name: &str,
+            _user_config: RemoteUserAccessConfig,
+        ) -> Result<Node, allocator::Error> {
+            if self.config.as_deref() != Some("config") {
+                return Err(allocator::Error::Operation("no config".to_string()));
+            }
+            let mut nodes = self.nodes.lock().unwrap();
+            let idx = nodes.len();
+            let node = Node {
+                name: format!("{}-{}", name, idx),
+                system: name.to_string(),
+                addr: [10, 0, 0, idx as u8 + 1].into(),
+                ssh_port: 22,
+                allocated_at: None,
+            };
+            nodes.push(NodeInfo {
+                name: node.name.clone(),
+                node: Some(node.clone()),
+                cpu: 1,
+                memory: 1024,
+            });
+            Ok(node)
+        }
+
+        fn discard_by_addr(&mut self, addr: &str) -> Result<Option<Node>, allocator::Error> {
+            let mut nodes = self.nodes.lock().unwrap();
+            let idx = nodes
+                .iter()
+                .position(|n| n.node.as_ref().is_some_and(|n| n.addr.to_string() == addr))
+                .ok_or_else(|| allocator::Error::NotFound(format!("node {} not found", addr)))?;
+            Ok(nodes.remove(idx).node)
+        }
+
+        fn discard_by_name(&mut self, name: &str) -> Result<Option<Node>, allocator::Error> {
+            let mut nodes = self.nodes.lock().unwrap();
+            let idx = nodes
+                .iter()
+                .position(|n| n.name == name)
+                .ok_or_else(|| allocator::Error::NotFound(format!("node {} not found", name)))?;
+            Ok(nodes.remove(idx).node)
+        }
+
+        fn discard_all(&mut self) -> Result<(), allocator::Error> {
+            self.nodes.lock().unwrap().clear();
+            Ok(())
+        }
+
+        fn list(&mut self) -> Result<Vec<NodeInfo>, allocator::Error> {
+            Ok(self.nodes.lock().unwrap().clone())
+        }
+
+        fn collect_diagnostics(&mut self, node: &NodeRef) -> Result<PathBuf, allocator::Error> {
+            if self.config.as_deref() != Some("config") {
+                return Err(allocator::Error::Operation("no config".to_string()));
+            }
+            Ok(PathBuf::from("/artifacts").join(node.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_client_daemon() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-daemon-{}",
+            std::process::id()
+        ));
+        let socket = dir.join(SOCKET_FILE_NAME);
+        let config = dir.join("spread-lxd.yaml");
+        fs::create_dir_all(&dir).expect("cannot create directory");
+        fs::write(&config, "config").expect("cannot write config");
+
+        assert!(Client::connect(&socket).is_none());
+
+        let listener = bind(&socket).expect("cannot bind");
+        // binding again fails while the daemon is running
+        assert_eq!(
+            bind(&socket).expect_err("unexpected success").kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        let nodes = Arc::new(Mutex::new(vec![]));
+        let daemon_nodes = nodes.clone();
+        thread::spawn(move || {
+            serve(listener, Some(1), vec![], move |config, _postmortem| {
+                Ok(Box::new(FakeAllocator {
+                    nodes: daemon_nodes.clone(),
+                    config: config.map(|(_, c)| String::from_utf8_lossy(c).to_string()),
+                }))
+            })
+        });
+
+        let mut client = Client::connect(&socket)
+            .expect("daemon not running")
+            .with_config(Some(config.clone()));
+        let node = client
+            .allocate_by_name(
+                "ubuntu",
+                RemoteUserAccessConfig {
+                    user: "user",
+                    password: "pass",
+                },
+            )
+            .expect("cannot allocate");
+        assert_eq!(node.name, "ubuntu-0");
+        assert_eq!(node.system, "ubuntu");
+        assert_eq!(node.addr.to_string(), "10.0.0.1");
+        assert_eq!(node.ssh_port, 22);
+
+        let listed = client.list().expect("cannot list");
+        assert_eq!(listed, *nodes.lock().unwrap());
+        assert_eq!(listed.len(), 1);
+
+        // errors keep their kind
+        let err = client
+            .discard_by_addr("10.0.0.99")
+            .expect_err("unexpected success");
+        assert!(matches!(err, allocator::Error::NotFound(_)), "{:?}", err);
+        assert_eq!(err.to_string(), "node 10.0.0.99 not found");
+
+        let collected = client
+            .collect_diagnostics(&NodeRef::Addr([10, 0, 0, 1].into(), Some(22)))
+            .expect("cannot collect");
+        assert_eq!(collected, PathBuf::from("/artifacts/10.0.0.1:22"));
+
+        let discarded = client.discard_by_addr("10.0.0.1").expect("cannot discard");
+        assert_eq!(discarded, Some(node));
+        assert!(nodes.lock().unwrap().is_empty());
+
+        // allocation without configuration
+        let err = Client::connect(&socket)
+            .expect("daemon not running")
+            .allocate_by_name(
+                "ubuntu",
+                RemoteUserAccessConfig {
+                    user: "user",
+                    password: "pass",
+                },
+            )
+            .expect_err("unexpected success");
+        assert_eq!(err.to_string(), "cannot execute operation: no config");
+
+        // configuration which cannot be loaded
+        let err = Client::connect(&socket)
+            .expect("daemon not running")
+            .with_config(Some(dir.join("missing.yaml")))
+            .allocate_by_name(
+                "ubuntu",
+                RemoteUserAccessConfig {
+                    user: "user",
+                    password: "pass",
+                },
+            )
+            .expect_err("unexpected success");
+        assert!(matches!(err, allocator::Error::Config(_)), "{:?}", err);
+
+        let node = client
+            .allocate_by_name(
+                "fedora",
+                RemoteUserAccessConfig {
+                    user: "user",
+                    password: "pass",
+                },
+            )
+            .expect("cannot allocate");
+        let discarded = client
+            .discard_by_name(&node.name)
+            .expect("cannot discard by name");
+        assert_eq!(discarded, Some(node));
+
+        client.discard_all().expect("cannot cleanup");
+
+        fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_allocator_reuse() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-reuse-{}",
+            std::process::id()
+        ));
+        let config = dir.join("spread-lxd.yaml");
+        let user_config = dir.join("config.yaml");
+        fs::create_dir_all(&dir).expect("cannot create directory");
+        fs::write(&config, "config").expect("cannot write config");
+        fs::write(&user_config, "").expect("cannot write user config");
+
+        let created = Arc::new(AtomicUsize::new(0));
+        let daemon = Daemon {
+            new_allocator: {
+                let created = created.clone();
+                move |config: Option<(&Path, &[u8])>, _postmortem: Option<&str>| {
+                    created.fetch_add(1, Ordering::SeqCst);
+                    Ok(Box::new(FakeAllocator {
+                        nodes: Default::default(),
+                        config: config.map(|(_, c)| String::from_utf8_lossy(c).to_string()),
+                    }) as Box<dyn NodeAllocator + Send>)
+                }
+            },
+            queue: Queue::new(None),
+            configs: Default::default(),
+            pool: AllocatorPool::new(vec![user_config.clone()]),
+        };
+        let allocate = || {
+            daemon.handle(Request::Allocate {
+                system: "ubuntu".to_string(),
+                user: "user".to_string(),
+                password: "pass".to_string(),
+                config: Some(config.clone()),
+            })
+        };
+        let touch = |path: &Path, secs: u64| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .and_then(|f| f.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)))
+                .expect("cannot set modification time");
+        };
+
+        assert!(matches!(allocate(), Ok(Response::Allocated(_))));
+        assert!(matches!(allocate(), Ok(Response::Allocated(_))));
+        assert_eq!(created.load(Ordering::SeqCst), 1);
+
+        // requests without configuration use their own allocator
+        assert!(matches!(
+            daemon.handle(Request::List),
+            Ok(Response::Nodes(_))
+        ));
+        assert!(matches!(
+            daemon.handle(Request::List),
+            Ok(Response::Nodes(_))
+        ));
+        assert_eq!(created.load(Ordering::SeqCst), 2);
+
+        // modified configuration
+        touch(&config, 1);
+        assert!(matches!(allocate(), Ok(Response::Allocated(_))));
+        assert_eq!(created.load(Ordering::SeqCst), 3);
+
+        // modified watched file
+        touch(&user_config, 1);
+        assert!(matches!(allocate(), Ok(Response::Allocated(_))));
+        assert!(matches!(
+            daemon.handle(Request::List),
+            Ok(Response::Nodes(_))
+        ));
+        assert_eq!(created.load(Ordering::SeqCst), 5);
+
+        // a failed allocator is not kept
+        assert!(daemon
+            .handle(Request::Discard {
+                addr: "10.0.0.99".to_string(),
+                postmortem: None,
+            })
+            .is_err());
+        assert!(matches!(
+            daemon.handle(Request::List),
+            Ok(Response::Nodes(_))
+        ));
+        assert_eq!(created.load(Ordering::SeqCst), 6);
+
+        fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_client_postmortem_mode() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-postmortem-{}",
+            std::process::id()
+        ));
+        let socket = dir.join(SOCKET_FILE_NAME);
+        let config = dir.join("spread-lxd.yaml");
+        fs::create_dir_all(&dir).expect("cannot create directory");
+        fs::write(&config, "config").expect("cannot write config");
+
+        let listener = bind(&socket).expect("cannot bind");
+        let nodes = Arc::new(Mutex::new(vec![]));
+        let modes = Arc::new(Mutex::new(vec![]));
+        {
+            let nodes = nodes.clone();
+            let modes = modes.clone();
+            thread::spawn(move || {
+                serve(listener, Some(1), vec![], move |config, postmortem| {
+                    modes.lock().unwrap().push(postmortem.map(str::to_string));
+                    Ok(Box::new(FakeAllocator {
+                        nodes: nodes.clone(),
+                        config: config.map(|(_, c)| String::from_utf8_lossy(c).to_string()),
+                    }))
+                })
+            });
+        }
+
+        let client = || {
+            Client::connect(&socket)
+                .expect("daemon not running")
+                .with_config(Some(config.clone()))
+        };
+        let allocate = || {
+            client()
+                .allocate_by_name(
+                    "ubuntu",
+                    RemoteUserAccessConfig {
+                        user: "user",
+                        password: "pass",
+                    },
+                )
+                .expect("cannot allocate")
+        };
+
+        let first = allocate();
+        let second = allocate();
+        let third = allocate();
+        assert_eq!(*modes.lock().unwrap(), vec![None]);
+
+        client()
+            .discard_by_name(&third.name)
+            .expect("cannot discard by name");
+        assert_eq!(*modes.lock().unwrap(), vec![None, None]);
+
+        // allocators are not shared between modes
+        client()
+            .with_postmortem_mode(Some("snapshot".to_string()))
+            .discard_by_addr(&first.addr.to_string())
+            .expect("cannot discard");
+        client()
+            .with_postmortem_mode(Some("snapshot".to_string()))
+            .discard_by_name(&second.name)
+            .expect("cannot discard by name");
+        assert_eq!(
+            *modes.lock().unwrap(),
+            vec![None, None, Some("snapshot".to_string())]
+        );
+        assert!(nodes.lock().unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_client_config_absolute() {
+        let client = Client {
+            socket: PathBuf::from("daemon.sock"),
+            config: None,
+            postmortem: None,
+        }
+        .with_config(Some(PathBuf::from("spread-lxd.yaml")));
+        assert_eq!(
+            client.config,
+            Some(
+                std::env::current_dir()
+                    .expect("no current directory")
+                    .join("spread-lxd.yaml")
+            )
+        );
+    }
+
+    #[test]
+    fn test_queue_limit() {
+        let queue = Arc::new(Queue::new(Some(1)));
+        let turn = queue.enter();
+
+        let admitted = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let queue = queue.clone();
+            let admitted = admitted.clone();
+            thread::spawn(move || {
+                let _turn = queue.enter();
+                admitted.store(true, Ordering::SeqCst);
+            })
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(!admitted.load(Ordering::SeqCst));
+
+        drop(turn);
+        waiter.join().expect("waiter failed");
+        assert!(admitted.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,34 @@
+// SPDX-FileCopyrightText: 2025 Maciej Borzecki <maciek.borzecki@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Exclusive lock held on a file, shared across processes. The lock is
+/// released when dropped.
+pub struct FileLock {
+    _file: fs::File,
+}
+
+impl FileLock {
+    /// Acquire the lock, blocking until it becomes available. The file and
+    /// its parent directories are created as needed.
+    pub fn acquire(path: &Path) -> io::Result<FileLock> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        log::trace!("acquiring lock {}", path.display());
+        file.lock()?;
+        log::trace!("acquired lock {}", path.display());
+
+        Ok(FileLock { _file: file })
+    }
+}
@@ -0,0 +1,221 @@
+// SPDX-FileCopyrightText: 2025 Maciej Borzecki <maciek.borzecki@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable with the log level, used unless the level was given
+/// explicitly.
+pub const LOG_LEVEL_ENV: &str = "SPREAD_ADHOC_LOG";
+
+thread_local! {
+    /// Log of the node being allocated by the current thread.
+    static NODE_LOG: RefCell<Option<fs::File>> = const { RefCell::new(None) };
+}
+
+/// Maximum level of messages logged while no node log is in use.
+static MAX_LEVEL: OnceLock<log::LevelFilter> = OnceLock::new();
+/// Number of node logs in use by all threads.
+static NODE_LOGS: Mutex<usize> = Mutex::new(0);
+
+/// Logs to stderr, and optionally a log file and a per node log file.
+struct Logger {
+    level: log::LevelFilter,
+    file: Option<Mutex<fs::File>>,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+            || self.file.is_some()
+            || NODE_LOG.with(|f| f.borrow().is_some())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!(
+            "{} {:<5} [{}] {}",
+            timestamp(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        if record.level() <= self.level {
+            eprintln!("{}", line);
+        }
+
+        // both log files are meant for finding out what happened after the
+        // fact, thus carry all the details
+        if let Some(file) = self.file.as_ref() {
+            if let Ok(mut file) = file.lock() {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+
+        NODE_LOG.with(|f| {
+            if let Some(f) = f.borrow_mut().as_mut() {
+                let _ = writeln!(f, "{}", line);
+            }
+        });
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Set up logging to stderr with a given level, and optionally to a file.
+pub fn init(level: log::LevelFilter, log_file: Option<&Path>) -> io::Result<()> {
+    let file = log_file
+        .map(|path| fs::OpenOptions::new().create(true).append(true).open(path))
+        .transpose()?;
+
+    let logger = Box::leak(Box::new(Logger {
+        level,
+        file: file.map(Mutex::new),
+    }));
+    log::set_logger(logger).map_err(|e| io::Error::other(e.to_string()))?;
+
+    // the log file carries all the details
+    let max_level = if logger.file.is_some() {
+        log::LevelFilter::Trace
+    } else {
+        level
+    };
+    let _ = MAX_LEVEL.set(max_level);
+    let node_logs = NODE_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+    log::set_max_level(if *node_logs > 0 {
+        log::LevelFilter::Trace
+    } else {
+        max_level
+    });
+    Ok(())
+}
+
+/// Parses the log level from the environment.
+pub fn level_from_env() -> Option<log::LevelFilter> {
+    std::env::var(LOG_LEVEL_ENV)
+        .ok()
+        .and_then(|v| v.parse::<log::LevelFilter>().ok())
+}
+
+/// Keeps logging from the current thread to a node log file until dropped.
+pub struct NodeLog {
+    _private: (),
+}
+
+impl Drop for NodeLog {
+    fn drop(&mut self) {
+        NODE_LOG.with(|f| f.borrow_mut().take());
+
+        let mut node_logs = NODE_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+        *node_logs -= 1;
+        if *node_logs == 0 {
+            log::set_max_level(MAX_LEVEL.get().copied().unwrap_or(log::LevelFilter::Off));
+        }
+    }
+}
+
+/// Start logging messages from the current thread to a given file, creating
+/// parent directories as needed.
+pub fn node_log(path: &Path) -> io::Result<NodeLog> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    NODE_LOG.with(|f| f.borrow_mut().replace(file));
+
+    // node logs carry all the details
+    let mut node_logs = NODE_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+    *node_logs += 1;
+    log::set_max_level(log::LevelFilter::Trace);
+    Ok(NodeLog { _private: () })
+}
+
+/// Formats time as RFC 3339 timestamp in UTC, with millisecond precision.
+pub fn timestamp(t: SystemTime) -> String {
+    let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(1736700938123)),
+            "2025-01-12T16:55:38.123Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn test_logger_enabled() {
+        use log::Log;
+
+        let logger = Logger {
+            level: log::LevelFilter::Warn,
+            file: None,
+        };
+        let metadata = |level| log::Metadata::builder().level(level).build();
+        assert!(logger.enabled(&metadata(log::Level::Error)));
+        assert!(!logger.enabled(&metadata(log::Level::Trace)));
+
+        // all messages go to a node log while it is in use
+        let path = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-node-log-{}.log",
+            std::process::id()
+        ));
+        let node_log = node_log(&path).expect("cannot start node log");
+        assert!(logger.enabled(&metadata(log::Level::Trace)));
+        assert_eq!(log::max_level(), log::LevelFilter::Trace);
+        drop(node_log);
+        assert!(!logger.enabled(&metadata(log::Level::Trace)));
+
+        std::fs::remove_file(&path).expect("cannot clean up");
+    }
+}
@@ -4,19 +4,23 @@
 
 use core::net;
 use core::time;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
 use std::thread;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
 use log::debug;
 use rand::random;
-use serde;
-use serde_yml;
-use thiserror;
 
 use crate::allocator;
+use crate::audit::{self, Event, EventSink};
+use crate::lock::FileLock;
+use crate::logging;
+use crate::state::StateStore;
 
 /// Wraps LXD executor errors.
 #[derive(thiserror::Error, Debug)]
@@ -33,6 +37,10 @@ pub enum LxdError {
     Discard(String),
     #[error("{0}")]
     NotFound(String),
+    #[error("cannot admit node: {0}")]
+    Capacity(String),
+    #[error("timed out: {0}")]
+    Timeout(String),
 }
 
 impl PartialEq for LxdError {
@@ -44,8 +52,15 @@ impl PartialEq for LxdError {
 impl From<LxdError> for allocator::Error {
     fn from(err: LxdError) -> Self {
         match err {
+            LxdError::Config(_) | LxdError::ConfigInvalid(_) => {
+                allocator::Error::Config(err.to_string())
+            }
+            LxdError::Executor(_) | LxdError::Allocate(_) | LxdError::Discard(_) => {
+                allocator::Error::Backend(err.to_string())
+            }
             LxdError::NotFound(_) => allocator::Error::NotFound(err.to_string()),
-            _ => allocator::Error::Operation(err.to_string()),
+            LxdError::Capacity(_) => allocator::Error::Capacity(err.to_string()),
+            LxdError::Timeout(_) => allocator::Error::Timeout(err.to_string()),
         }
     }
 }
@@ -54,32 +69,146 @@ impl From<LxdError> for allocator::Error {
 #[derive(Debug, PartialEq)]
 pub struct LxdNodeAllocation {
     pub name: String,
-    pub addr: net::Ipv4Addr,
+    pub addr: net::IpAddr,
     pub ssh_port: u32,
+    /// Duration of each phase of the allocation.
+    pub phases: Vec<(String, time::Duration)>,
 }
 
-/// Carries details of a node to allocate.
+/// Describes the LXD server.
+#[derive(Debug, PartialEq)]
+pub struct LxdServerInfo {
+    /// Whether the server is a member of a cluster.
+    pub clustered: bool,
+    /// Architectures supported by the server.
+    pub architectures: Vec<String>,
+}
+
+/// Describes a LXD cluster member.
+#[derive(Debug, PartialEq)]
+pub struct LxdClusterMember {
+    pub name: String,
+    pub architecture: String,
+    pub online: bool,
+}
+
+/// Describes resources assigned to an existing node.
 #[derive(Debug, PartialEq)]
+pub struct LxdNodeUsage {
+    pub name: String,
+    /// Cluster member the node is located on.
+    pub location: String,
+    pub cpu: u32,
+    pub memory: u64,
+}
+
+/// Carries details of a node to allocate.
+#[derive(Debug, PartialEq, Default)]
 pub struct LxdNodeDetails<'a> {
     image: &'a str,
     name: &'a str,
+    remote: Option<&'a str>,
+    target: Option<&'a str>,
     cpu: u32,
     memory: u64,
     root_size: u64,
+    vm: bool,
     secure_boot: bool,
-    provision_steps: &'a [String],
+    tpm: bool,
+    firmware: LxdFirmware,
+    address: LxdAddressFilter<'a>,
+    ssh_forward: Option<LxdSshForward>,
+    /// Instance configuration keys with cloud-init data.
+    cloud_init: &'a [(String, String)],
+    /// Setup steps, along with the names of their sets.
+    provision_steps: &'a [(String, LxdSetupStep)],
+    /// File to record the provisioning transcript to.
+    transcript: Option<&'a Path>,
+    /// File to save the console log to when the node does not obtain an
+    /// address.
+    console_log: Option<&'a Path>,
+}
+
+/// Forwarding of a port on the LXD host to the SSH port of a node.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LxdSshForward {
+    /// Host address to listen on, reported as the node address.
+    address: net::IpAddr,
+    port: u16,
+}
+
+/// Criteria for picking the address of a node.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct LxdAddressFilter<'a> {
+    family: LxdAddressFamily,
+    /// Interface name or glob pattern.
+    interface: Option<&'a str>,
+    subnet: Option<LxdSubnet>,
+}
+
+impl LxdAddressFilter<'_> {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(subnet) = self.subnet.as_ref() {
+            let family = if subnet.addr.is_ipv4() {
+                "inet"
+            } else {
+                "inet6"
+            };
+            if !self.family.accepts(family) {
+                return Err(format!(
+                    "subnet {} does not match {} address family",
+                    subnet,
+                    self.family.name()
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 /// An executor for allocating nodes using LXD.
-pub trait LxdAllocatorExecutor {
+pub trait LxdAllocatorExecutor: Send {
     /// Allocate a node with given confuguration.
     fn allocate(&mut self, node: &LxdNodeDetails) -> Result<LxdNodeAllocation, LxdError>;
     /// Discard a node with given address.
-    fn discard_by_addr(&mut self, addr: &str) -> Result<(), LxdError>;
+    fn discard_by_addr(&mut self, remote: Option<&str>, addr: &str) -> Result<(), LxdError>;
+    /// Discard a node with given name.
+    fn discard_by_name(&mut self, remote: Option<&str>, name: &str) -> Result<(), LxdError>;
     /// Discard all nodes.
-    fn discard_all(&mut self) -> Result<(), LxdError>;
+    fn discard_all(&mut self, remote: Option<&str>) -> Result<(), LxdError>;
     /// Ensure a given LXD project exists.
-    fn ensure_project(&mut self, project: &str) -> Result<(), LxdError>;
+    fn ensure_project(&mut self, remote: Option<&str>, project: &str) -> Result<(), LxdError>;
+    /// Obtain information about the LXD server.
+    fn server_info(&mut self, remote: Option<&str>) -> Result<LxdServerInfo, LxdError>;
+    /// List members of the LXD cluster.
+    fn cluster_members(&mut self, remote: Option<&str>) -> Result<Vec<LxdClusterMember>, LxdError>;
+    /// List resources assigned to existing nodes.
+    fn list_allocations(&mut self, remote: Option<&str>) -> Result<Vec<LxdNodeUsage>, LxdError>;
+    /// Collect diagnostics from a node into a given directory. Diagnostics
+    /// which cannot be obtained are skipped.
+    fn collect_diagnostics(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+        diagnostics: &[LxdDiagnostic],
+        dir: &Path,
+    ) -> Result<(), LxdError>;
+    /// Keep a node for post-mortem. The node is stopped, snapshotted or
+    /// exported to a given file, and moved to the post-mortem project under a
+    /// new name.
+    fn keep_node(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+        kept_name: &str,
+        mode: LxdPostmortemMode,
+        export: Option<&Path>,
+    ) -> Result<(), LxdError>;
+    /// Delete a node kept for post-mortem.
+    fn delete_kept_node(&mut self, remote: Option<&str>, name: &str) -> Result<(), LxdError>;
+    /// Open an interactive shell in a node kept for post-mortem, starting it
+    /// if needed.
+    fn shell_kept_node(&mut self, remote: Option<&str>, name: &str) -> Result<(), LxdError>;
 }
 
 struct LxcCommand(Command);
@@ -95,6 +224,7 @@ enum LxcCommandScope<'a> {
 /// Builds lxc command line.
 struct LxcCommandBuilder<'a> {
     scope: LxcCommandScope<'a>,
+    target: Option<&'a str>,
     args: Vec<&'a str>,
 }
 
@@ -102,6 +232,7 @@ impl<'a> LxcCommandBuilder<'a> {
     fn new() -> Self {
         Self {
             scope: LxcCommandScope::Default,
+            target: None,
             args: Vec::new(),
         }
     }
@@ -111,6 +242,12 @@ impl<'a> LxcCommandBuilder<'a> {
         self
     }
 
+    /// Place the instance on a given cluster member.
+    fn with_target(mut self, target: Option<&'a str>) -> Self {
+        self.target = target;
+        self
+    }
+
     fn args(mut self, args: &'a [&str]) -> Self {
         self.args = args.to_vec();
         self
@@ -120,10 +257,14 @@ impl<'a> LxcCommandBuilder<'a> {
         let mut cmd = Command::new("lxc");
         if let LxcCommandScope::Project(prj) = &self.scope {
             cmd.arg("--project");
-            cmd.arg(&prj);
+            cmd.arg(prj);
         }
 
         cmd.args(self.args);
+        if let Some(target) = self.target {
+            cmd.arg("--target");
+            cmd.arg(target);
+        }
         LxcCommand(cmd)
     }
 }
@@ -137,9 +278,40 @@ pub enum LxcRunnerError {
     Execution { stderr: String, exit_code: i32 },
 }
 
+/// Output of an lxc command which ran to completion, successfully or not.
+#[derive(Debug, PartialEq, Default)]
+struct LxcOutput {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    exit_code: i32,
+}
+
 /// Trait representing a way to run lxc command.
-trait LxcRunner {
+trait LxcRunner: Send {
     fn run(&mut self, cmd: LxcCommand) -> Result<Vec<u8>, LxcRunnerError>;
+
+    /// Runs a command capturing its output regardless of the exit status. The
+    /// default implementation only has the stdout of successful commands and
+    /// the stderr of failed ones.
+    fn run_captured(&mut self, cmd: LxcCommand) -> Result<LxcOutput, LxcRunnerError> {
+        match self.run(cmd) {
+            Ok(stdout) => Ok(LxcOutput {
+                stdout,
+                ..Default::default()
+            }),
+            Err(LxcRunnerError::Execution { stderr, exit_code }) => Ok(LxcOutput {
+                stderr: stderr.into_bytes(),
+                exit_code,
+                ..Default::default()
+            }),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Runs a command attached to the terminal, returning its exit code.
+    fn run_interactive(&mut self, cmd: LxcCommand) -> Result<i32, LxcRunnerError> {
+        self.run_captured(cmd).map(|out| out.exit_code)
+    }
 }
 
 /// Wrapper for runing lxc commands.
@@ -148,31 +320,54 @@ struct LxcCommandRunner;
 impl LxcRunner for LxcCommandRunner {
     /// Runs a command returning its output (stdout).
     fn run(&mut self, lxccmd: LxcCommand) -> Result<Vec<u8>, LxcRunnerError> {
-        let LxcCommand(mut cmd) = lxccmd;
-
-        log::trace!(
-            "running lxc with: {:?}",
-            cmd.get_args()
-                .by_ref()
-                .map(|a| a.to_string_lossy())
-                .collect::<Vec<_>>()
-        );
-
-        let res = match cmd.output() {
-            Ok(output) => output,
-            Err(err) => {
-                return Err(LxcRunnerError::Start(err));
-            }
-        };
+        let res = self.run_captured(lxccmd)?;
 
-        if !res.status.success() {
+        if res.exit_code != 0 {
             return Err(LxcRunnerError::Execution {
                 stderr: String::from_utf8_lossy(&res.stderr).trim().to_string(),
-                exit_code: res.status.code().unwrap_or(255),
+                exit_code: res.exit_code,
             });
         }
-        return Ok(res.stdout);
+        Ok(res.stdout)
+    }
+
+    fn run_captured(&mut self, lxccmd: LxcCommand) -> Result<LxcOutput, LxcRunnerError> {
+        let LxcCommand(mut cmd) = lxccmd;
+
+        log::trace!("running lxc with: {:?}", loggable_args(&cmd));
+
+        let res = cmd.output().map_err(LxcRunnerError::Start)?;
+
+        Ok(LxcOutput {
+            stdout: res.stdout,
+            stderr: res.stderr,
+            exit_code: res.status.code().unwrap_or(255),
+        })
+    }
+
+    fn run_interactive(&mut self, lxccmd: LxcCommand) -> Result<i32, LxcRunnerError> {
+        let LxcCommand(mut cmd) = lxccmd;
+        let status = cmd.status().map_err(LxcRunnerError::Start)?;
+        Ok(status.code().unwrap_or(255))
+    }
+}
+
+/// Returns the arguments of a command for logging, without the body of
+/// scripts, which may carry secrets such as the password set up for the user.
+/// Scripts of setup steps are logged separately, when safe to do so.
+fn loggable_args(cmd: &Command) -> Vec<String> {
+    let mut args: Vec<String> = vec![];
+    for arg in cmd.get_args().map(|a| a.to_string_lossy()) {
+        let script = args.len() >= 2
+            && args[args.len() - 1] == "-c"
+            && args[args.len() - 2].ends_with("bash");
+        args.push(if script {
+            "<script>".to_string()
+        } else {
+            arg.to_string()
+        });
     }
+    args
 }
 
 mod lxc {
@@ -201,6 +396,41 @@ mod lxc {
             pub state: InstanceState,
             pub status: String,
         }
+
+        #[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+        pub struct InstanceSummary {
+            pub name: String,
+            #[serde(default)]
+            pub location: String,
+            #[serde(default)]
+            pub config: HashMap<String, String>,
+        }
+
+        #[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+        pub struct ServerEnvironment {
+            pub architectures: Vec<String>,
+            pub server_clustered: bool,
+        }
+
+        #[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+        pub struct Server {
+            pub environment: ServerEnvironment,
+        }
+
+        #[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+        pub struct ClusterMember {
+            pub server_name: String,
+            pub architecture: String,
+            pub status: String,
+        }
+    }
+}
+
+/// Qualifies a name with a remote, ie. <remote>:<name>.
+fn remote_name(remote: Option<&str>, name: &str) -> String {
+    match remote {
+        Some(remote) => format!("{}:{}", remote, name),
+        None => name.to_string(),
     }
 }
 
@@ -211,6 +441,123 @@ fn lxdfy_name(name: &str) -> String {
     }))
 }
 
+/// Compares addresses, such that different notations of the same IPv6
+/// address are considered equal.
+fn same_address(a: &str, b: &str) -> bool {
+    match (a.parse::<net::IpAddr>(), b.parse::<net::IpAddr>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Matches a name against a glob pattern, where '*' matches any sequence of
+/// characters and '?' matches a single character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    fn matches(p: &[char], n: &[char]) -> bool {
+        match p.split_first() {
+            None => n.is_empty(),
+            Some(('*', rest)) => (0..=n.len()).any(|i| matches(rest, &n[i..])),
+            Some(('?', rest)) => !n.is_empty() && matches(rest, &n[1..]),
+            Some((c, rest)) => n.first() == Some(c) && matches(rest, &n[1..]),
+        }
+    }
+
+    matches(&pattern, &name)
+}
+
+/// Picks an address of a node from the state of its network interfaces.
+/// Interfaces are considered in order of their names, the loopback interface
+/// is skipped unless explicitly selected, as are loopback and link-local
+/// addresses. When any family is acceptable, IPv4 addresses are preferred.
+/// Returns a description of the interfaces and addresses seen when none
+/// matched.
+fn select_address(
+    network: &HashMap<String, lxc::types::NetworkState>,
+    filter: &LxdAddressFilter,
+) -> Result<net::IpAddr, String> {
+    let mut ifnames: Vec<&String> = network.keys().collect();
+    ifnames.sort();
+
+    let mut candidates: Vec<net::IpAddr> = vec![];
+
+    for ifname in ifnames.iter() {
+        let selected = match filter.interface {
+            Some(pattern) => glob_match(pattern, ifname),
+            None => *ifname != "lo",
+        };
+        if !selected {
+            continue;
+        }
+
+        for ifaceaddr in network[*ifname].addresses.iter() {
+            if !filter.family.accepts(&ifaceaddr.family) {
+                continue;
+            }
+
+            let Ok(parsed) = ifaceaddr.address.parse::<net::IpAddr>() else {
+                log::debug!("cannot parse address {}", ifaceaddr.address);
+                continue;
+            };
+
+            let skip = parsed.is_loopback()
+                || match parsed {
+                    net::IpAddr::V4(v4) => v4.is_link_local(),
+                    net::IpAddr::V6(v6) => v6.is_unicast_link_local(),
+                };
+            if skip {
+                continue;
+            }
+            if let Some(subnet) = filter.subnet.as_ref() {
+                if !subnet.contains(&parsed) {
+                    continue;
+                }
+            }
+
+            log::debug!("found address {} on {}", parsed, ifname);
+            candidates.push(parsed);
+        }
+    }
+
+    if let Some(addr) = candidates
+        .iter()
+        .find(|a| a.is_ipv4())
+        .or(candidates.first())
+    {
+        return Ok(*addr);
+    }
+
+    if ifnames.is_empty() {
+        return Err("no network interfaces seen".to_string());
+    }
+    let seen: Vec<String> = ifnames
+        .iter()
+        .map(|ifname| {
+            let addrs: Vec<&str> = network[*ifname]
+                .addresses
+                .iter()
+                .map(|a| a.address.as_str())
+                .collect();
+            format!("{} ({})", ifname, addrs.join(", "))
+        })
+        .collect();
+    Err(format!(
+        "no {} address{}{} among interfaces: {}",
+        filter.family.name(),
+        filter
+            .interface
+            .map(|i| format!(" on interface {}", i))
+            .unwrap_or_default(),
+        filter
+            .subnet
+            .map(|n| format!(" in subnet {}", n))
+            .unwrap_or_default(),
+        seen.join(", ")
+    ))
+}
+
 /// Wraps lxc backend executor errors.
 #[derive(thiserror::Error, Debug)]
 pub enum LxcCliAllocatorError {
@@ -222,10 +569,217 @@ pub enum LxcCliAllocatorError {
     NodeNotFound,
     #[error("cannot delete node: {0}")]
     DeleteNode(String),
-    #[error("cannot obtain address")]
-    AddressTimeout,
+    #[error("cannot obtain address, {0}")]
+    AddressTimeout(String),
+    #[error("cannot obtain console log: {0}")]
+    ConsoleLog(String),
     #[error("cannot provision node: {0}")]
     Provision(String),
+    #[error("cannot add device: {0}")]
+    AddDevice(String),
+    #[error("cannot pin address: {0}")]
+    PinAddress(String),
+    #[error("cloud-init did not complete: {0}")]
+    CloudInit(String),
+    #[error("cannot start node: {0}")]
+    Start(String),
+    #[error("cannot parse {what}: {err}, output: '{output}'")]
+    Parse {
+        what: &'static str,
+        err: String,
+        /// Raw output, truncated.
+        output: String,
+    },
+}
+
+/// Maximum length of raw lxc output included in errors.
+const PARSE_ERROR_OUTPUT_MAX_CHARS: usize = 256;
+
+/// Parses JSON output of lxc commands.
+fn parse_lxc_output<T>(what: &'static str, output: &[u8]) -> Result<T, LxcCliAllocatorError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    serde_json::from_slice(output).map_err(|e| {
+        let raw = String::from_utf8_lossy(output);
+        let output = if raw.chars().count() > PARSE_ERROR_OUTPUT_MAX_CHARS {
+            format!(
+                "{}...",
+                raw.chars()
+                    .take(PARSE_ERROR_OUTPUT_MAX_CHARS)
+                    .collect::<String>()
+            )
+        } else {
+            raw.into_owned()
+        };
+        LxcCliAllocatorError::Parse {
+            what,
+            err: e.to_string(),
+            output,
+        }
+    })
+}
+
+/// Number of trailing transcript lines logged when provisioning fails.
+const TRANSCRIPT_TAIL_LINES: usize = 15;
+/// Number of trailing console log lines logged when no address is obtained.
+const CONSOLE_LOG_TAIL_LINES: usize = 15;
+/// Maximum length of a single line of output included in errors and logs.
+const ERROR_LINE_MAX_CHARS: usize = 160;
+
+/// Record of the output, exit status and duration of each provisioning step,
+/// kept in memory and mirrored to a file when one is given.
+struct ProvisionTranscript {
+    text: String,
+    file: Option<(PathBuf, std::fs::File)>,
+}
+
+impl ProvisionTranscript {
+    fn new(path: Option<&Path>) -> Self {
+        let file = path.and_then(|path| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).ok()?;
+            }
+            std::fs::File::create(path)
+                .inspect(|_| log::info!("provisioning transcript {}", path.display()))
+                .inspect_err(|err| {
+                    log::warn!("cannot create transcript {}: {}", path.display(), err)
+                })
+                .ok()
+                .map(|f| (path.to_path_buf(), f))
+        });
+        Self {
+            text: String::new(),
+            file,
+        }
+    }
+
+    /// Records the result of a step.
+    fn record(&mut self, header: &str, res: &Result<LxcOutput, String>, duration: time::Duration) {
+        let mut entry = format!("--- {}\n", header);
+        match res {
+            Ok(out) => {
+                entry += &format!(
+                    "--- exit status {}, took {:.3}s\n",
+                    out.exit_code,
+                    duration.as_secs_f64()
+                );
+                for (what, data) in [("stdout", &out.stdout), ("stderr", &out.stderr)] {
+                    if data.is_empty() {
+                        continue;
+                    }
+                    let data = String::from_utf8_lossy(data);
+                    entry += &format!("--- {}:\n{}", what, data);
+                    if !data.ends_with('\n') {
+                        entry.push('\n');
+                    }
+                }
+            }
+            Err(err) => {
+                entry += &format!("--- error: {}\n", err);
+            }
+        }
+
+        if let Some((path, file)) = self.file.as_mut() {
+            if let Err(err) = io::Write::write_all(file, entry.as_bytes()) {
+                log::warn!("cannot write transcript {}: {}", path.display(), err);
+                self.file = None;
+            }
+        }
+        self.text += &entry;
+    }
+
+    /// Returns the trailing lines of the transcript, clipped for logging.
+    fn tail(&self) -> String {
+        tail_lines(&self.text, TRANSCRIPT_TAIL_LINES)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.file.as_ref().map(|(path, _)| path.as_path())
+    }
+}
+
+/// Joins lines, each cut to a maximum length.
+fn clip_lines(lines: &[&str]) -> String {
+    let out: Vec<String> = lines
+        .iter()
+        .map(|line| {
+            if line.chars().count() > ERROR_LINE_MAX_CHARS {
+                format!(
+                    "{}...",
+                    line.chars().take(ERROR_LINE_MAX_CHARS).collect::<String>()
+                )
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    out.join("\n")
+}
+
+/// Returns up to a given number of trailing lines of text, clipped for
+/// logging.
+fn tail_lines(text: &str, count: usize) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let skip = lines.len().saturating_sub(count);
+    let tail = clip_lines(&lines[skip..]);
+    if skip > 0 {
+        format!("...\n{}", tail)
+    } else {
+        tail
+    }
+}
+
+/// Removes terminal escape sequences and control characters other than line
+/// breaks and tabs, as found in console output.
+fn strip_terminal_codes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                // CSI sequences end with a character in the @ to ~ range,
+                // other escapes take a single character
+                if chars.next_if_eq(&'[').is_some() {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                } else {
+                    chars.next();
+                }
+            }
+            '\n' | '\t' => out.push(c),
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Returns the text of a step of a given set for logs, the transcript and
+/// errors. The step setting up user access carries the password, thus it is
+/// only described.
+fn step_text(set: &str, step: &LxdSetupStep) -> String {
+    if set == USER_ACCESS_SETUP {
+        "<set password for user>".to_string()
+    } else {
+        step.to_string()
+    }
+}
+
+/// Returns the first line of text, clipped for inclusion in an error.
+fn clip_first_line(text: &str) -> String {
+    let mut lines = text.lines();
+    let first = lines.next().unwrap_or_default();
+    let clipped = clip_lines(&[first]);
+    // clipped lines already end with an ellipsis
+    if lines.next().is_some() && clipped == first {
+        format!("{}...", clipped)
+    } else {
+        clipped
+    }
 }
 
 /// Lxd node allocator which uses 'lxc' command.
@@ -234,6 +788,8 @@ where
     R: LxcRunner,
 {
     runner: R,
+    /// Lock serializing project setup across processes.
+    project_lock: Option<PathBuf>,
 }
 
 impl<R> LxdCliAllocator<R>
@@ -241,7 +797,15 @@ where
     R: LxcRunner,
 {
     fn new(r: R) -> Self {
-        Self { runner: r }
+        Self {
+            runner: r,
+            project_lock: None,
+        }
+    }
+
+    fn with_project_lock(mut self, path: &Path) -> Self {
+        self.project_lock = Some(path.to_path_buf());
+        self
     }
 
     // Consume self and return the underlying runner. Only useful for tests to
@@ -251,14 +815,19 @@ where
         self.runner
     }
 
-    fn add_project(&mut self, project: &str) -> Result<(), LxcCliAllocatorError> {
+    fn add_project(
+        &mut self,
+        remote: Option<&str>,
+        project: &str,
+    ) -> Result<(), LxcCliAllocatorError> {
+        let project = remote_name(remote, project);
         self.runner
             .run(
                 LxcCommandBuilder::new()
                     .args(&[
                         "project",
                         "create",
-                        project,
+                        &project,
                         "-c",
                         "features.images=false",
                         "-c",
@@ -267,77 +836,99 @@ where
                     .build(),
             )
             .map(|_| ())
-            .map_err(|e| LxcCliAllocatorError::AddProject(e.to_string()))
+            .or_else(|e| match e {
+                // another process may have raced with us
+                LxcRunnerError::Execution { ref stderr, .. }
+                    if stderr.contains("already exists") =>
+                {
+                    log::debug!("project {} already exists", project);
+                    Ok(())
+                }
+                _ => Err(LxcCliAllocatorError::AddProject(e.to_string())),
+            })
     }
 
-    fn list_nodes(&mut self) -> Result<Vec<lxc::types::Instance>, LxcCliAllocatorError> {
+    fn list_nodes(
+        &mut self,
+        remote: Option<&str>,
+    ) -> Result<Vec<lxc::types::Instance>, LxcCliAllocatorError> {
+        let output = self.list(remote)?;
+        parse_lxc_output("instance list", &output)
+    }
+
+    /// Runs lxc list returning the raw JSON output.
+    fn list(&mut self, remote: Option<&str>) -> Result<Vec<u8>, LxcCliAllocatorError> {
+        let remote_arg = remote.map(|r| remote_name(Some(r), ""));
+        let mut args = vec!["list", "--format=json"];
+        if let Some(remote_arg) = remote_arg.as_deref() {
+            args.push(remote_arg);
+        }
+
         self.runner
             .run(
                 LxcCommandBuilder::new()
                     .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
-                    .args(&["list", "--format=json"])
+                    .args(&args)
                     .build(),
             )
             .map_err(|e| LxcCliAllocatorError::ListNodes(e.to_string()))
-            .and_then(|output| {
-                Ok(
-                    serde_json::from_slice::<Vec<lxc::types::Instance>>(&output).expect(&format!(
-                        "cannot parse instance list JSON: '{}",
-                        String::from_utf8_lossy(&output)
-                    )),
-                )
-            })
     }
 
     fn list_node_by_name(
         &mut self,
+        remote: Option<&str>,
         name: &str,
     ) -> Result<lxc::types::Instance, LxcCliAllocatorError> {
-        let nodes = self
+        let name = remote_name(remote, name);
+        let output = self
             .runner
             .run(
                 LxcCommandBuilder::new()
                     .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
-                    .args(&["list", "--format=json", name])
+                    .args(&["list", "--format=json", &name])
                     .build(),
             )
-            .map_err(|e| LxcCliAllocatorError::ListNodes(e.to_string()))
-            .and_then(|output| {
-                Ok(
-                    serde_json::from_slice::<Vec<lxc::types::Instance>>(&output).expect(&format!(
-                        "cannot parse instance JSON: '{}'",
-                        String::from_utf8_lossy(&output)
-                    )),
-                )
-            })?;
+            .map_err(|e| LxcCliAllocatorError::ListNodes(e.to_string()))?;
+        let nodes: Vec<lxc::types::Instance> = parse_lxc_output("instance", &output)?;
 
-        if nodes.len() == 0 {
+        if nodes.is_empty() {
             Err(LxcCliAllocatorError::NodeNotFound)
         } else {
             Ok(nodes[0].clone())
         }
     }
 
-    fn discard_by_name(&mut self, name: &str) -> Result<(), LxcCliAllocatorError> {
-        log::debug!("discard by name '{}'", name);
-
+    fn delete_node(
+        &mut self,
+        project: &str,
+        remote: Option<&str>,
+        name: &str,
+    ) -> Result<(), LxcCliAllocatorError> {
+        let name = remote_name(remote, name);
         self.runner
             .run(
                 LxcCommandBuilder::new()
-                    .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
-                    .args(&["delete", "--force", name])
+                    .with_scope(LxcCommandScope::Project(project))
+                    .args(&["delete", "--force", &name])
                     .build(),
             )
-            .map_err(|e| LxcCliAllocatorError::DeleteNode(e.to_string()))
             .map(|_| ())
+            .map_err(|e| match e {
+                LxcRunnerError::Execution { ref stderr, .. } if stderr.contains("not found") => {
+                    LxcCliAllocatorError::NodeNotFound
+                }
+                _ => LxcCliAllocatorError::DeleteNode(e.to_string()),
+            })
     }
 
     fn wait_for_address(
         &mut self,
+        remote: Option<&str>,
         name: &str,
+        filter: &LxdAddressFilter,
         timeout: time::Duration,
-    ) -> Result<net::Ipv4Addr, LxcCliAllocatorError> {
-        let mut addr: Option<net::Ipv4Addr> = None;
+    ) -> Result<net::IpAddr, LxcCliAllocatorError> {
+        let mut addr: Option<net::IpAddr> = None;
 
         let now = Instant::now();
 
@@ -346,88 +937,112 @@ where
 
             thread::sleep(time::Duration::from_millis(500));
 
-            let instance = self.list_node_by_name(&name)?;
-            if instance.status != "Running" {
-                log::debug!("not yet running, in state {}", instance.status);
-                continue;
-            }
+            let instance = self.list_node_by_name(remote, name)?;
+            let found = if instance.status != "Running" {
+                Err(format!("instance is {}", instance.status))
+            } else {
+                select_address(&instance.state.network.unwrap_or_default(), filter)
+            };
 
-            for (ifname, ifstate) in instance.state.network.unwrap_or(HashMap::new()).iter() {
-                if ifname == "lo" {
-                    continue;
+            match found {
+                Ok(found) => addr = Some(found),
+                Err(seen) if now.elapsed() > timeout => {
+                    return Err(LxcCliAllocatorError::AddressTimeout(seen));
                 }
+                Err(seen) => log::debug!("no matching address yet, {}", seen),
+            }
+        }
 
-                for ifaceaddr in ifstate.addresses.iter() {
-                    if ifaceaddr.family != "inet" {
-                        continue;
-                    }
+        Ok(addr.expect("address not set"))
+    }
 
-                    log::debug!("found address {}", ifaceaddr.address);
+    /// Runs an lxc command within a given project.
+    fn run_in_project(&mut self, project: &str, args: &[&str]) -> Result<Vec<u8>, LxcRunnerError> {
+        self.runner.run(
+            LxcCommandBuilder::new()
+                .with_scope(LxcCommandScope::Project(project))
+                .args(args)
+                .build(),
+        )
+    }
 
-                    if let Ok(parsed) = ifaceaddr.address.parse::<net::Ipv4Addr>() {
-                        addr = Some(parsed);
-                        break;
-                    } else {
-                        log::debug!("cannot parse address");
-                    }
-                }
+    /// Obtains the console log of a VM, or the log of a container.
+    fn console_log(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+        vm: bool,
+    ) -> Result<String, LxcCliAllocatorError> {
+        let name = remote_name(remote, name);
+        let cmd = if vm { "console" } else { "info" };
+        self.runner
+            .run(
+                LxcCommandBuilder::new()
+                    .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+                    .args(&[cmd, "--show-log", &name])
+                    .build(),
+            )
+            .map(|out| strip_terminal_codes(&String::from_utf8_lossy(&out)))
+            .map_err(|e| LxcCliAllocatorError::ConsoleLog(e.to_string()))
+    }
 
-                if addr.is_some() {
-                    break;
-                }
-            }
+    /// Builds the error of a node not obtaining an address in time, with the
+    /// tail of its console log which is also saved to a given file.
+    fn address_timeout_error(
+        &mut self,
+        node: &LxdNodeDetails,
+        name: &str,
+        timeout: time::Duration,
+        seen: &str,
+    ) -> LxdError {
+        let mut msg = format!(
+            "cannot obtain address of {} within {}s, {}",
+            name,
+            timeout.as_secs(),
+            seen
+        );
 
-            if addr.is_none() && now.elapsed() > timeout {
-                return Err(LxcCliAllocatorError::AddressTimeout);
+        match self.console_log(node.remote, name, node.vm) {
+            Ok(console) => {
+                // the error is reported by spread in a single line, thus the
+                // console log is logged separately
+                log::error!(
+                    "{} did not obtain an address, console log tail:\n{}",
+                    name,
+                    tail_lines(&console, CONSOLE_LOG_TAIL_LINES)
+                );
+                if let Some(path) = node.console_log {
+                    let res = path
+                        .parent()
+                        .map_or(Ok(()), std::fs::create_dir_all)
+                        .and_then(|_| std::fs::write(path, &console));
+                    match res {
+                        Ok(()) => msg += &format!(" (console log: {})", path.display()),
+                        Err(err) => {
+                            log::warn!("cannot save console log to {}: {}", path.display(), err)
+                        }
+                    }
+                }
             }
+            Err(err) => log::warn!("{}", err),
         }
 
-        return Ok(addr.expect("address not set"));
+        LxdError::Timeout(msg)
     }
 
-    fn provision(&mut self, name: &str, steps: &[String]) -> Result<(), LxcCliAllocatorError> {
-        log::debug!("provision {}", name);
-
-        for step in steps {
-            log::debug!("provisioning step:\n{}", step);
-            self.runner
-                .run(
-                    LxcCommandBuilder::new()
-                        .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
-                        .args(&["exec", name, "--", "/bin/bash", "-c", step])
-                        .build(),
-                )
-                .map_err(|e| LxcCliAllocatorError::Provision(e.to_string()))?;
-        }
-        Ok(())
-    }
-}
+    fn add_device(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+        device: &str,
+        kind: &str,
+        props: &[&str],
+    ) -> Result<(), LxcCliAllocatorError> {
+        log::debug!("add {} device {} to {}", kind, device, name);
 
-impl<R> LxdAllocatorExecutor for LxdCliAllocator<R>
-where
-    R: LxcRunner,
-{
-    fn allocate(&mut self, node: &LxdNodeDetails) -> Result<LxdNodeAllocation, LxdError> {
-        let memory_arg = format!("limits.memory={}", node.memory);
-        let cpu_arg = format!("limits.cpu={}", node.cpu);
-        let secure_boot_arg = format!("security.secureboot={}", node.secure_boot);
-        let root_size_arg = format!("root,size={}", node.root_size);
-        let name = lxdfy_name(node.name);
-        let args = vec![
-            "launch",
-            "--ephemeral",
-            "--vm",
-            "--config",
-            &memory_arg,
-            "--config",
-            &cpu_arg,
-            "--config",
-            &secure_boot_arg,
-            "--device",
-            &root_size_arg,
-            node.image,
-            &name,
-        ];
+        let name = remote_name(remote, name);
+        let mut args = vec!["config", "device", "add", &name, device, kind];
+        args.extend_from_slice(props);
 
         self.runner
             .run(
@@ -436,28 +1051,413 @@ where
                     .args(&args)
                     .build(),
             )
-            .map_err(|e| LxdError::Allocate(e.to_string()))
+            .map_err(|e| LxcCliAllocatorError::AddDevice(e.to_string()))
+            .map(|_| ())
+    }
+
+    /// Pins the address of the NIC of a node to a given one, by overriding
+    /// the device coming from the profile.
+    fn pin_address(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+        addr: net::IpAddr,
+    ) -> Result<(), LxcCliAllocatorError> {
+        log::debug!("pin address of {} to {}", name, addr);
+
+        let name = remote_name(remote, name);
+        let prop = match addr {
+            net::IpAddr::V4(_) => format!("ipv4.address={}", addr),
+            net::IpAddr::V6(_) => format!("ipv6.address={}", addr),
+        };
+        self.runner
+            .run(
+                LxcCommandBuilder::new()
+                    .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+                    .args(&["config", "device", "override", &name, "eth0", &prop])
+                    .build(),
+            )
+            .map_err(|e| LxcCliAllocatorError::PinAddress(e.to_string()))
+            .map(|_| ())
+    }
+
+    /// Waits for cloud-init to complete in a node. Completion with recoverable
+    /// errors, eg. deprecated keys, is only reported.
+    fn wait_for_cloud_init(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+    ) -> Result<(), LxcCliAllocatorError> {
+        log::debug!("wait for cloud-init in {}", name);
+
+        let name = remote_name(remote, name);
+        let out = self
+            .runner
+            .run_captured(
+                LxcCommandBuilder::new()
+                    .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+                    .args(&["exec", &name, "--", "cloud-init", "status", "--wait"])
+                    .build(),
+            )
+            .map_err(|e| LxcCliAllocatorError::CloudInit(e.to_string()))?;
+        let status = String::from_utf8_lossy(&out.stdout);
+        let status = status.lines().last().unwrap_or_default().trim();
+        match out.exit_code {
+            0 => Ok(()),
+            2 => {
+                log::warn!("cloud-init in {} completed with recoverable errors", name);
+                Ok(())
+            }
+            code => Err(LxcCliAllocatorError::CloudInit(format!(
+                "exited with status {}, {}",
+                code,
+                if status.is_empty() {
+                    String::from_utf8_lossy(&out.stderr).trim().to_string()
+                } else {
+                    status.to_string()
+                }
+            ))),
+        }
+    }
+
+    fn start(&mut self, remote: Option<&str>, name: &str) -> Result<(), LxcCliAllocatorError> {
+        log::debug!("start {}", name);
+
+        let name = remote_name(remote, name);
+        self.runner
+            .run(
+                LxcCommandBuilder::new()
+                    .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+                    .args(&["start", &name])
+                    .build(),
+            )
+            .map_err(|e| LxcCliAllocatorError::Start(e.to_string()))
+            .map(|_| ())
+    }
+
+    fn provision(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+        steps: &[(String, LxdSetupStep)],
+        transcript: Option<&Path>,
+    ) -> Result<Vec<time::Duration>, LxcCliAllocatorError> {
+        log::debug!("provision {}", name);
+
+        let name = remote_name(remote, name);
+        let mut transcript = ProvisionTranscript::new(transcript);
+        let mut durations = vec![];
+        for (i, (set, step)) in steps.iter().enumerate() {
+            let text = step_text(set, step);
+            log::debug!("provisioning step of {}:\n{}", set, text);
+            let start = Instant::now();
+            let res = self.provision_step(&name, step);
+            let duration = start.elapsed();
+            transcript.record(
+                &format!("{} step {}/{}: {}", set, i + 1, steps.len(), text),
+                &res,
+                duration,
+            );
+
+            let failure = match res {
+                Ok(out) if out.exit_code == 0 => None,
+                Ok(out) => Some(format!("exited with status {}", out.exit_code)),
+                Err(err) => Some(err),
+            };
+            if let Some(failure) = failure {
+                // the error is reported by spread in a single line, thus the
+                // output is logged separately
+                log::error!(
+                    "{} step {}/{} failed, transcript tail:\n{}",
+                    set,
+                    i + 1,
+                    steps.len(),
+                    transcript.tail()
+                );
+                let mut msg = format!(
+                    "{} step {}: {}",
+                    set,
+                    clip_first_line(&text),
+                    clip_first_line(&failure)
+                );
+                if let Some(path) = transcript.path() {
+                    msg += &format!(" (transcript: {})", path.display());
+                }
+                return Err(LxcCliAllocatorError::Provision(msg));
+            }
+            durations.push(duration);
+        }
+        Ok(durations)
+    }
+
+    /// Saves a diagnostic of a node, given by its remote qualified name, to a
+    /// local file.
+    fn collect_diagnostic(
+        &mut self,
+        name: &str,
+        diagnostic: &LxdDiagnostic,
+        dest: &Path,
+    ) -> Result<(), String> {
+        let dest_arg = dest.to_string_lossy();
+        match &diagnostic.source {
+            LxdDiagnosticSource::Command(command) => {
+                let out = self
+                    .runner
+                    .run_captured(
+                        LxcCommandBuilder::new()
+                            .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+                            .args(&["exec", name, "--", "/bin/bash", "-c", command])
+                            .build(),
+                    )
+                    .map_err(|e| e.to_string())?;
+                // output of failed commands is kept, it may still be useful
+                let mut data = out.stdout;
+                data.extend(out.stderr);
+                std::fs::write(dest, data)
+                    .map_err(|e| format!("cannot write {}: {}", dest.display(), e))?;
+                if out.exit_code != 0 {
+                    return Err(format!("command exited with status {}", out.exit_code));
+                }
+                Ok(())
+            }
+            LxdDiagnosticSource::File(file) => self
+                .runner
+                .run(
+                    LxcCommandBuilder::new()
+                        .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+                        .args(&["file", "pull", &format!("{}{}", name, file), &dest_arg])
+                        .build(),
+                )
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Executes a setup step in a node, given by its remote qualified name.
+    fn provision_step(&mut self, name: &str, step: &LxdSetupStep) -> Result<LxcOutput, String> {
+        let mut args: Vec<String> = vec![];
+        // inline content is pushed from a temporary file
+        let mut tmp: Option<PathBuf> = None;
+
+        match step {
+            LxdSetupStep::Run {
+                command,
+                user,
+                env,
+                cwd,
+                timeout,
+            } => {
+                args.push("exec".to_string());
+                if let Some(user) = user {
+                    args.push(format!("--user={}", user));
+                }
+                if let Some(cwd) = cwd {
+                    args.push(format!("--cwd={}", cwd));
+                }
+                for (k, v) in env {
+                    args.push(format!("--env={}={}", k, v));
+                }
+                args.extend([name.to_string(), "--".to_string()]);
+                if let Some(timeout) = timeout {
+                    args.extend(["timeout".to_string(), timeout.to_string()]);
+                }
+                args.extend(["/bin/bash", "-c", command].map(str::to_string));
+            }
+            LxdSetupStep::Push {
+                path,
+                source,
+                mode,
+                uid,
+                gid,
+            } => {
+                let source = match source {
+                    LxdPushSource::File(source) => source.clone(),
+                    LxdPushSource::Content(content) => {
+                        let path = std::env::temp_dir().join(format!(
+                            "spread-adhoc-push-{}-{}",
+                            std::process::id(),
+                            random::<u32>()
+                        ));
+                        std::fs::write(&path, content).map_err(|e| {
+                            format!("cannot write temporary file {}: {}", path.display(), e)
+                        })?;
+                        tmp = Some(path.clone());
+                        path
+                    }
+                };
+                args.extend(["file", "push", "--create-dirs"].map(str::to_string));
+                if let Some(mode) = mode {
+                    args.push(format!("--mode={}", mode));
+                }
+                if let Some(uid) = uid {
+                    args.push(format!("--uid={}", uid));
+                }
+                if let Some(gid) = gid {
+                    args.push(format!("--gid={}", gid));
+                }
+                args.push(source.to_string_lossy().to_string());
+                args.push(format!("{}{}", name, path));
+            }
+            LxdSetupStep::Pull { path, destination } => {
+                args.extend(["file", "pull"].map(str::to_string));
+                args.push(format!("{}{}", name, path));
+                args.push(destination.to_string_lossy().to_string());
+            }
+        }
+
+        let res = self.runner.run_captured(
+            LxcCommandBuilder::new()
+                .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+                .args(&args.iter().map(String::as_str).collect::<Vec<_>>())
+                .build(),
+        );
+        if let Some(tmp) = tmp {
+            let _ = std::fs::remove_file(tmp);
+        }
+        res.map_err(|e| e.to_string())
+    }
+}
+
+impl<R> LxdAllocatorExecutor for LxdCliAllocator<R>
+where
+    R: LxcRunner,
+{
+    fn allocate(&mut self, node: &LxdNodeDetails) -> Result<LxdNodeAllocation, LxdError> {
+        let memory_arg = format!("limits.memory={}", node.memory);
+        let cpu_arg = format!("limits.cpu={}", node.cpu);
+        let secure_boot_arg = format!("security.secureboot={}", node.secure_boot);
+        let root_size_arg = format!("root,size={}", node.root_size);
+        let name = lxdfy_name(node.name);
+        // devices other than the ones coming from the profile cannot be added
+        // at launch, in which case the instance is only created, then started
+        // once the devices have been added
+        let mut args = vec![if node.tpm { "init" } else { "launch" }, "--ephemeral"];
+        if node.vm {
+            args.push("--vm");
+        }
+        args.extend_from_slice(&["--config", &memory_arg, "--config", &cpu_arg]);
+        if node.vm {
+            args.extend_from_slice(&["--config", &secure_boot_arg]);
+            if node.firmware == LxdFirmware::Csm {
+                args.extend_from_slice(&["--config", "security.csm=true"]);
+            }
+        }
+        let cloud_init_args: Vec<String> = node
+            .cloud_init
+            .iter()
+            .map(|(key, data)| format!("{}={}", key, data))
+            .collect();
+        for arg in cloud_init_args.iter() {
+            args.extend_from_slice(&["--config", arg]);
+        }
+        let instance = remote_name(node.remote, &name);
+        args.extend_from_slice(&["--device", &root_size_arg, node.image, &instance]);
+
+        let mut phases = vec![];
+        let launch_start = Instant::now();
+        self.runner
+            .run(
+                LxcCommandBuilder::new()
+                    .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+                    .with_target(node.target)
+                    .args(&args)
+                    .build(),
+            )
+            .map_err(|e| LxdError::Allocate(e.to_string()))
             .map(|_| ())?;
 
-        let addr = self
-            .wait_for_address(&name, time::Duration::from_secs(60))
-            .map_err(|e| LxdError::Allocate(e.to_string()))?;
+        if node.tpm {
+            // containers need an explicit path for the TPM device node
+            let props: &[&str] = if node.vm { &[] } else { &["path=/dev/tpm0"] };
+            self.add_device(node.remote, &name, "vtpm", "tpm", props)
+                .and_then(|_| self.start(node.remote, &name))
+                .map_err(|e| LxdError::Allocate(e.to_string()))?;
+        }
+        phases.push(("launch".to_string(), launch_start.elapsed()));
+
+        let address_start = Instant::now();
+        let address_timeout = time::Duration::from_secs(60);
+        let addr = match self.wait_for_address(node.remote, &name, &node.address, address_timeout) {
+            Ok(addr) => addr,
+            Err(LxcCliAllocatorError::AddressTimeout(seen)) => {
+                return Err(self.address_timeout_error(node, &name, address_timeout, &seen));
+            }
+            Err(e) => return Err(LxdError::Allocate(e.to_string())),
+        };
+        phases.push(("address".to_string(), address_start.elapsed()));
+
+        // user access may be set up by cloud-init, which keeps running after
+        // the node has obtained an address
+        if !node.cloud_init.is_empty() {
+            let cloud_init_start = Instant::now();
+            self.wait_for_cloud_init(node.remote, &name)
+                .map_err(|e| LxdError::Allocate(e.to_string()))?;
+            phases.push(("cloud-init".to_string(), cloud_init_start.elapsed()));
+        }
+
+        let (addr, ssh_port) = match node.ssh_forward {
+            Some(forward) => {
+                let forward_start = Instant::now();
+                // VMs support only NAT mode, which connects to the address of
+                // the instance rather than within it, thus the address must
+                // not change
+                if node.vm {
+                    self.pin_address(node.remote, &name, addr).map_err(|e| {
+                        LxdError::Allocate(format!(
+                            "cannot forward SSH to a VM without a static address, {}",
+                            e
+                        ))
+                    })?;
+                }
+                let listen = format!(
+                    "listen=tcp:{}",
+                    net::SocketAddr::new(forward.address, forward.port)
+                );
+                let connect = if node.vm {
+                    format!("connect=tcp:{}", net::SocketAddr::new(addr, 22))
+                } else {
+                    "connect=tcp:127.0.0.1:22".to_string()
+                };
+                let mut props = vec![listen.as_str(), connect.as_str()];
+                if node.vm {
+                    props.push("nat=true");
+                }
+                self.add_device(node.remote, &name, "ssh-forward", "proxy", &props)
+                    .map_err(|e| LxdError::Allocate(e.to_string()))?;
+                phases.push(("ssh-forward".to_string(), forward_start.elapsed()));
+                (forward.address, u32::from(forward.port))
+            }
+            None => (addr, 22),
+        };
 
-        self.provision(&name, node.provision_steps)
+        let provision_start = Instant::now();
+        let steps = self
+            .provision(node.remote, &name, node.provision_steps, node.transcript)
             .map_err(|e| LxdError::Allocate(e.to_string()))?;
+        phases.push(("provision".to_string(), provision_start.elapsed()));
+        for (i, duration) in steps.into_iter().enumerate() {
+            phases.push((format!("provision-step-{}", i + 1), duration));
+        }
+        // time from launch until the node is ready for use
+        phases.push(("ready".to_string(), launch_start.elapsed()));
+
+        for (phase, duration) in phases.iter() {
+            log::debug!("{} phase {} took {:?}", name, phase, duration);
+        }
 
         Ok(LxdNodeAllocation {
-            name: name,
-            addr: addr,
-            ssh_port: 22,
+            name,
+            addr,
+            ssh_port,
+            phases,
         })
     }
 
-    fn discard_by_addr(&mut self, addr: &str) -> Result<(), LxdError> {
+    fn discard_by_addr(&mut self, remote: Option<&str>, addr: &str) -> Result<(), LxdError> {
         log::debug!("discard by address '{}'", addr);
 
         let nodes = self
-            .list_nodes()
+            .list_nodes(remote)
             .map_err(|e| LxdError::Discard(e.to_string()))?;
 
         let mut name: Option<String> = None;
@@ -476,7 +1476,7 @@ where
                     iface
                         .addresses
                         .iter()
-                        .find(|ifaceaddr| ifaceaddr.address == addr)
+                        .find(|ifaceaddr| same_address(&ifaceaddr.address, addr))
                         .is_some()
                 })
                 .is_some();
@@ -488,675 +1488,6102 @@ where
         }
 
         if let Some(name) = name {
-            self.discard_by_name(&name)
-                .map_err(|e| LxdError::Discard(e.to_string()))
+            self.discard_by_name(remote, &name)
         } else {
             Err(LxdError::NotFound(addr.to_string()))
         }
     }
 
-    fn discard_all(&mut self) -> Result<(), LxdError> {
+    fn collect_diagnostics(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+        diagnostics: &[LxdDiagnostic],
+        dir: &Path,
+    ) -> Result<(), LxdError> {
+        log::debug!("collect diagnostics of {}", name);
+
+        self.list_node_by_name(remote, name).map_err(|e| match e {
+            LxcCliAllocatorError::NodeNotFound => {
+                LxdError::NotFound(format!("node {} not found", remote_name(remote, name)))
+            }
+            _ => LxdError::Executor(e.to_string()),
+        })?;
+        std::fs::create_dir_all(dir).map_err(|e| {
+            LxdError::Executor(format!("cannot create directory {}: {}", dir.display(), e))
+        })?;
+
+        let name = remote_name(remote, name);
+        for diagnostic in diagnostics {
+            if let Err(err) =
+                self.collect_diagnostic(&name, diagnostic, &dir.join(&diagnostic.name))
+            {
+                log::warn!("cannot collect {} of {}: {}", diagnostic, name, err);
+            }
+        }
+        Ok(())
+    }
+
+    fn discard_by_name(&mut self, remote: Option<&str>, name: &str) -> Result<(), LxdError> {
+        log::debug!("discard by name '{}'", name);
+
+        self.delete_node(LXD_PROJECT_NAME, remote, name)
+            .map_err(|e| match e {
+                LxcCliAllocatorError::NodeNotFound => {
+                    LxdError::NotFound(format!("node {} not found", remote_name(remote, name)))
+                }
+                _ => LxdError::Discard(e.to_string()),
+            })
+    }
+
+    fn keep_node(
+        &mut self,
+        remote: Option<&str>,
+        name: &str,
+        kept_name: &str,
+        mode: LxdPostmortemMode,
+        export: Option<&Path>,
+    ) -> Result<(), LxdError> {
+        log::debug!("keep {} as {}", name, kept_name);
+
+        let name_arg = remote_name(remote, name);
+        let kept_arg = remote_name(remote, kept_name);
+        let keep_err = |e: LxcRunnerError| {
+            LxdError::Discard(format!("cannot keep node for post-mortem: {}", e))
+        };
+
+        // ephemeral instances are deleted once stopped
+        self.run_in_project(
+            LXD_PROJECT_NAME,
+            &["config", "set", &name_arg, "ephemeral=false"],
+        )
+        .map_err(keep_err)?;
+        // the forwarded port is released for other nodes
+        let devices = self
+            .run_in_project(LXD_PROJECT_NAME, &["config", "device", "list", &name_arg])
+            .map_err(keep_err)?;
+        if String::from_utf8_lossy(&devices)
+            .lines()
+            .any(|d| d.trim() == "ssh-forward")
+        {
+            self.run_in_project(
+                LXD_PROJECT_NAME,
+                &["config", "device", "remove", &name_arg, "ssh-forward"],
+            )
+            .map_err(keep_err)?;
+        }
+        if let Err(err) =
+            self.run_in_project(LXD_PROJECT_NAME, &["stop", "--timeout=30", &name_arg])
+        {
+            log::debug!("cannot stop {} cleanly: {}", name_arg, err);
+            self.run_in_project(LXD_PROJECT_NAME, &["stop", "--force", &name_arg])
+                .map_err(keep_err)?;
+        }
+
+        match (mode, export) {
+            (LxdPostmortemMode::Export, Some(path)) => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir).map_err(|e| {
+                        LxdError::Discard(format!(
+                            "cannot create directory {}: {}",
+                            dir.display(),
+                            e
+                        ))
+                    })?;
+                }
+                self.run_in_project(
+                    LXD_PROJECT_NAME,
+                    &["export", &name_arg, &path.to_string_lossy()],
+                )
+                .map_err(keep_err)?;
+            }
+            (LxdPostmortemMode::Export, None) => {
+                return Err(LxdError::Discard(
+                    "cannot keep node for post-mortem: no file to export to".to_string(),
+                ));
+            }
+            _ => {
+                self.run_in_project(
+                    LXD_PROJECT_NAME,
+                    &["snapshot", &name_arg, POSTMORTEM_SNAPSHOT_NAME],
+                )
+                .map_err(keep_err)?;
+            }
+        }
+
+        self.run_in_project(
+            LXD_PROJECT_NAME,
+            &[
+                "move",
+                &name_arg,
+                &kept_arg,
+                "--target-project",
+                LXD_POSTMORTEM_PROJECT_NAME,
+            ],
+        )
+        .map_err(keep_err)
+        .map(|_| ())
+    }
+
+    fn delete_kept_node(&mut self, remote: Option<&str>, name: &str) -> Result<(), LxdError> {
+        log::debug!("delete kept node '{}'", name);
+
+        self.delete_node(LXD_POSTMORTEM_PROJECT_NAME, remote, name)
+            .map_err(|e| match e {
+                LxcCliAllocatorError::NodeNotFound => {
+                    LxdError::NotFound(format!("node {} not found", remote_name(remote, name)))
+                }
+                _ => LxdError::Discard(e.to_string()),
+            })
+    }
+
+    fn shell_kept_node(&mut self, remote: Option<&str>, name: &str) -> Result<(), LxdError> {
+        let name = remote_name(remote, name);
+        match self.run_in_project(LXD_POSTMORTEM_PROJECT_NAME, &["start", &name]) {
+            Ok(_) => {}
+            Err(LxcRunnerError::Execution { ref stderr, .. })
+                if stderr.contains("already running") => {}
+            Err(LxcRunnerError::Execution { ref stderr, .. }) if stderr.contains("not found") => {
+                return Err(LxdError::NotFound(format!("node {} not found", name)));
+            }
+            Err(e) => return Err(LxdError::Executor(format!("cannot start node: {}", e))),
+        }
+
+        let code = self
+            .runner
+            .run_interactive(
+                LxcCommandBuilder::new()
+                    .with_scope(LxcCommandScope::Project(LXD_POSTMORTEM_PROJECT_NAME))
+                    .args(&["exec", &name, "--", "su", "-l"])
+                    .build(),
+            )
+            .map_err(|e| LxdError::Executor(e.to_string()))?;
+        log::debug!("shell exited with status {}", code);
+        Ok(())
+    }
+
+    fn discard_all(&mut self, remote: Option<&str>) -> Result<(), LxdError> {
         let nodes = self
-            .list_nodes()
+            .list_nodes(remote)
             .map_err(|e| LxdError::Discard(e.to_string()))?;
         log::debug!("discard {} nodes: {:?}", nodes.len(), nodes);
 
         for node in nodes {
-            self.discard_by_name(&node.name)
-                .map_err(|e| LxdError::Discard(e.to_string()))?;
+            self.discard_by_name(remote, &node.name)?;
         }
 
         Ok(())
     }
 
-    fn ensure_project(&mut self, project: &str) -> Result<(), LxdError> {
+    fn ensure_project(&mut self, remote: Option<&str>, project: &str) -> Result<(), LxdError> {
         #[derive(serde::Deserialize, Debug)]
         struct _LxcProject {
             name: String,
         }
 
-        let found = self
-            .runner
-            .run(
-                LxcCommandBuilder::new()
-                    .args(&["project", "list", "--format=json"])
-                    .build(),
-            )
-            .and_then(|output| {
-                let found = serde_json::from_slice::<Vec<_LxcProject>>(&output)
-                    .expect("cannot parse project JSON")
-                    .iter()
-                    .find(|p| p.name == project)
-                    .is_some();
-
-                debug!("project found {}", found);
+        let _lock = self
+            .project_lock
+            .as_deref()
+            .map(FileLock::acquire)
+            .transpose()
+            .map_err(|e| LxdError::Executor(format!("cannot lock project setup: {}", e)))?;
+
+        let remote_arg = remote.map(|r| remote_name(Some(r), ""));
+        let mut args = vec!["project", "list", "--format=json"];
+        if let Some(remote_arg) = remote_arg.as_deref() {
+            args.push(remote_arg);
+        }
 
-                Ok(found)
-            })
+        let output = self
+            .runner
+            .run(LxcCommandBuilder::new().args(&args).build())
             .map_err(|e| LxdError::Executor(e.to_string()))?;
+        let found = parse_lxc_output::<Vec<_LxcProject>>("project list", &output)
+            .map_err(|e| LxdError::Executor(e.to_string()))?
+            .iter()
+            .any(|p| p.name == project);
+
+        debug!("project found {}", found);
 
         if !found {
-            self.add_project(project)
+            self.add_project(remote, project)
                 .map_err(|e| LxdError::Executor(e.to_string()))
         } else {
             Ok(())
         }
     }
-}
-
-const LXD_PROJECT_NAME: &str = "spread-adhoc";
-
-/// Spread node allocator using LXD backend.
-pub struct LxdAllocator {
-    backend: Box<dyn LxdAllocatorExecutor>,
-    conf: LxdBackendConfig,
-}
-
-impl allocator::NodeAllocator for LxdAllocator {
-    /// Allocate a node for a spread system and set up remote access for the
-    /// user.
-    fn allocate_by_name(
-        &mut self,
-        sysname: &str,
-        user_config: allocator::RemoteUserAccessConfig,
-    ) -> Result<allocator::Node, allocator::Error> {
-        let sysconf = if let Some(sysconf) = self.conf.system.get(sysname) {
-            sysconf
-        } else {
-            return Err(LxdError::NotFound(format!(
-                "system \"{}\" not found in configuration",
-                sysname
-            ))
-            .into());
-        };
-
-        let steps = if let Some(setup_steps) = sysconf.setup_steps.as_ref() {
-            if let Some(steps) = self.conf.setup.get(setup_steps) {
-                steps
-            } else {
-                return Err(LxdError::NotFound(format!(
-                    "setup steps \"{}\" not found in configuration",
-                    setup_steps
-                ))
-                .into());
-            }
-        } else {
-            log::warn!("no setup steps declared for this system");
-            &vec![]
-        };
-
-        // TODO validate user & password
-        let mut steps = steps.clone();
 
-        steps.push(format!(
-            "echo {}:{} | chpasswd",
-            user_config.user, user_config.password
-        ));
+    fn server_info(&mut self, remote: Option<&str>) -> Result<LxdServerInfo, LxdError> {
+        let path = remote_name(remote, "/1.0");
+        let output = self
+            .runner
+            .run(LxcCommandBuilder::new().args(&["query", &path]).build())
+            .map_err(|e| LxdError::Executor(e.to_string()))?;
+        let server = parse_lxc_output::<lxc::types::Server>("server info", &output)
+            .map_err(|e| LxdError::Executor(e.to_string()))?;
 
-        let name = format!("{}-{}", sysname, random::<u32>());
+        Ok(LxdServerInfo {
+            clustered: server.environment.server_clustered,
+            architectures: server.environment.architectures,
+        })
+    }
 
-        self.backend.ensure_project(LXD_PROJECT_NAME)?;
+    fn cluster_members(&mut self, remote: Option<&str>) -> Result<Vec<LxdClusterMember>, LxdError> {
+        let remote_arg = remote.map(|r| remote_name(Some(r), ""));
+        let mut args = vec!["cluster", "list", "--format=json"];
+        if let Some(remote_arg) = remote_arg.as_deref() {
+            args.push(remote_arg);
+        }
 
-        self.backend
-            .allocate(&LxdNodeDetails {
-                image: &sysconf.image,
-                cpu: sysconf.resources.cpu,
-                memory: sysconf.resources.mem.as_u64(),
-                name: &name,
-                root_size: sysconf.resources.size.as_u64(),
-                secure_boot: sysconf.secure_boot,
-                provision_steps: &steps,
-            })
-            .and_then(|node| {
-                Ok(allocator::Node {
-                    addr: node.addr,
-                    ssh_port: node.ssh_port,
-                })
+        let output = self
+            .runner
+            .run(LxcCommandBuilder::new().args(&args).build())
+            .map_err(|e| LxdError::Executor(e.to_string()))?;
+        let members =
+            parse_lxc_output::<Vec<lxc::types::ClusterMember>>("cluster members", &output)
+                .map_err(|e| LxdError::Executor(e.to_string()))?;
+
+        Ok(members
+            .into_iter()
+            .map(|m| LxdClusterMember {
+                online: m.status == "Online",
+                name: m.server_name,
+                architecture: m.architecture,
             })
-            .map_err(|err| allocator::Error::Operation(err.to_string()))
+            .collect())
     }
 
-    /// Discard a node associated with a given address.
-    fn discard_by_addr(&mut self, addr: &str) -> Result<(), allocator::Error> {
-        Ok(self.backend.discard_by_addr(addr)?)
+    fn list_allocations(&mut self, remote: Option<&str>) -> Result<Vec<LxdNodeUsage>, LxdError> {
+        let output = self
+            .list(remote)
+            .map_err(|e| LxdError::Executor(e.to_string()))?;
+        let instances =
+            parse_lxc_output::<Vec<lxc::types::InstanceSummary>>("instance list", &output)
+                .map_err(|e| LxdError::Executor(e.to_string()))?;
+
+        Ok(instances
+            .into_iter()
+            .map(|i| LxdNodeUsage {
+                cpu: i
+                    .config
+                    .get("limits.cpu")
+                    .and_then(|v| v.parse::<u32>().ok())
+                    .unwrap_or(0),
+                memory: i
+                    .config
+                    .get("limits.memory")
+                    .and_then(|v| v.parse::<bytesize::ByteSize>().ok())
+                    .map(|v| v.as_u64())
+                    .unwrap_or(0),
+                name: i.name,
+                location: i.location,
+            })
+            .collect())
     }
+}
 
-    /// Discard all nodes.
-    fn discard_all(&mut self) -> Result<(), allocator::Error> {
-        Ok(self.backend.discard_all()?)
+const LXD_PROJECT_NAME: &str = "spread-adhoc";
+/// Project of nodes kept for post-mortem, such that they are neither counted
+/// against the budget, nor listed or cleaned up with allocated ones.
+const LXD_POSTMORTEM_PROJECT_NAME: &str = "spread-adhoc-postmortem";
+/// Snapshot of a node kept for post-mortem, with its state at discard.
+const POSTMORTEM_SNAPSHOT_NAME: &str = "postmortem";
+
+/// Selects a cluster member for placing a node, taking the architecture and
+/// placement strategy into account. Returns None when the node can be placed on
+/// the server itself, or the choice is left to LXD. An image alias resolves to
+/// the variant of the primary architecture of a server, thus other
+/// architectures of a standalone server need an image per architecture. The
+/// round robin counter is advanced through a callback which is given the number
+/// of candidates.
+fn select_target(
+    backend: &mut dyn LxdAllocatorExecutor,
+    remote: Option<&str>,
+    arch: Option<LxdArchitecture>,
+    image_per_arch: bool,
+    placement: Option<&LxdPlacement>,
+    next_round_robin: &mut dyn FnMut(usize) -> usize,
+) -> Result<Option<String>, LxdError> {
+    if arch.is_none() && placement.is_none() {
+        return Ok(None);
     }
-}
 
-impl LxdAllocator {
-    /// Returns a new, unconfigured allocator.
-    fn new() -> Self {
-        LxdAllocator {
-            conf: LxdBackendConfig {
-                setup: HashMap::new(),
-                system: HashMap::new(),
-            },
-            backend: Box::new(LxdCliAllocator::<LxcCommandRunner>::new(
-                LxcCommandRunner {},
-            )),
+    let info = backend.server_info(remote)?;
+
+    if !info.clustered {
+        if let Some(arch) = arch {
+            if !info.architectures.iter().any(|a| a == arch.lxd_name()) {
+                return Err(LxdError::ConfigInvalid(format!(
+                    "architecture {} is not supported by LXD server, supported: {}",
+                    arch.lxd_name(),
+                    info.architectures.join(", ")
+                )));
+            }
+            if !image_per_arch
+                && info.architectures.first().map(String::as_str) != Some(arch.lxd_name())
+            {
+                return Err(LxdError::ConfigInvalid(format!(
+                    "architecture {} is not the primary one of LXD server, an image per architecture is needed",
+                    arch.lxd_name()
+                )));
+            }
+        }
+        if placement.is_some() {
+            log::warn!("LXD server is not clustered, ignoring placement");
         }
+        return Ok(None);
     }
 
-    fn new_with_config(conf: LxdBackendConfig) -> Self {
-        LxdAllocator {
-            conf: conf,
-            backend: Box::new(LxdCliAllocator::<LxcCommandRunner>::new(
-                LxcCommandRunner {},
-            )),
-        }
+    let members = backend.cluster_members(remote)?;
+    let candidates: Vec<&LxdClusterMember> = members
+        .iter()
+        .filter(|m| m.online && arch.is_none_or(|arch| m.architecture == arch.lxd_name()))
+        .collect();
+
+    if candidates.is_empty() {
+        let members = members
+            .iter()
+            .map(|m| format!("{} ({})", m.name, m.architecture))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(LxdError::ConfigInvalid(match arch {
+            Some(arch) => format!(
+                "no online cluster member with architecture {}, available: {}",
+                arch.lxd_name(),
+                members
+            ),
+            None => format!("no online cluster members, available: {}", members),
+        }));
     }
-}
 
-fn default_mem() -> bytesize::ByteSize {
-    bytesize::ByteSize(bytesize::gib(2 as u64))
+    let member = match placement.map(|p| &p.strategy) {
+        // keep the original behavior, the first member able to run the node
+        None => candidates[0],
+        Some(LxdPlacementStrategy::Fixed) => {
+            let target = placement
+                .and_then(|p| p.target.as_deref())
+                .expect("fixed placement without target");
+            match candidates.iter().find(|m| m.name == target) {
+                Some(member) => member,
+                None => {
+                    return Err(LxdError::ConfigInvalid(format!(
+                        "cluster member {} is not available for placement",
+                        target
+                    )))
+                }
+            }
+        }
+        Some(LxdPlacementStrategy::RoundRobin) => {
+            candidates[next_round_robin(candidates.len()) % candidates.len()]
+        }
+        Some(LxdPlacementStrategy::LeastAllocated) => {
+            let mut used: HashMap<&str, u64> = HashMap::new();
+            let allocations = backend.list_allocations(remote)?;
+            for node in allocations.iter() {
+                *used.entry(&node.location).or_default() += node.memory;
+            }
+            candidates
+                .iter()
+                .min_by_key(|m| used.get(m.name.as_str()).copied().unwrap_or(0))
+                .expect("no candidates")
+        }
+    };
+
+    log::debug!("selected cluster member {}", member.name);
+    Ok(Some(member.name.clone()))
 }
 
-fn default_cpu() -> u32 {
-    2
+/// Ensures a project exists, unless it is among given known projects, which
+/// it is added to.
+fn ensure_project(
+    backend: &mut dyn LxdAllocatorExecutor,
+    known: &mut HashSet<(Option<String>, &'static str)>,
+    remote: Option<&str>,
+    project: &'static str,
+) -> Result<(), LxdError> {
+    let key = (remote.map(str::to_string), project);
+    if !known.contains(&key) {
+        backend.ensure_project(remote, project)?;
+        known.insert(key);
+    }
+    Ok(())
 }
 
-fn default_root_size() -> bytesize::ByteSize {
-    bytesize::ByteSize(bytesize::gib(10 as u64))
+/// Waits until a node with given resources fits in the budget.
+///
+/// When state is available, the check is done while holding the state lock and
+/// the resources are reserved for the node under a token of the request, such
+/// that concurrent allocations account for each other.
+fn admit(
+    backend: &mut dyn LxdAllocatorExecutor,
+    state: Option<&StateStore<LxdState>>,
+    budget: Option<&LxdBudget>,
+    remote: Option<&str>,
+    name: &str,
+    token: u64,
+    resources: &LxdNodeResources,
+) -> Result<(), LxdError> {
+    let cpu = resources.cpu;
+    let memory = resources.mem.as_u64();
+    let budget = match budget {
+        Some(budget) => budget,
+        None => return Ok(()),
+    };
+
+    if !budget.fits(cpu, memory) {
+        return Err(LxdError::Capacity(format!(
+            "node with {} CPUs and {} of memory exceeds the budget",
+            cpu,
+            bytesize::ByteSize(memory).to_string_as(true)
+        )));
+    }
+
+    let timeout = time::Duration::from_secs(budget.queue_timeout);
+    let now = Instant::now();
+    loop {
+        let verdict = match state {
+            Some(state) => state
+                .update(|st| -> Result<Result<(), String>, LxdError> {
+                    let mut existing = backend.list_allocations(remote)?;
+
+                    let now = SystemTime::now();
+                    st.reservations.retain(|r| r.is_live(now));
+                    for r in st.reservations.iter() {
+                        // nodes which were already launched are listed
+                        if r.remote.as_deref() == remote
+                            && !existing.iter().any(|n| n.name == r.name)
+                        {
+                            existing.push(LxdNodeUsage {
+                                name: r.name.clone(),
+                                location: String::new(),
+                                cpu: r.cpu,
+                                memory: r.memory,
+                            });
+                        }
+                    }
+
+                    let verdict = budget.check(&existing, cpu, memory);
+                    if verdict.is_ok() {
+                        st.reservations.push(LxdReservation {
+                            name: name.to_string(),
+                            remote: remote.map(str::to_string),
+                            cpu,
+                            memory,
+                            token,
+                            expires_at: unix_time(now + RESERVATION_LIFETIME),
+                            pid: std::process::id(),
+                        });
+                    }
+                    Ok(verdict)
+                })
+                .map_err(|e| LxdError::Executor(format!("cannot update state: {}", e)))??,
+            None => budget.check(&backend.list_allocations(remote)?, cpu, memory),
+        };
+
+        match verdict {
+            Ok(()) => return Ok(()),
+            Err(reason) if now.elapsed() >= timeout => {
+                return Err(LxdError::Capacity(format!(
+                    "{}, gave up after {}s",
+                    reason,
+                    now.elapsed().as_secs()
+                )))
+            }
+            Err(reason) => {
+                log::info!("waiting for resources: {}", reason);
+                thread::sleep(ADMISSION_POLL_INTERVAL);
+            }
+        }
+    }
 }
 
-/// Resources assigned to a node.
-#[derive(serde::Deserialize, Debug)]
-struct LxdNodeResources {
-    /// RAM
-    #[serde(default = "default_mem")]
-    mem: bytesize::ByteSize,
-    /// Number of CPUs.
-    #[serde(default = "default_cpu")]
-    cpu: u32,
-    /// Root disk size (applicable to VM).
-    #[serde(default = "default_root_size")]
-    size: bytesize::ByteSize,
+/// Collects diagnostics of a node into a directory named after it, under a
+/// given artifacts directory. Returns the directory of the node.
+fn collect_node_diagnostics(
+    backend: &mut dyn LxdAllocatorExecutor,
+    artifacts_dir: Option<&Path>,
+    remote: Option<&str>,
+    name: &str,
+    diagnostics: &[LxdDiagnostic],
+) -> Result<PathBuf, LxdError> {
+    let Some(artifacts_dir) = artifacts_dir else {
+        return Err(LxdError::Executor(
+            "no state directory to collect diagnostics into".to_string(),
+        ));
+    };
+    if diagnostics.is_empty() {
+        return Err(LxdError::ConfigInvalid(
+            "no diagnostics declared in configuration".to_string(),
+        ));
+    }
+
+    let dir = artifacts_dir.join(name);
+    backend.collect_diagnostics(remote, name, diagnostics, &dir)?;
+    Ok(dir)
 }
 
-impl Default for LxdNodeResources {
-    fn default() -> Self {
-        LxdNodeResources {
-            mem: default_mem(),
-            cpu: default_cpu(),
-            size: default_root_size(),
+/// Picks a host port in a given range for forwarding to a node, such that it
+/// is used neither by allocated nodes, nor by ones which are being allocated.
+/// The port remains reserved until the node is recorded, or if its allocation
+/// fails, until the node left behind is deleted.
+fn reserve_port(
+    backend: &mut dyn LxdAllocatorExecutor,
+    state: Option<&StateStore<LxdState>>,
+    remote: Option<&str>,
+    name: &str,
+    ports: &LxdPortRange,
+) -> Result<u16, LxdError> {
+    let state = match state {
+        Some(state) => state,
+        None => {
+            log::warn!("no state to track used ports, picking a random one");
+            let count = u32::from(ports.last - ports.first) + 1;
+            return Ok(ports.first + (random::<u32>() % count) as u16);
+        }
+    };
+
+    // nodes of failed allocations may have been deleted behind our back
+    let failed: Vec<LxdPortReservation> = state
+        .load()
+        .map(|st| st.ports.into_iter().filter(|r| r.failed).collect())
+        .unwrap_or_default();
+    let mut gone = vec![];
+    let mut listed: HashMap<Option<String>, Vec<String>> = HashMap::new();
+    for r in failed {
+        if !listed.contains_key(&r.remote) {
+            match backend.list_allocations(r.remote.as_deref()) {
+                Ok(usage) => {
+                    listed.insert(
+                        r.remote.clone(),
+                        usage.into_iter().map(|u| u.name).collect(),
+                    );
+                }
+                Err(err) => {
+                    log::debug!("cannot list nodes holding forwarded ports: {}", err);
+                    continue;
+                }
+            }
+        }
+        if !listed[&r.remote].contains(&r.name) {
+            gone.push(r);
         }
     }
+
+    state
+        .update(|st| {
+            st.ports.retain(|r| r.is_live() && !gone.contains(r));
+            let port = (ports.first..=ports.last).find(|port| {
+                !st.nodes.iter().any(|n| n.ssh_port == u32::from(*port))
+                    && !st.ports.iter().any(|r| r.port == *port)
+            });
+            if let Some(port) = port {
+                st.ports.push(LxdPortReservation {
+                    name: name.to_string(),
+                    remote: remote.map(str::to_string),
+                    port,
+                    pid: std::process::id(),
+                    failed: false,
+                });
+            }
+            port
+        })
+        .map_err(|e| LxdError::Executor(format!("cannot update state: {}", e)))?
+        .ok_or_else(|| {
+            LxdError::Capacity(format!("no free port in range {} to forward SSH to", ports))
+        })
 }
 
-/// Configuration for a new LXD node.
-#[derive(serde::Deserialize, Debug)]
-struct LxdNodeConfig {
-    /// Image to use.
-    image: String,
-    /// Setup steps.
-    #[serde(rename = "setup-steps")]
-    setup_steps: Option<String>,
-    /// Resources configuration.
+/// Record of a node allocated by this allocator.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct LxdNodeRecord {
+    name: String,
+    system: String,
     #[serde(default)]
-    resources: LxdNodeResources,
-    /// Secure boot support (applicable to VMs).
-    #[serde(rename = "secure-boot", default)]
-    secure_boot: bool,
-    /// Whether the system is a VM.
+    remote: Option<String>,
     #[serde(default)]
-    vm: bool,
+    target: Option<String>,
+    addr: String,
+    ssh_port: u32,
+    /// Time of allocation, RFC 3339.
+    #[serde(default)]
+    allocated_at: Option<String>,
 }
 
-/// Configuration for the LXD backend.
-#[derive(serde::Deserialize, Debug, Default)]
-struct LxdBackendConfig {
-    /// Systems with their properties, keyed by spread system name.
+impl LxdNodeRecord {
+    /// Returns the node described by the record.
+    fn node(&self) -> Option<allocator::Node> {
+        Some(allocator::Node {
+            name: remote_name(self.remote.as_deref(), &self.name),
+            system: self.system.clone(),
+            addr: self.addr.parse().ok()?,
+            ssh_port: self.ssh_port,
+            allocated_at: self.allocated_at.clone(),
+        })
+    }
+}
+
+/// State of the LXD allocator.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+struct LxdState {
+    /// Allocated nodes.
     #[serde(default)]
-    system: HashMap<String, LxdNodeConfig>,
-    /// Setup steps.
+    nodes: Vec<LxdNodeRecord>,
+    /// Round robin placement counters, keyed by remote.
     #[serde(default)]
-    setup: HashMap<String, Vec<String>>,
+    round_robin: HashMap<String, usize>,
+    /// Resources reserved for nodes being allocated.
+    #[serde(default)]
+    reservations: Vec<LxdReservation>,
+    /// Host ports reserved for forwarding to nodes being allocated, or left
+    /// behind by failed allocations.
+    #[serde(default)]
+    ports: Vec<LxdPortReservation>,
+    /// Nodes kept for post-mortem, in the order they were discarded.
+    #[serde(default)]
+    postmortem: Vec<LxdPostmortemRecord>,
 }
 
-/// User configuration for the LXD backend.
-#[derive(serde::Deserialize, Debug, Default)]
-struct LxdBackendUserConfig {}
+/// Node kept for post-mortem in the post-mortem project.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct LxdPostmortemRecord {
+    /// Name of the kept instance.
+    name: String,
+    #[serde(default)]
+    remote: Option<String>,
+    system: String,
+    /// Name of the instance while it was allocated.
+    instance: String,
+    /// Time of discard, RFC 3339.
+    discarded_at: String,
+    /// Exported instance.
+    #[serde(default)]
+    export: Option<PathBuf>,
+}
 
-/// Builder for creating LxdAllocator.
-pub struct LxdAllocatorBuilder {
-    cfg: LxdBackendConfig,
-    user_cfg: LxdBackendUserConfig,
+/// Resources reserved for a node which is being allocated.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct LxdReservation {
+    name: String,
+    #[serde(default)]
+    remote: Option<String>,
+    cpu: u32,
+    memory: u64,
+    /// Token of the request which made the reservation.
+    #[serde(default)]
+    token: u64,
+    /// Time the reservation lapses at, in seconds since the epoch.
+    #[serde(default)]
+    expires_at: u64,
+    /// Process which made the reservation.
+    pid: u32,
 }
 
-impl LxdAllocatorBuilder {
-    pub fn new() -> Self {
-        LxdAllocatorBuilder {
-            cfg: Default::default(),
-            user_cfg: Default::default(),
-        }
+impl LxdReservation {
+    /// Whether the reservation holds at a given time. A daemon serves all
+    /// requests from one process, thus a reservation left behind by a request
+    /// lapses at a deadline, or earlier when its process is gone.
+    fn is_live(&self, now: SystemTime) -> bool {
+        unix_time(now) < self.expires_at && is_process_live(self.pid)
+    }
+}
+
+/// Returns a time in seconds since the epoch.
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Host port reserved for a node which is being allocated, or whose
+/// allocation failed, in which case the node left behind may still listen on
+/// the port.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct LxdPortReservation {
+    name: String,
+    #[serde(default)]
+    remote: Option<String>,
+    port: u16,
+    /// Process which made the reservation.
+    pid: u32,
+    /// Allocation of the node failed, the reservation is held until the node
+    /// is deleted.
+    #[serde(default)]
+    failed: bool,
+}
+
+impl LxdPortReservation {
+    /// Whether the reservation holds, that is the node failed, or the process
+    /// which made the reservation is still around.
+    fn is_live(&self) -> bool {
+        self.failed || is_process_live(self.pid)
     }
+}
 
-    pub fn with_config<R>(mut self, cfg: R) -> Result<Self, LxdError>
-    where
-        R: io::Read,
-    {
-        let conf: LxdBackendConfig =
-            serde_yml::from_reader(cfg).map_err(|e| LxdError::Config(e))?;
-        log::debug!("config: {:?}", conf);
+fn is_process_live(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
 
-        // validate configuration consistency:
-        // - system setup steps are found
+const LXD_STATE_FILE_NAME: &str = "lxd-state.json";
+const LXD_PROJECT_LOCK_FILE_NAME: &str = "lxd-project.lock";
+const LOGS_DIR_NAME: &str = "logs";
+const ARTIFACTS_DIR_NAME: &str = "artifacts";
 
-        for (&ref sysname, &ref sysconf) in &conf.system {
-            if let Some(setup_steps) = sysconf.setup_steps.as_ref() {
-                if let None = conf.setup.get(setup_steps) {
-                    return Err(LxdError::ConfigInvalid(format!(
-                        "system \"{}\" is invalid, setup steps \"{}\" not found in configuration",
-                        sysname, setup_steps
-                    )));
-                }
-            }
-        }
+/// Lifetime of a resource reservation. Once the instance is created, its
+/// resources are accounted for by listing it, thus the reservation only needs
+/// to cover launching, including the download of the image.
+const RESERVATION_LIFETIME: time::Duration = time::Duration::from_secs(30 * 60);
 
-        self.cfg = conf;
-        Ok(self)
+/// Interval of checking whether resources have become available.
+const ADMISSION_POLL_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// Spread node allocator using LXD backend.
+pub struct LxdAllocator {
+    backend: Box<dyn LxdAllocatorExecutor>,
+    conf: LxdBackendConfig,
+    user_conf: LxdBackendUserConfig,
+    state: Option<StateStore<LxdState>>,
+    /// Directory for allocation logs.
+    logs_dir: Option<PathBuf>,
+    /// Directory for diagnostics collected from nodes.
+    artifacts_dir: Option<PathBuf>,
+    /// Destination of audit events.
+    events: Box<dyn EventSink>,
+    /// Projects known to exist, keyed by remote, such that an allocator kept
+    /// between requests does not check them each time.
+    projects: HashSet<(Option<String>, &'static str)>,
+}
+
+impl allocator::NodeAllocator for LxdAllocator {
+    /// Allocate a node for a spread system and set up remote access for the
+    /// user.
+    fn allocate_by_name(
+        &mut self,
+        sysname: &str,
+        user_config: allocator::RemoteUserAccessConfig,
+    ) -> Result<allocator::Node, allocator::Error> {
+        let started = SystemTime::now();
+        let name = lxdfy_name(&format!("{}-{}", sysname, random::<u32>()));
+
+        let _node_log = self.logs_dir.as_ref().and_then(|dir| {
+            let path = dir.join(format!("{}.log", name));
+            logging::node_log(&path)
+                .inspect(|_| log::info!("allocation log {}", path.display()))
+                .inspect_err(|err| log::warn!("cannot set up allocation log: {}", err))
+                .ok()
+        });
+
+        let res = self.allocate_node(sysname, &name, user_config);
+
+        let allocated = res.as_ref().ok();
+        self.events.record(
+            &Event::new(audit::Operation::Allocate, started, &res)
+                .with_system(sysname)
+                .with_instance(Some(&name))
+                .with_address(allocated.map(|(n, _)| n.addr.to_string()).as_deref())
+                .with_phases(
+                    allocated
+                        .map(|(_, a)| a.phases.as_slice())
+                        .unwrap_or_default(),
+                ),
+        );
+
+        res.map(|(node, _)| node)
     }
 
-    pub fn with_optional_user_config<R>(mut self, cfg: Option<R>) -> Result<Self, LxdError>
-    where
-        R: io::Read,
-    {
-        if let Some(cfg) = cfg {
-            let conf: LxdBackendUserConfig =
-                serde_yml::from_reader(cfg).map_err(|e| LxdError::Config(e))?;
-            log::debug!("user config: {:?}", conf);
+    /// Discard a node associated with a given address.
+    fn discard_by_addr(&mut self, addr: &str) -> Result<Option<allocator::Node>, allocator::Error> {
+        let started = SystemTime::now();
+        // nodes reached through forwarded ports share the host address
+        let (ip, port) = match addr.parse::<allocator::NodeRef>() {
+            Ok(allocator::NodeRef::Addr(ip, port)) => (ip.to_string(), port),
+            _ => (addr.to_string(), None),
+        };
+        let record = match self.record_by_addr(addr, &ip, port) {
+            Ok(record) => record,
+            Err(err) => return self.discarded(started, Err(err), None, None, Some(addr)),
+        };
 
-            self.user_cfg = conf;
+        let keep = self.user_conf.postmortem.mode != LxdPostmortemMode::Off;
+        let res = if let Some(record) = record.as_ref() {
+            log::debug!("found node record {:?}", record);
+            if keep {
+                self.keep_node(record)
+            } else {
+                self.backend
+                    .discard_by_name(record.remote.as_deref(), &record.name)
+            }
+        } else {
+            self.backend.discard_by_addr(None, &ip)
+        };
+
+        let kept = keep && record.is_some();
+        let res = self.discarded(started, res, record, None, Some(addr));
+        if kept {
+            self.reap_kept_nodes();
         }
-        Ok(self)
+        res
     }
 
-    pub fn build(self) -> LxdAllocator {
-        LxdAllocator::new_with_config(self.cfg)
-        // TODO apply user config
-    }
-}
+    /// Discard a node with a given name, optionally qualified with a remote.
+    fn discard_by_name(&mut self, name: &str) -> Result<Option<allocator::Node>, allocator::Error> {
+        let started = SystemTime::now();
+        let (remote, instance) = match name.split_once(':') {
+            Some((remote, instance)) => (Some(remote), instance),
+            None => (None, name),
+        };
+        let record = self
+            .load_state()
+            .nodes
+            .into_iter()
+            .find(|n| n.name == instance && n.remote.as_deref() == remote);
 
-/// Returns the file name of a LXD node allocator.
-pub fn config_file_name() -> &'static str {
-    return "spread-lxd.yaml";
-}
+        let res = self.backend.discard_by_name(remote, instance);
 
-#[cfg(test)]
-mod tests {
-    use std::{collections::VecDeque, str::FromStr};
+        self.discarded(started, res, record, Some(instance), None)
+    }
 
-    use super::*;
+    /// Collect diagnostics declared for the system of a node into the
+    /// artifacts directory in the state directory.
+    fn collect_diagnostics(
+        &mut self,
+        node: &allocator::NodeRef,
+    ) -> Result<PathBuf, allocator::Error> {
+        let (remote, name, system) = match node {
+            allocator::NodeRef::Addr(ip, port) => {
+                let record = self
+                    .record_by_addr(&node.to_string(), &ip.to_string(), *port)?
+                    .ok_or_else(|| LxdError::NotFound(format!("node {} not found", node)))?;
+                (record.remote, record.name, Some(record.system))
+            }
+            allocator::NodeRef::Name(name) => {
+                let (remote, instance) = match name.split_once(':') {
+                    Some((remote, instance)) => (Some(remote.to_string()), instance.to_string()),
+                    None => (None, name.clone()),
+                };
+                let system = self
+                    .load_state()
+                    .nodes
+                    .into_iter()
+                    .find(|n| n.name == instance && n.remote == remote)
+                    .map(|n| n.system);
+                (remote, instance, system)
+            }
+        };
 
-    const ONE_PROJECT_LIST: &str = r##"[{
-"name":"default","description":"Default LXD project","config":{"features.images":"true","features.networks":"true","features.networks.zones":"true","features.profiles":"true","features.storage.buckets":"true","features.storage.volumes":"true"},"used_by":["/1.0/profiles/default","/1.0/images/16c5963a3c55d17639f96099f8133d986601dbafc79c53d26ba384cbcfcd5bad","/1.0/networks/lxdbr0"]},{"name":"snapcraft","description":"","config":{"features.images":"true","features.profiles":"true","features.storage.buckets":"true","features.storage.volumes":"true"},"used_by":["/1.0/profiles/default?project=snapcraft"]},{"name":"spread-adhoc","description":"","config":{"features.images":"false","features.profiles":"false","features.storage.buckets":"true","features.storage.volumes":"true"},"used_by":["/1.0/storage-pools/default/volumes/virtual-machine/ubuntu-24-04-64-1744396627?project=spread-adhoc","/1.0/instances/ubuntu-24-04-64-1744396627?project=spread-adhoc"]
-}]"##;
-    const ONE_NODE_LIST: &str = r##"[{
-"name":"ubuntu-24-04-64-1744396627",
-"description":"","status":"Running","status_code":103,"created_at":"2025-01-26T14:33:11.319917616Z","last_used_at":"2025-01-26T14:33:19.637141509Z","location":"none","type":"virtual-machine","project":"spread-adhoc","architecture":"x86_64","ephemeral":true,"stateful":false,"profiles":["default"],"config":{"image.architecture":"amd64","image.description":"ubuntu 24.04 LTS amd64 (release) (20250115)","image.label":"release","image.os":"ubuntu","image.release":"noble","image.serial":"20250115","image.type":"disk1.img","image.version":"24.04","limits.cpu":"4","limits.memory":"4294967296","security.secureboot":"false","volatile.base_image":"16c5963a3c55d17639f96099f8133d986601dbafc79c53d26ba384cbcfcd5bad","volatile.cloud-init.instance-id":"0c428aad-043f-45e8-b4fe-edd762f72757","volatile.eth0.host_name":"tapc73ec1df","volatile.eth0.hwaddr":"00:16:3e:3d:1a:76","volatile.last_state.power":"RUNNING","volatile.uuid":"a3e00b40-df48-4939-b03e-bdaa962dd898","volatile.uuid.generation":"a3e00b40-df48-4939-b03e-bdaa962dd898","volatile.vsock_id":"721893514"},"devices":{"root":{"path":"/","pool":"default","size":"16106127360","type":"disk"}},"expanded_config":{"image.architecture":"amd64","image.description":"ubuntu 24.04 LTS amd64 (release) (20250115)","image.label":"release","image.os":"ubuntu","image.release":"noble","image.serial":"20250115","image.type":"disk1.img","image.version":"24.04","limits.cpu":"4","limits.memory":"4294967296","security.secureboot":"false","volatile.base_image":"16c5963a3c55d17639f96099f8133d986601dbafc79c53d26ba384cbcfcd5bad","volatile.cloud-init.instance-id":"0c428aad-043f-45e8-b4fe-edd762f72757","volatile.eth0.host_name":"tapc73ec1df","volatile.eth0.hwaddr":"00:16:3e:3d:1a:76","volatile.last_state.power":"RUNNING","volatile.uuid":"a3e00b40-df48-4939-b03e-bdaa962dd898","volatile.uuid.generation":"a3e00b40-df48-4939-b03e-bdaa962dd898","volatile.vsock_id":"721893514"},"expanded_devices":{"eth0":{"name":"eth0","network":"lxdbr0","type":"nic"},"root":{"path":"/","pool":"default","size":"16106127360","type":"disk"}},"backups":null,"state":{"status":"Running","status_code":103,"disk":null,"memory":{"usage":444915712,"usage_peak":0,"total":4097273856,"swap_usage":0,"swap_usage_peak":0},"network":{"enp5s0":{"addresses":[{"family":"inet","address":"10.22.100.75","netmask":"24","scope":"global"},{"family":"inet6","address":"fd42:2245:81ae:90da:216:3eff:fe3d:1a76","netmask":"64","scope":"global"},{"family":"inet6","address":"fe80::216:3eff:fe3d:1a76","netmask":"64","scope":"link"}],"counters":{"bytes_received":316098,"bytes_sent":13324,"packets_received":220,"packets_sent":148,"errors_received":0,"errors_sent":0,"packets_dropped_outbound":0,"packets_dropped_inbound":0},"hwaddr":"00:16:3e:3d:1a:76","host_name":"tapc73ec1df","mtu":1500,"state":"up","type":"broadcast"},"lo":{"addresses":[{"family":"inet","address":"127.0.0.1","netmask":"8","scope":"local"},{"family":"inet6","address":"::1","netmask":"128","scope":"local"}],"counters":{"bytes_received":7652,"bytes_sent":7652,"packets_received":96,"packets_sent":96,"errors_received":0,"errors_sent":0,"packets_dropped_outbound":0,"packets_dropped_inbound":0},"hwaddr":"","host_name":"","mtu":65536,"state":"up","type":"loopback"}},"pid":134121,"processes":21,"cpu":{"usage":12200111000}},"snapshots":null
-}]"##;
+        let sysconf = system.as_ref().and_then(|s| self.conf.system.get(s));
+        let dir = collect_node_diagnostics(
+            self.backend.as_mut(),
+            self.artifacts_dir.as_deref(),
+            remote.as_deref(),
+            &name,
+            self.conf.diagnostics(sysconf),
+        )?;
+        log::info!("diagnostics of {} collected in {}", name, dir.display());
+        Ok(dir)
+    }
 
-    struct MockLxcRunner {
-        seen_calls: VecDeque<Vec<String>>,
-        outputs: VecDeque<Result<Vec<u8>, LxcRunnerError>>,
+    /// Discard all nodes.
+    fn discard_all(&mut self) -> Result<(), allocator::Error> {
+        let started = SystemTime::now();
+        let res = self.discard_all_nodes();
+        self.events
+            .record(&Event::new(audit::Operation::Cleanup, started, &res));
+        res
     }
 
-    impl MockLxcRunner {
-        fn new(calls: Vec<Result<Vec<u8>, LxcRunnerError>>) -> Self {
-            Self {
-                seen_calls: VecDeque::new(),
-                outputs: VecDeque::from(calls),
+    /// List nodes in the LXD project, with details coming from their records.
+    fn list(&mut self) -> Result<Vec<allocator::NodeInfo>, allocator::Error> {
+        let records = self.load_state().nodes;
+
+        let mut nodes = vec![];
+        for remote in Self::remotes(&records) {
+            for usage in self.backend.list_allocations(remote.as_deref())? {
+                let record = records
+                    .iter()
+                    .find(|r| r.name == usage.name && r.remote == remote);
+                nodes.push(allocator::NodeInfo {
+                    name: remote_name(remote.as_deref(), &usage.name),
+                    node: record.and_then(LxdNodeRecord::node),
+                    cpu: usage.cpu,
+                    memory: usage.memory,
+                });
             }
         }
+        Ok(nodes)
     }
+}
 
-    impl LxcRunner for MockLxcRunner {
-        fn run(&mut self, cmd: LxcCommand) -> Result<Vec<u8>, LxcRunnerError> {
-            let LxcCommand(cmd) = cmd;
-            let call = cmd
-                .get_args()
-                .by_ref()
-                .map(|v| v.to_string_lossy().to_string())
-                .collect();
+impl LxdAllocator {
+    /// Allocate a node with a given name for a spread system.
+    fn allocate_node(
+        &mut self,
+        sysname: &str,
+        name: &str,
+        user_config: allocator::RemoteUserAccessConfig,
+    ) -> Result<(allocator::Node, LxdNodeAllocation), allocator::Error> {
+        let sysconf = if let Some(sysconf) = self.conf.system.get(sysname) {
+            sysconf
+        } else {
+            return Err(LxdError::NotFound(format!(
+                "system \"{}\" not found in configuration",
+                sysname
+            ))
+            .into());
+        };
 
-            let out = self
-                .outputs
-                .pop_front()
-                .expect(&format!("expected mock result for call {:?}", call));
+        let image = sysconf.image().to_string();
 
-            eprint!(
-                "call {:?} output: {}\n",
-                call,
-                String::from_utf8_lossy(out.as_ref().unwrap())
-            );
-            self.seen_calls.push_back(call);
-            out
+        if sysconf.setup_steps.is_empty() {
+            log::warn!("no setup steps declared for this system");
         }
-    }
+        let builtins = BTreeMap::from([
+            ("system".to_string(), sysname.to_string()),
+            ("instance".to_string(), name.to_string()),
+            ("image".to_string(), image.clone()),
+            ("user".to_string(), user_config.user.to_string()),
+            ("cpu".to_string(), sysconf.resources.cpu.to_string()),
+            (
+                "mem".to_string(),
+                sysconf.resources.mem.as_u64().to_string(),
+            ),
+            (
+                "size".to_string(),
+                sysconf.resources.size.as_u64().to_string(),
+            ),
+        ]);
+        let mut steps = self
+            .conf
+            .setup_steps(sysconf, &builtins)
+            .map_err(LxdError::ConfigInvalid)?;
+
+        let cloud_init = sysconf
+            .cloud_init
+            .as_ref()
+            .map(|c| c.instance_config(&user_config, &|p| self.conf.resolve_path(p)))
+            .transpose()
+            .map_err(|e| LxdError::ConfigInvalid(format!("cloud-init: {}", e)))?
+            .unwrap_or_default();
+
+        let transcript = self
+            .logs_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.transcript", name)));
+        let console_log = self
+            .logs_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.console", name)));
 
-    #[test]
-    fn test_cli_alloc_emsure_project_exists() {
-        let r = MockLxcRunner::new(vec![Ok(ONE_PROJECT_LIST.as_bytes().to_vec())]);
-        let mut a = LxdCliAllocator::new(r);
-        let res = a.ensure_project(LXD_PROJECT_NAME);
-        assert!(res.is_ok());
+        // TODO validate user & password
 
-        let mut r = a.test_into_runner();
-        assert_eq!(r.seen_calls.len(), 1);
-        assert_eq!(
-            r.seen_calls.pop_front().expect("expected a call"),
-            vec!["project", "list", "--format=json",]
-        );
-    }
+        if !sysconf.cloud_init.as_ref().is_some_and(|c| c.user_access) {
+            steps.push((
+                USER_ACCESS_SETUP.to_string(),
+                LxdSetupStep::run(&format!(
+                    "echo {}:{} | chpasswd",
+                    user_config.user, user_config.password
+                )),
+            ));
+        }
 
-    #[test]
-    fn test_cli_alloc_ensure_project_add() {
-        let r = MockLxcRunner::new(vec![
-            Ok("[]".as_bytes().to_vec()),
-            Ok("".as_bytes().to_vec()),
-        ]);
-        let mut a = LxdCliAllocator::new(r);
-        let res = a.ensure_project(LXD_PROJECT_NAME);
-        assert!(res.is_ok());
+        log::info!("allocating {} for system {}", name, sysname);
+        let remote = sysconf.remote.as_deref().or(self.conf.remote.as_deref());
+
+        ensure_project(
+            self.backend.as_mut(),
+            &mut self.projects,
+            remote,
+            LXD_PROJECT_NAME,
+        )?;
+
+        let state = self.state.as_ref();
+        let target = select_target(
+            self.backend.as_mut(),
+            remote,
+            sysconf.architecture,
+            matches!(sysconf.image, LxdImage::PerArchitecture(_)),
+            sysconf.placement.as_ref().or(self.conf.placement.as_ref()),
+            &mut |candidates| match state {
+                Some(state) => state
+                    .update(|st| {
+                        let counter = st
+                            .round_robin
+                            .entry(remote.unwrap_or_default().to_string())
+                            .or_default();
+                        let next = *counter % candidates;
+                        *counter = next + 1;
+                        next
+                    })
+                    .unwrap_or_else(|err| {
+                        log::warn!("cannot update round robin state: {}", err);
+                        0
+                    }),
+                None => random::<usize>(),
+            },
+        )?;
+
+        let token = random::<u64>();
+        admit(
+            self.backend.as_mut(),
+            self.state.as_ref(),
+            self.user_conf.budget.as_ref(),
+            remote,
+            name,
+            token,
+            &sysconf.resources,
+        )?;
+
+        let ssh_forward = sysconf
+            .ssh_forward
+            .as_ref()
+            .or(self.conf.ssh_forward.as_ref())
+            .map(|forward| {
+                reserve_port(
+                    self.backend.as_mut(),
+                    self.state.as_ref(),
+                    remote,
+                    name,
+                    &forward.ports,
+                )
+                .map(|port| LxdSshForward {
+                    address: forward.address,
+                    port,
+                })
+            })
+            .transpose();
+        let ssh_forward = match ssh_forward {
+            Ok(ssh_forward) => ssh_forward,
+            Err(err) => {
+                self.update_state(|st| st.reservations.retain(|r| r.token != token));
+                return Err(err.into());
+            }
+        };
 
-        let mut r = a.test_into_runner();
-        assert_eq!(r.seen_calls.len(), 2);
-        assert_eq!(
-            r.seen_calls.pop_front().expect("expected a call"),
+        let res = self.backend.allocate(&LxdNodeDetails {
+            image: &image,
+            remote,
+            target: target.as_deref(),
+            cpu: sysconf.resources.cpu,
+            memory: sysconf.resources.mem.as_u64(),
+            name,
+            root_size: sysconf.resources.size.as_u64(),
+            vm: sysconf.vm,
+            secure_boot: sysconf.secure_boot,
+            tpm: sysconf.tpm,
+            firmware: sysconf.firmware,
+            address: self.conf.address_filter(sysconf),
+            ssh_forward,
+            cloud_init: &cloud_init,
+            provision_steps: &steps,
+            transcript: transcript.as_deref(),
+            console_log: console_log.as_deref(),
+        });
+
+        if res.is_err() {
+            // the project may have been removed behind our back
+            self.projects.clear();
+        }
+        self.update_state(|st| {
+            st.reservations.retain(|r| r.token != token);
+            if res.is_err() {
+                // the node left behind may be listening on the port
+                st.ports
+                    .iter_mut()
+                    .filter(|r| r.name == name)
+                    .for_each(|r| r.failed = true);
+            }
+        });
+
+        let node = match res {
+            Ok(node) => node,
+            Err(err) => {
+                // the node is left behind, collect whatever may explain
+                // the failure
+                let diagnostics = self.conf.diagnostics(Some(sysconf));
+                if !diagnostics.is_empty() && self.artifacts_dir.is_some() {
+                    match collect_node_diagnostics(
+                        self.backend.as_mut(),
+                        self.artifacts_dir.as_deref(),
+                        remote,
+                        name,
+                        diagnostics,
+                    ) {
+                        Ok(dir) => log::info!("diagnostics collected in {}", dir.display()),
+                        Err(LxdError::NotFound(_)) => {
+                            log::debug!("node not created, no diagnostics to collect")
+                        }
+                        Err(e) => log::warn!("cannot collect diagnostics: {}", e),
+                    }
+                }
+                return Err(err.into());
+            }
+        };
+
+        let allocated_at = logging::timestamp(SystemTime::now());
+        self.update_state(|st| {
+            // the record takes over the reserved port
+            st.ports.retain(|r| r.name != name);
+            st.nodes.push(LxdNodeRecord {
+                name: node.name.clone(),
+                system: sysname.to_string(),
+                remote: remote.map(str::to_string),
+                target: target.clone(),
+                addr: node.addr.to_string(),
+                ssh_port: node.ssh_port,
+                allocated_at: Some(allocated_at.clone()),
+            })
+        });
+
+        Ok((
+            allocator::Node {
+                name: remote_name(remote, &node.name),
+                system: sysname.to_string(),
+                addr: node.addr,
+                ssh_port: node.ssh_port,
+                allocated_at: Some(allocated_at),
+            },
+            node,
+        ))
+    }
+
+    /// Completes discarding a node, removing its record, if any, and
+    /// recording the event.
+    fn discarded(
+        &mut self,
+        started: SystemTime,
+        res: Result<(), LxdError>,
+        record: Option<LxdNodeRecord>,
+        instance: Option<&str>,
+        addr: Option<&str>,
+    ) -> Result<Option<allocator::Node>, allocator::Error> {
+        let res = res
+            .map_err(allocator::Error::from)
+            .map(|_| record.as_ref().and_then(LxdNodeRecord::node));
+
+        if let (Ok(_), Some(record)) = (res.as_ref(), record.as_ref()) {
+            self.update_state(|st| {
+                st.nodes
+                    .retain(|n| !(n.name == record.name && n.remote == record.remote))
+            });
+        }
+        if let (Ok(_), Some(instance)) = (res.as_ref(), instance) {
+            // a node of a failed allocation releases its forwarded port
+            self.update_state(|st| st.ports.retain(|r| !(r.failed && r.name == instance)));
+        }
+
+        let mut event = Event::new(audit::Operation::Discard, started, &res)
+            .with_instance(record.as_ref().map(|r| r.name.as_str()).or(instance))
+            .with_address(record.as_ref().map(|r| r.addr.as_str()).or(addr));
+        if let Some(record) = record.as_ref() {
+            event = event.with_system(&record.system);
+        }
+        self.events.record(&event);
+        res
+    }
+
+    /// Keeps a node discarded by spread for post-mortem, or deletes it if it
+    /// cannot be kept.
+    fn keep_node(&mut self, record: &LxdNodeRecord) -> Result<(), LxdError> {
+        let mode = self.user_conf.postmortem.mode;
+        let remote = record.remote.as_deref();
+        let discarded_at = logging::timestamp(SystemTime::now());
+        let kept_name = postmortem_name(&record.name, &discarded_at);
+        let export = match mode {
+            LxdPostmortemMode::Export => self
+                .artifacts_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{}.tar.gz", kept_name))),
+            _ => None,
+        };
+
+        let res = ensure_project(
+            self.backend.as_mut(),
+            &mut self.projects,
+            remote,
+            LXD_POSTMORTEM_PROJECT_NAME,
+        )
+        .and_then(|_| {
+            self.backend
+                .keep_node(remote, &record.name, &kept_name, mode, export.as_deref())
+        });
+        if let Err(err) = res {
+            log::warn!("{}, deleting it instead", err);
+            return self.backend.discard_by_name(remote, &record.name);
+        }
+
+        log::info!(
+            "node {} kept for post-mortem as {}",
+            record.name,
+            remote_name(remote, &kept_name)
+        );
+        self.update_state(|st| {
+            st.postmortem.push(LxdPostmortemRecord {
+                name: kept_name,
+                remote: record.remote.clone(),
+                system: record.system.clone(),
+                instance: record.name.clone(),
+                discarded_at,
+                export,
+            })
+        });
+        Ok(())
+    }
+
+    /// Deletes the oldest nodes kept for post-mortem beyond the retention
+    /// limit.
+    fn reap_kept_nodes(&mut self) {
+        let kept = self.load_state().postmortem;
+        let excess = kept.len().saturating_sub(self.user_conf.postmortem.retain);
+        for record in kept.into_iter().take(excess) {
+            let started = SystemTime::now();
+            log::info!("reaping node {} kept for post-mortem", record.name);
+            let res = self
+                .delete_kept_node(&record)
+                .map_err(allocator::Error::from);
+            self.events.record(
+                &Event::new(audit::Operation::Reap, started, &res)
+                    .with_system(&record.system)
+                    .with_instance(Some(&record.name)),
+            );
+        }
+    }
+
+    /// Deletes a node kept for post-mortem along with its export, removing
+    /// its record.
+    fn delete_kept_node(&mut self, record: &LxdPostmortemRecord) -> Result<(), LxdError> {
+        match self
+            .backend
+            .delete_kept_node(record.remote.as_deref(), &record.name)
+        {
+            Ok(()) => {}
+            Err(LxdError::NotFound(_)) => log::debug!("node {} already gone", record.name),
+            Err(err) => return Err(err),
+        }
+        if let Some(export) = record.export.as_ref() {
+            if let Err(err) = std::fs::remove_file(export) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    log::warn!("cannot remove export {}: {}", export.display(), err);
+                }
+            }
+        }
+        self.update_state(|st| {
+            st.postmortem
+                .retain(|r| !(r.name == record.name && r.remote == record.remote))
+        });
+        Ok(())
+    }
+
+    /// Lists nodes kept for post-mortem, oldest first.
+    pub fn postmortem_list(&self) -> Vec<LxdPostmortemNode> {
+        self.load_state()
+            .postmortem
+            .into_iter()
+            .map(|r| LxdPostmortemNode {
+                name: remote_name(r.remote.as_deref(), &r.name),
+                system: r.system,
+                instance: r.instance,
+                discarded_at: r.discarded_at,
+                export: r.export,
+            })
+            .collect()
+    }
+
+    /// Opens an interactive shell in a node kept for post-mortem, given by
+    /// its name, optionally qualified with a remote.
+    pub fn postmortem_shell(&mut self, name: &str) -> Result<(), allocator::Error> {
+        let (remote, instance) = match name.split_once(':') {
+            Some((remote, instance)) => (Some(remote), instance),
+            None => (None, name),
+        };
+        Ok(self.backend.shell_kept_node(remote, instance)?)
+    }
+
+    /// Deletes a node kept for post-mortem, given by its name, optionally
+    /// qualified with a remote.
+    pub fn postmortem_delete(&mut self, name: &str) -> Result<(), allocator::Error> {
+        let (remote, instance) = match name.split_once(':') {
+            Some((remote, instance)) => (Some(remote), instance),
+            None => (None, name),
+        };
+        let record = self
+            .load_state()
+            .postmortem
+            .into_iter()
+            .find(|r| r.name == instance && r.remote.as_deref() == remote);
+        match record {
+            Some(record) => self.delete_kept_node(&record)?,
+            // the node may be known to LXD only
+            None => self.backend.delete_kept_node(remote, instance)?,
+        }
+        Ok(())
+    }
+
+    /// Returns the default remote, and all remotes nodes were placed on.
+    fn remotes(nodes: &[LxdNodeRecord]) -> Vec<Option<String>> {
+        let mut remotes: Vec<Option<String>> = vec![None];
+        for node in nodes {
+            if !remotes.contains(&node.remote) {
+                remotes.push(node.remote.clone());
+            }
+        }
+        remotes
+    }
+
+    fn discard_all_nodes(&mut self) -> Result<(), allocator::Error> {
+        let remotes = Self::remotes(&self.load_state().nodes);
+        for remote in remotes.iter() {
+            self.backend.discard_all(remote.as_deref())?;
+        }
+
+        self.update_state(|st| {
+            st.nodes.clear();
+            st.ports
+                .retain(|r| !(r.failed && remotes.contains(&r.remote)));
+        });
+        Ok(())
+    }
+
+    fn new_with_config(
+        conf: LxdBackendConfig,
+        user_conf: LxdBackendUserConfig,
+        state_dir: Option<&Path>,
+        events: Box<dyn EventSink>,
+    ) -> Self {
+        let mut backend = LxdCliAllocator::<LxcCommandRunner>::new(LxcCommandRunner {});
+        if let Some(dir) = state_dir {
+            backend = backend.with_project_lock(&dir.join(LXD_PROJECT_LOCK_FILE_NAME));
+        }
+
+        LxdAllocator {
+            conf,
+            user_conf,
+            state: state_dir.map(|d| StateStore::new(&d.join(LXD_STATE_FILE_NAME))),
+            logs_dir: state_dir.map(|d| d.join(LOGS_DIR_NAME)),
+            artifacts_dir: state_dir.map(|d| d.join(ARTIFACTS_DIR_NAME)),
+            events,
+            backend: Box::new(backend),
+            projects: HashSet::new(),
+        }
+    }
+
+    /// Finds the record of a node reached through a given address, and
+    /// optionally a port. An address without a port may be shared by nodes
+    /// reached through forwarded ports, in which case the node is ambiguous.
+    fn record_by_addr(
+        &self,
+        addr: &str,
+        ip: &str,
+        port: Option<u16>,
+    ) -> Result<Option<LxdNodeRecord>, LxdError> {
+        let mut records: Vec<LxdNodeRecord> = self
+            .load_state()
+            .nodes
+            .into_iter()
+            .filter(|n| {
+                same_address(&n.addr, ip) && port.is_none_or(|p| n.ssh_port == u32::from(p))
+            })
+            .collect();
+
+        if records.len() > 1 {
+            let names: Vec<&str> = records.iter().map(|r| r.name.as_str()).collect();
+            return Err(LxdError::NotFound(format!(
+                "address {} is shared by nodes {}, give the port or instance name",
+                addr,
+                names.join(", ")
+            )));
+        }
+        Ok(records.pop())
+    }
+
+    fn load_state(&self) -> LxdState {
+        self.state
+            .as_ref()
+            .map(|st| {
+                st.load().unwrap_or_else(|err| {
+                    log::warn!("cannot load state: {}", err);
+                    Default::default()
+                })
+            })
+            .unwrap_or_default()
+    }
+
+    fn update_state<F>(&self, f: F)
+    where
+        F: FnOnce(&mut LxdState),
+    {
+        if let Some(state) = self.state.as_ref() {
+            if let Err(err) = state.update(f) {
+                log::warn!("cannot update state: {}", err);
+            }
+        }
+    }
+}
+
+fn default_mem() -> bytesize::ByteSize {
+    bytesize::ByteSize(bytesize::gib(2_u64))
+}
+
+fn default_cpu() -> u32 {
+    2
+}
+
+fn default_root_size() -> bytesize::ByteSize {
+    bytesize::ByteSize(bytesize::gib(10_u64))
+}
+
+/// Resources assigned to a node.
+#[derive(serde::Deserialize, Debug)]
+struct LxdNodeResources {
+    /// RAM
+    #[serde(default = "default_mem")]
+    mem: bytesize::ByteSize,
+    /// Number of CPUs.
+    #[serde(default = "default_cpu")]
+    cpu: u32,
+    /// Root disk size (applicable to VM).
+    #[serde(default = "default_root_size")]
+    size: bytesize::ByteSize,
+}
+
+impl Default for LxdNodeResources {
+    fn default() -> Self {
+        LxdNodeResources {
+            mem: default_mem(),
+            cpu: default_cpu(),
+            size: default_root_size(),
+        }
+    }
+}
+
+fn default_vm() -> bool {
+    true
+}
+
+/// Firmware used for booting a VM.
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LxdFirmware {
+    /// UEFI, with secure boot variables when secure boot is enabled.
+    #[default]
+    Uefi,
+    /// UEFI with Compatibility Support Module, for legacy BIOS boot.
+    Csm,
+}
+
+/// Family of the address through which a node is reached.
+#[derive(serde::Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum LxdAddressFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+    /// Either, IPv4 when both are available.
+    Any,
+}
+
+impl LxdAddressFamily {
+    fn name(&self) -> &'static str {
+        match self {
+            LxdAddressFamily::Ipv4 => "IPv4",
+            LxdAddressFamily::Ipv6 => "IPv6",
+            LxdAddressFamily::Any => "IPv4 or IPv6",
+        }
+    }
+
+    /// Checks whether an address family as reported by LXD is acceptable.
+    fn accepts(&self, family: &str) -> bool {
+        match self {
+            LxdAddressFamily::Ipv4 => family == "inet",
+            LxdAddressFamily::Ipv6 => family == "inet6",
+            LxdAddressFamily::Any => family == "inet" || family == "inet6",
+        }
+    }
+}
+
+/// IP subnet, given as <address>/<prefix length>.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct LxdSubnet {
+    addr: net::IpAddr,
+    prefix: u8,
+}
+
+impl LxdSubnet {
+    fn contains(&self, addr: &net::IpAddr) -> bool {
+        match (self.addr, addr) {
+            (net::IpAddr::V4(net), net::IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(*addr) & mask
+            }
+            (net::IpAddr::V6(net), net::IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(*addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for LxdSubnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s
+            .split_once('/')
+            .ok_or_else(|| format!("subnet \"{}\" is not in <address>/<prefix> form", s))?;
+        let addr: net::IpAddr = addr
+            .parse()
+            .map_err(|e| format!("invalid subnet address \"{}\": {}", addr, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix
+            .parse::<u8>()
+            .ok()
+            .filter(|p| *p <= max)
+            .ok_or_else(|| format!("invalid prefix length in subnet \"{}\"", s))?;
+        Ok(LxdSubnet { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for LxdSubnet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for LxdSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Range of ports, given as <first>-<last>.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+struct LxdPortRange {
+    first: u16,
+    last: u16,
+}
+
+impl FromStr for LxdPortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("port range \"{}\" is not in <first>-<last> form", s);
+        let (first, last) = s.split_once('-').ok_or_else(invalid)?;
+        let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+        let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+        if first == 0 || first > last {
+            return Err(format!("invalid port range \"{}\"", s));
+        }
+        Ok(LxdPortRange { first, last })
+    }
+}
+
+impl TryFrom<String> for LxdPortRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for LxdPortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+/// Forwarding of host ports to the SSH port of nodes, for when the LXD bridge
+/// is not reachable by spread.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+struct LxdSshForwardConfig {
+    /// Address of the LXD host to listen on, which spread connects to.
+    address: net::IpAddr,
+    /// Host ports to pick from.
+    ports: LxdPortRange,
+}
+
+impl LxdSshForwardConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.address.is_unspecified() {
+            return Err(format!(
+                "SSH forwarding requires a specific host address, not {}",
+                self.address
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Selection of the network interface and address of a node.
+#[derive(serde::Deserialize, Debug, Default, Clone, PartialEq)]
+struct LxdNetworkConfig {
+    /// Interface name or glob pattern, eg. enp*.
+    interface: Option<String>,
+    /// Subnet the address must belong to.
+    subnet: Option<LxdSubnet>,
+}
+
+/// CPU architecture of a node. Nodes run natively, VMs of a foreign
+/// architecture are not emulated by LXD.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum LxdArchitecture {
+    #[serde(alias = "x86_64")]
+    Amd64,
+    #[serde(alias = "aarch64")]
+    Arm64,
+    #[serde(alias = "armv7l")]
+    Armhf,
+    #[serde(alias = "ppc64le")]
+    Ppc64el,
+    S390x,
+    Riscv64,
+}
+
+impl LxdArchitecture {
+    /// Name of the architecture as reported by LXD.
+    fn lxd_name(&self) -> &'static str {
+        match self {
+            LxdArchitecture::Amd64 => "x86_64",
+            LxdArchitecture::Arm64 => "aarch64",
+            LxdArchitecture::Armhf => "armv7l",
+            LxdArchitecture::Ppc64el => "ppc64le",
+            LxdArchitecture::S390x => "s390x",
+            LxdArchitecture::Riscv64 => "riscv64",
+        }
+    }
+}
+
+/// Strategy for placing nodes on cluster members.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum LxdPlacementStrategy {
+    /// Always use the same member.
+    Fixed,
+    /// Cycle through the members.
+    RoundRobin,
+    /// Pick the member with the least memory assigned to nodes.
+    LeastAllocated,
+}
+
+/// Placement of nodes in a cluster.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+struct LxdPlacement {
+    strategy: LxdPlacementStrategy,
+    /// Cluster member for fixed placement.
+    target: Option<String>,
+}
+
+impl LxdPlacement {
+    fn validate(&self) -> Result<(), String> {
+        if self.strategy == LxdPlacementStrategy::Fixed && self.target.is_none() {
+            return Err("fixed placement requires a target".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Source of a file pushed to a node.
+#[derive(Debug, Clone, PartialEq)]
+enum LxdPushSource {
+    /// File on the host, relative to the directory of the configuration
+    /// file.
+    File(PathBuf),
+    /// Inline content.
+    Content(String),
+}
+
+/// A step of setting up a node.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "LxdSetupStepConfig")]
+enum LxdSetupStep {
+    /// Run a shell snippet with bash.
+    Run {
+        command: String,
+        /// UID to run as.
+        user: Option<u32>,
+        env: BTreeMap<String, String>,
+        /// Working directory.
+        cwd: Option<String>,
+        /// Time limit, in seconds.
+        timeout: Option<u64>,
+    },
+    /// Push a file to a given path in the node.
+    Push {
+        path: String,
+        source: LxdPushSource,
+        /// Octal file mode, eg. 0644.
+        mode: Option<String>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    },
+    /// Pull a file at a given path in the node to the host.
+    Pull { path: String, destination: PathBuf },
+}
+
+impl LxdSetupStep {
+    /// Returns a step running a shell snippet with default settings.
+    fn run(command: &str) -> Self {
+        LxdSetupStep::Run {
+            command: command.to_string(),
+            user: None,
+            env: BTreeMap::new(),
+            cwd: None,
+            timeout: None,
+        }
+    }
+}
+
+impl LxdSetupStep {
+    /// Renders the step with given variables.
+    fn render(&self, vars: &BTreeMap<String, String>) -> Result<Self, String> {
+        let r = |s: &str| render_template(s, vars);
+        let r_path = |p: &Path| r(&p.to_string_lossy()).map(PathBuf::from);
+        Ok(match self {
+            LxdSetupStep::Run {
+                command,
+                user,
+                env,
+                cwd,
+                timeout,
+            } => LxdSetupStep::Run {
+                command: r(command)?,
+                user: *user,
+                env: env
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), r(v)?)))
+                    .collect::<Result<_, String>>()?,
+                cwd: cwd.as_deref().map(r).transpose()?,
+                timeout: *timeout,
+            },
+            LxdSetupStep::Push {
+                path,
+                source,
+                mode,
+                uid,
+                gid,
+            } => LxdSetupStep::Push {
+                path: r(path)?,
+                source: match source {
+                    LxdPushSource::File(source) => LxdPushSource::File(r_path(source)?),
+                    LxdPushSource::Content(content) => LxdPushSource::Content(r(content)?),
+                },
+                mode: mode.clone(),
+                uid: *uid,
+                gid: *gid,
+            },
+            LxdSetupStep::Pull { path, destination } => LxdSetupStep::Pull {
+                path: r(path)?,
+                destination: r_path(destination)?,
+            },
+        })
+    }
+}
+
+impl LxdSetupStep {
+    /// Returns the step with host paths passed through a given function.
+    fn resolve_paths(self, resolve: impl Fn(&Path) -> PathBuf) -> Self {
+        match self {
+            LxdSetupStep::Push {
+                path,
+                source: LxdPushSource::File(source),
+                mode,
+                uid,
+                gid,
+            } => LxdSetupStep::Push {
+                path,
+                source: LxdPushSource::File(resolve(&source)),
+                mode,
+                uid,
+                gid,
+            },
+            LxdSetupStep::Pull { path, destination } => LxdSetupStep::Pull {
+                path,
+                destination: resolve(&destination),
+            },
+            step => step,
+        }
+    }
+}
+
+impl fmt::Display for LxdSetupStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LxdSetupStep::Run { command, .. } => write!(f, "run: {}", command),
+            LxdSetupStep::Push { path, .. } => write!(f, "push: {}", path),
+            LxdSetupStep::Pull { path, .. } => write!(f, "pull: {}", path),
+        }
+    }
+}
+
+/// Setup step as it appears in the configuration, either a shell snippet, or
+/// a map with one of run, push or pull keys.
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+enum LxdSetupStepConfig {
+    Shorthand(String),
+    Full(Box<LxdSetupStepFields>),
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct LxdSetupStepFields {
+    run: Option<String>,
+    user: Option<u32>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    cwd: Option<String>,
+    timeout: Option<u64>,
+    push: Option<String>,
+    source: Option<PathBuf>,
+    content: Option<String>,
+    mode: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    pull: Option<String>,
+    destination: Option<PathBuf>,
+}
+
+impl TryFrom<LxdSetupStepConfig> for LxdSetupStep {
+    type Error = String;
+
+    fn try_from(conf: LxdSetupStepConfig) -> Result<Self, Self::Error> {
+        let f = match conf {
+            LxdSetupStepConfig::Shorthand(command) => return Ok(LxdSetupStep::run(&command)),
+            LxdSetupStepConfig::Full(fields) => *fields,
+        };
+
+        let run_only = f.user.is_some() || !f.env.is_empty() || f.cwd.is_some();
+        let push_only = f.source.is_some()
+            || f.content.is_some()
+            || f.mode.is_some()
+            || f.uid.is_some()
+            || f.gid.is_some();
+
+        match (f.run, f.push, f.pull) {
+            (Some(command), None, None) => {
+                if push_only || f.destination.is_some() {
+                    return Err(format!("unexpected settings for run step \"{}\"", command));
+                }
+                Ok(LxdSetupStep::Run {
+                    command,
+                    user: f.user,
+                    env: f.env,
+                    cwd: f.cwd,
+                    timeout: f.timeout,
+                })
+            }
+            (None, Some(path), None) => {
+                if run_only || f.timeout.is_some() || f.destination.is_some() {
+                    return Err(format!("unexpected settings for push step \"{}\"", path));
+                }
+                let source = match (f.source, f.content) {
+                    (Some(source), None) => LxdPushSource::File(source),
+                    (None, Some(content)) => LxdPushSource::Content(content),
+                    _ => {
+                        return Err(format!(
+                            "push step \"{}\" needs either source or content",
+                            path
+                        ))
+                    }
+                };
+                if let Some(mode) = f.mode.as_ref() {
+                    if u32::from_str_radix(mode, 8).is_err() {
+                        return Err(format!(
+                            "invalid mode \"{}\" of push step \"{}\", expected octal",
+                            mode, path
+                        ));
+                    }
+                }
+                Ok(LxdSetupStep::Push {
+                    path,
+                    source,
+                    mode: f.mode,
+                    uid: f.uid,
+                    gid: f.gid,
+                })
+            }
+            (None, None, Some(path)) => {
+                if run_only || push_only || f.timeout.is_some() {
+                    return Err(format!("unexpected settings for pull step \"{}\"", path));
+                }
+                let destination = f
+                    .destination
+                    .ok_or_else(|| format!("pull step \"{}\" needs a destination", path))?;
+                Ok(LxdSetupStep::Pull { path, destination })
+            }
+            _ => Err("setup step needs exactly one of run, push or pull".to_string()),
+        }
+    }
+}
+
+/// Names of variables available to all setup steps.
+const SETUP_BUILTIN_VARS: &[&str] = &["system", "instance", "image", "user", "cpu", "mem", "size"];
+
+/// Renders a template, replacing each {{ <name> }} with the value of a given
+/// variable. Braces enclosing anything other than a name, such as Go
+/// templates or jq filters, are kept as is, and \{{ is kept as a literal {{.
+fn render_template(template: &str, vars: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        if rest[..start].ends_with('\\') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("{{");
+            rest = after;
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let var = after
+            .find("}}")
+            .map(|end| (after[..end].trim(), end))
+            .filter(|(name, _)| is_template_var_name(name));
+        match var {
+            Some((name, end)) => {
+                let value = vars
+                    .get(name)
+                    .ok_or_else(|| format!("undefined variable \"{}\"", name))?;
+                out.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                out.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Whether a name can be used for a template variable.
+fn is_template_var_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Reference to a set of setup steps, given either by name, or as a map with
+/// the name and variables for rendering the steps.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "LxdSetupRefConfig")]
+struct LxdSetupRef {
+    name: String,
+    vars: BTreeMap<String, String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+enum LxdSetupRefConfig {
+    Name(String),
+    WithVars {
+        name: String,
+        #[serde(default)]
+        vars: BTreeMap<String, serde_yml::Value>,
+    },
+}
+
+impl TryFrom<LxdSetupRefConfig> for LxdSetupRef {
+    type Error = String;
+
+    fn try_from(conf: LxdSetupRefConfig) -> Result<Self, Self::Error> {
+        let (name, vars) = match conf {
+            LxdSetupRefConfig::Name(name) => (name, BTreeMap::new()),
+            LxdSetupRefConfig::WithVars { name, vars } => (name, vars),
+        };
+        let vars = vars
+            .into_iter()
+            .map(|(k, v)| {
+                if SETUP_BUILTIN_VARS.contains(&k.as_str()) {
+                    return Err(format!("variable \"{}\" is built-in", k));
+                }
+                let v = match v {
+                    serde_yml::Value::String(s) => s,
+                    serde_yml::Value::Number(n) => n.to_string(),
+                    serde_yml::Value::Bool(b) => b.to_string(),
+                    _ => return Err(format!("variable \"{}\" is not a scalar", k)),
+                };
+                Ok((k, v))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(LxdSetupRef { name, vars })
+    }
+}
+
+/// Deserializes a single reference to a set of setup steps, or a list of them.
+fn deserialize_setup_refs<'de, D>(d: D) -> Result<Vec<LxdSetupRef>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+
+    // going through a value retains errors of the actual form
+    let value = serde_yml::Value::deserialize(d)?;
+    if value.is_sequence() {
+        serde_yml::from_value(value).map_err(D::Error::custom)
+    } else {
+        serde_yml::from_value(value)
+            .map(|r| vec![r])
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Cloud-init data, given inline or as a file relative to the directory of
+/// the configuration file.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+enum LxdCloudInitData {
+    Inline(String),
+    File { file: PathBuf },
+}
+
+impl LxdCloudInitData {
+    fn load(&self, resolve: &dyn Fn(&Path) -> PathBuf) -> Result<String, String> {
+        match self {
+            LxdCloudInitData::Inline(data) => Ok(data.clone()),
+            LxdCloudInitData::File { file } => {
+                let file = resolve(file);
+                std::fs::read_to_string(&file)
+                    .map_err(|e| format!("cannot read {}: {}", file.display(), e))
+            }
+        }
+    }
+}
+
+/// Cloud-init configuration of a node.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+struct LxdCloudInitConfig {
+    user_data: Option<LxdCloudInitData>,
+    network_config: Option<LxdCloudInitData>,
+    vendor_data: Option<LxdCloudInitData>,
+    /// Set up remote user access with vendor data generated by the
+    /// allocator, rather than with a setup step.
+    #[serde(default)]
+    user_access: bool,
+}
+
+impl LxdCloudInitConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.user_access && self.vendor_data.is_some() {
+            return Err("cloud-init user access cannot be used with vendor data".to_string());
+        }
+        Ok(())
+    }
+
+    /// Returns the instance configuration keys with cloud-init data, files
+    /// are located with a given function.
+    fn instance_config(
+        &self,
+        user_config: &allocator::RemoteUserAccessConfig,
+        resolve: &dyn Fn(&Path) -> PathBuf,
+    ) -> Result<Vec<(String, String)>, String> {
+        let mut config = vec![];
+        for (key, data) in [
+            ("cloud-init.user-data", self.user_data.as_ref()),
+            ("cloud-init.network-config", self.network_config.as_ref()),
+            ("cloud-init.vendor-data", self.vendor_data.as_ref()),
+        ] {
+            if let Some(data) = data {
+                config.push((key.to_string(), data.load(resolve)?));
+            }
+        }
+        if self.user_access {
+            config.push((
+                "cloud-init.vendor-data".to_string(),
+                cloud_init_user_access(user_config),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// Returns cloud-config enabling SSH login of a user with a password.
+fn cloud_init_user_access(user_config: &allocator::RemoteUserAccessConfig) -> String {
+    // JSON strings are valid YAML scalars
+    let quote = |s: &str| serde_json::Value::from(s).to_string();
+    format!(
+        "#cloud-config
+ssh_pwauth: true
+disable_root: false
+chpasswd:
+  expire: false
+  users:
+    - name: {}
+      password: {}
+      type: text
+",
+        quote(user_config.user),
+        quote(user_config.password)
+    )
+}
+
+/// Name of the set of steps setting up remote user access.
+const USER_ACCESS_SETUP: &str = "user-access";
+
+/// Diagnostic data collected from a node into a local file.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "LxdDiagnosticConfig")]
+pub struct LxdDiagnostic {
+    source: LxdDiagnosticSource,
+    /// Name of the local file.
+    name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum LxdDiagnosticSource {
+    /// Command executed in the node, with its output saved.
+    Command(String),
+    /// File pulled from the node.
+    File(String),
+}
+
+/// Diagnostic as it appears in the configuration, either a command or a map
+/// with the command or file and an optional name.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum LxdDiagnosticConfig {
+    Shorthand(String),
+    Full(LxdDiagnosticFields),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LxdDiagnosticFields {
+    command: Option<String>,
+    file: Option<String>,
+    name: Option<String>,
+}
+
+impl TryFrom<LxdDiagnosticConfig> for LxdDiagnostic {
+    type Error = String;
+
+    fn try_from(conf: LxdDiagnosticConfig) -> Result<Self, Self::Error> {
+        let f = match conf {
+            LxdDiagnosticConfig::Shorthand(command) => LxdDiagnosticFields {
+                command: Some(command),
+                file: None,
+                name: None,
+            },
+            LxdDiagnosticConfig::Full(fields) => fields,
+        };
+
+        let source = match (f.command, f.file) {
+            (Some(command), None) => LxdDiagnosticSource::Command(command),
+            (None, Some(file)) => {
+                if !file.starts_with('/') {
+                    return Err(format!(
+                        "diagnostic file \"{}\" is not an absolute path",
+                        file
+                    ));
+                }
+                LxdDiagnosticSource::File(file)
+            }
+            (Some(_), Some(_)) => {
+                return Err("diagnostic cannot have both a command and a file".to_string())
+            }
+            (None, None) => return Err("diagnostic needs either a command or a file".to_string()),
+        };
+
+        let name = match f.name {
+            Some(name) => {
+                if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                    return Err(format!("invalid diagnostic name \"{}\"", name));
+                }
+                name
+            }
+            None => {
+                let (text, suffix) = match &source {
+                    LxdDiagnosticSource::Command(command) => (command, ".txt"),
+                    LxdDiagnosticSource::File(file) => (file, ""),
+                };
+                let name = diagnostic_file_name(text);
+                if name.is_empty() {
+                    return Err(format!(
+                        "cannot derive a name for diagnostic \"{}\", set one",
+                        text
+                    ));
+                }
+                name + suffix
+            }
+        };
+
+        Ok(LxdDiagnostic { source, name })
+    }
+}
+
+impl fmt::Display for LxdDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            LxdDiagnosticSource::Command(command) => write!(f, "command: {}", command),
+            LxdDiagnosticSource::File(file) => write!(f, "file: {}", file),
+        }
+    }
+}
+
+/// Maximum length of file names derived from diagnostics.
+const DIAGNOSTIC_NAME_MAX_CHARS: usize = 64;
+
+/// Derives a file name from a command or a path, replacing runs of characters
+/// other than alphanumerics, dots and underscores with a dash.
+fn diagnostic_file_name(text: &str) -> String {
+    let mut name = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    name.chars()
+        .take(DIAGNOSTIC_NAME_MAX_CHARS)
+        .collect::<String>()
+        .trim_end_matches('-')
+        .to_string()
+}
+
+/// Checks that diagnostics are saved to distinct files.
+fn validate_diagnostics(diagnostics: &[LxdDiagnostic]) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    for diagnostic in diagnostics {
+        if !seen.insert(diagnostic.name.as_str()) {
+            return Err(format!(
+                "diagnostics with duplicate name \"{}\"",
+                diagnostic.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Image of a node, either an image alias or fingerprint used as given, or
+/// one per architecture.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+enum LxdImage {
+    /// Resolved by LXD for the architecture of the server or cluster member
+    /// the node is placed on.
+    Any(String),
+    PerArchitecture(HashMap<LxdArchitecture, String>),
+}
+
+/// Configuration for a new LXD node.
+#[derive(serde::Deserialize, Debug)]
+struct LxdNodeConfig {
+    /// Image to use.
+    image: LxdImage,
+    /// Sets of setup steps, executed in order.
+    #[serde(
+        rename = "setup-steps",
+        default,
+        deserialize_with = "deserialize_setup_refs"
+    )]
+    setup_steps: Vec<LxdSetupRef>,
+    /// Resources configuration.
+    #[serde(default)]
+    resources: LxdNodeResources,
+    /// Secure boot support (applicable to VMs).
+    #[serde(rename = "secure-boot", default)]
+    secure_boot: bool,
+    /// Whether the system is a VM.
+    #[serde(default = "default_vm")]
+    vm: bool,
+    /// Virtual TPM device.
+    #[serde(default)]
+    tpm: bool,
+    /// Firmware variant (applicable to VMs).
+    #[serde(default)]
+    firmware: LxdFirmware,
+    /// Architecture, the host architecture if unset.
+    architecture: Option<LxdArchitecture>,
+    /// LXD remote to allocate the node on.
+    remote: Option<String>,
+    /// Placement in a cluster.
+    placement: Option<LxdPlacement>,
+    /// Family of the node address.
+    #[serde(rename = "address-family")]
+    address_family: Option<LxdAddressFamily>,
+    /// Selection of the node address.
+    network: Option<LxdNetworkConfig>,
+    /// Forwarding of a host port to SSH of the node.
+    #[serde(rename = "ssh-forward")]
+    ssh_forward: Option<LxdSshForwardConfig>,
+    /// Cloud-init data passed to the node.
+    #[serde(rename = "cloud-init")]
+    cloud_init: Option<LxdCloudInitConfig>,
+    /// Diagnostics collected from the node.
+    diagnostics: Option<Vec<LxdDiagnostic>>,
+}
+
+impl LxdNodeConfig {
+    /// Returns the image of the node, for its architecture if given per
+    /// architecture.
+    fn image(&self) -> &str {
+        match (&self.image, self.architecture) {
+            (LxdImage::Any(image), _) => image,
+            (LxdImage::PerArchitecture(images), Some(arch)) => images
+                .get(&arch)
+                .expect("image for architecture checked at load time"),
+            (LxdImage::PerArchitecture(_), None) => {
+                panic!("image per architecture without architecture")
+            }
+        }
+    }
+
+    /// Checks for combinations of settings which are not supported.
+    fn validate(&self) -> Result<(), String> {
+        if !self.vm {
+            if self.secure_boot {
+                return Err("secure boot is not supported for containers".to_string());
+            }
+            if self.firmware != LxdFirmware::Uefi {
+                return Err("firmware selection is not supported for containers".to_string());
+            }
+        }
+        if self.firmware == LxdFirmware::Csm && self.secure_boot {
+            return Err("secure boot cannot be used with CSM firmware".to_string());
+        }
+        if let LxdImage::PerArchitecture(images) = &self.image {
+            match self.architecture {
+                None => {
+                    return Err("an image per architecture requires an architecture".to_string())
+                }
+                Some(arch) if !images.contains_key(&arch) => {
+                    return Err(format!("no image for architecture {}", arch.lxd_name()))
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some(placement) = self.placement.as_ref() {
+            placement.validate()?;
+        }
+        if let Some(ssh_forward) = self.ssh_forward.as_ref() {
+            ssh_forward.validate()?;
+        }
+        if let Some(cloud_init) = self.cloud_init.as_ref() {
+            cloud_init.validate()?;
+        }
+        if let Some(diagnostics) = self.diagnostics.as_ref() {
+            validate_diagnostics(diagnostics)?;
+        }
+        Ok(())
+    }
+}
+
+/// Configuration for the LXD backend.
+#[derive(serde::Deserialize, Debug, Default)]
+struct LxdBackendConfig {
+    /// Systems with their properties, keyed by spread system name.
+    #[serde(default)]
+    system: HashMap<String, LxdNodeConfig>,
+    /// Setup steps.
+    #[serde(default)]
+    setup: HashMap<String, Vec<LxdSetupStep>>,
+    /// Default LXD remote.
+    remote: Option<String>,
+    /// Default placement in a cluster.
+    placement: Option<LxdPlacement>,
+    /// Default family of node addresses.
+    #[serde(rename = "address-family")]
+    address_family: Option<LxdAddressFamily>,
+    /// Default selection of node addresses.
+    network: Option<LxdNetworkConfig>,
+    /// Default forwarding of host ports to SSH of nodes.
+    #[serde(rename = "ssh-forward")]
+    ssh_forward: Option<LxdSshForwardConfig>,
+    /// Default diagnostics collected from nodes.
+    #[serde(default)]
+    diagnostics: Vec<LxdDiagnostic>,
+    /// Directory of the configuration file, which relative paths are
+    /// resolved against.
+    #[serde(skip)]
+    dir: Option<PathBuf>,
+}
+
+impl LxdBackendConfig {
+    /// Resolves a path relative to the directory of the configuration file,
+    /// if known.
+    fn resolve_path(&self, path: &Path) -> PathBuf {
+        match self.dir.as_deref() {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Returns the diagnostics of a system, or the default ones for nodes of
+    /// unknown systems.
+    fn diagnostics<'a>(&'a self, sysconf: Option<&'a LxdNodeConfig>) -> &'a [LxdDiagnostic] {
+        sysconf
+            .and_then(|c| c.diagnostics.as_deref())
+            .unwrap_or(&self.diagnostics)
+    }
+
+    /// Returns the setup steps of a system along with the names of their
+    /// sets, in order of the sets. Steps are rendered with the variables of
+    /// each reference and given values of built-in variables, then their
+    /// relative host paths are resolved.
+    fn setup_steps(
+        &self,
+        sysconf: &LxdNodeConfig,
+        builtins: &BTreeMap<String, String>,
+    ) -> Result<Vec<(String, LxdSetupStep)>, String> {
+        let mut all = vec![];
+        for setup_ref in &sysconf.setup_steps {
+            let steps = self.setup.get(&setup_ref.name).ok_or_else(|| {
+                format!(
+                    "setup steps \"{}\" not found in configuration",
+                    setup_ref.name
+                )
+            })?;
+
+            let mut vars = setup_ref.vars.clone();
+            vars.extend(builtins.iter().map(|(k, v)| (k.clone(), v.clone())));
+            for step in steps {
+                let step = step
+                    .render(&vars)
+                    .map_err(|e| format!("setup steps \"{}\": {}", setup_ref.name, e))?
+                    .resolve_paths(|p| self.resolve_path(p));
+                all.push((setup_ref.name.clone(), step));
+            }
+        }
+        Ok(all)
+    }
+
+    /// Returns the criteria for picking the address of a system, falling back
+    /// to the defaults.
+    fn address_filter<'a>(&'a self, sysconf: &'a LxdNodeConfig) -> LxdAddressFilter<'a> {
+        let networks = [sysconf.network.as_ref(), self.network.as_ref()];
+        LxdAddressFilter {
+            family: sysconf
+                .address_family
+                .or(self.address_family)
+                .unwrap_or_default(),
+            interface: networks
+                .iter()
+                .flatten()
+                .find_map(|n| n.interface.as_deref()),
+            subnet: networks.iter().flatten().find_map(|n| n.subnet),
+        }
+    }
+}
+
+fn default_queue_timeout() -> u64 {
+    600
+}
+
+/// Limits of resources assigned to all nodes.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+struct LxdBudget {
+    /// Total memory.
+    max_memory: Option<bytesize::ByteSize>,
+    /// Total number of CPUs.
+    max_cpu: Option<u32>,
+    /// Number of nodes.
+    max_nodes: Option<usize>,
+    /// Time to wait for resources to become available, in seconds. Fail right
+    /// away if 0.
+    #[serde(default = "default_queue_timeout")]
+    queue_timeout: u64,
+}
+
+impl LxdBudget {
+    /// Checks whether a node can be added to existing ones without exceeding
+    /// the budget. Returns a description of the exceeded limit otherwise.
+    fn check(&self, existing: &[LxdNodeUsage], cpu: u32, memory: u64) -> Result<(), String> {
+        if let Some(max_nodes) = self.max_nodes {
+            if existing.len() + 1 > max_nodes {
+                return Err(format!(
+                    "nodes limit exceeded, {} allocated of {}",
+                    existing.len(),
+                    max_nodes
+                ));
+            }
+        }
+        if let Some(max_cpu) = self.max_cpu {
+            let used: u32 = existing.iter().map(|n| n.cpu).sum();
+            if used + cpu > max_cpu {
+                return Err(format!(
+                    "CPU limit exceeded, {} requested with {} in use of {}",
+                    cpu, used, max_cpu
+                ));
+            }
+        }
+        if let Some(max_memory) = self.max_memory {
+            let used: u64 = existing.iter().map(|n| n.memory).sum();
+            if used + memory > max_memory.as_u64() {
+                return Err(format!(
+                    "memory limit exceeded, {} requested with {} in use of {}",
+                    bytesize::ByteSize(memory).to_string_as(true),
+                    bytesize::ByteSize(used).to_string_as(true),
+                    max_memory.to_string_as(true)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether a node fits the budget at all.
+    fn fits(&self, cpu: u32, memory: u64) -> bool {
+        self.check(&[], cpu, memory).is_ok()
+    }
+}
+
+/// Node kept for post-mortem.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct LxdPostmortemNode {
+    /// Name of the kept instance, optionally qualified with a remote.
+    pub name: String,
+    pub system: String,
+    /// Name of the instance while it was allocated.
+    pub instance: String,
+    /// Time of discard, RFC 3339.
+    pub discarded_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub export: Option<PathBuf>,
+}
+
+/// Maximum length of LXD instance names.
+const LXD_INSTANCE_NAME_MAX_CHARS: usize = 63;
+
+/// Returns the name of a node kept for post-mortem, with a suffix carrying
+/// the time of discard given in RFC 3339.
+fn postmortem_name(instance: &str, discarded_at: &str) -> String {
+    let stamp: String = discarded_at
+        .chars()
+        .filter(char::is_ascii_digit)
+        .take(14)
+        .collect();
+    let suffix = format!("-failed-{}", stamp);
+    let base: String = instance
+        .chars()
+        .take(LXD_INSTANCE_NAME_MAX_CHARS - suffix.len())
+        .collect();
+    format!("{}{}", base.trim_end_matches('-'), suffix)
+}
+
+/// Environment variable overriding the post-mortem mode of the user
+/// configuration, for debugging a single failing run.
+pub const POSTMORTEM_ENV: &str = "SPREAD_ADHOC_POSTMORTEM";
+
+/// What happens to nodes discarded by spread. Nodes are discarded whether
+/// their tasks passed or failed, thus a mode other than off keeps all of
+/// them, and is only useful for debugging a single failing run.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LxdPostmortemMode {
+    /// Nodes are deleted.
+    #[default]
+    Off,
+    /// Nodes are kept, with a snapshot of their state at discard.
+    Snapshot,
+    /// Nodes are kept, and exported to the artifacts directory.
+    Export,
+}
+
+impl FromStr for LxdPostmortemMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(LxdPostmortemMode::Off),
+            "snapshot" => Ok(LxdPostmortemMode::Snapshot),
+            "export" => Ok(LxdPostmortemMode::Export),
+            _ => Err(format!(
+                "invalid post-mortem mode \"{}\", expected off, snapshot or export",
+                s
+            )),
+        }
+    }
+}
+
+/// Keeping of nodes discarded by spread for post-mortem.
+#[derive(serde::Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+struct LxdPostmortemConfig {
+    #[serde(default)]
+    mode: LxdPostmortemMode,
+    /// Number of kept nodes, the oldest ones are deleted beyond it.
+    #[serde(default = "default_postmortem_retain")]
+    retain: usize,
+}
+
+fn default_postmortem_retain() -> usize {
+    3
+}
+
+impl Default for LxdPostmortemConfig {
+    fn default() -> Self {
+        LxdPostmortemConfig {
+            mode: Default::default(),
+            retain: default_postmortem_retain(),
+        }
+    }
+}
+
+/// User configuration for the LXD backend.
+#[derive(serde::Deserialize, Debug, Default)]
+struct LxdBackendUserConfig {
+    /// Limits of resources used by nodes.
+    budget: Option<LxdBudget>,
+    /// Keeping of discarded nodes for post-mortem.
+    #[serde(default)]
+    postmortem: LxdPostmortemConfig,
+}
+
+/// Builder for creating LxdAllocator.
+pub struct LxdAllocatorBuilder {
+    cfg: LxdBackendConfig,
+    user_cfg: LxdBackendUserConfig,
+    state_dir: Option<PathBuf>,
+    events: Box<dyn EventSink>,
+    postmortem_mode: Option<LxdPostmortemMode>,
+    config_dir: Option<PathBuf>,
+}
+
+impl LxdAllocatorBuilder {
+    pub fn new() -> Self {
+        LxdAllocatorBuilder {
+            cfg: Default::default(),
+            user_cfg: Default::default(),
+            state_dir: None,
+            events: Box::new(audit::NullSink),
+            postmortem_mode: None,
+            config_dir: None,
+        }
+    }
+
+    /// Override the post-mortem mode of the user configuration, as given in
+    /// the environment.
+    pub fn with_postmortem_mode(mut self, mode: Option<&str>) -> Result<Self, LxdError> {
+        self.postmortem_mode = mode
+            .map(|m| {
+                m.parse::<LxdPostmortemMode>()
+                    .map_err(|e| LxdError::ConfigInvalid(format!("{}: {}", POSTMORTEM_ENV, e)))
+            })
+            .transpose()?;
+        Ok(self)
+    }
+
+    /// Keep track of allocated nodes in a given directory.
+    pub fn with_state_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.state_dir = dir;
+        self
+    }
+
+    /// Record events of operations on nodes in a given sink.
+    pub fn with_event_sink(mut self, events: Box<dyn EventSink>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Resolve relative paths of the configuration against a given directory,
+    /// the one of the configuration file.
+    pub fn with_config_dir(mut self, dir: Option<&Path>) -> Self {
+        self.config_dir = dir.map(Path::to_path_buf);
+        self
+    }
+
+    pub fn with_config<R>(mut self, cfg: R) -> Result<Self, LxdError>
+    where
+        R: io::Read,
+    {
+        let conf: LxdBackendConfig = serde_yml::from_reader(cfg).map_err(LxdError::Config)?;
+        log::debug!("config: {:?}", conf);
+
+        // validate configuration consistency:
+        // - system setup steps are found and use defined variables
+        // - system settings are consistent
+
+        if let Some(placement) = conf.placement.as_ref() {
+            placement.validate().map_err(LxdError::ConfigInvalid)?;
+        }
+        if let Some(ssh_forward) = conf.ssh_forward.as_ref() {
+            ssh_forward.validate().map_err(LxdError::ConfigInvalid)?;
+        }
+        validate_diagnostics(&conf.diagnostics).map_err(LxdError::ConfigInvalid)?;
+
+        for (sysname, sysconf) in &conf.system {
+            sysconf
+                .validate()
+                .and_then(|_| conf.address_filter(sysconf).validate())
+                .map_err(|e| {
+                    LxdError::ConfigInvalid(format!("system \"{}\" is invalid, {}", sysname, e))
+                })?;
+            // built-in variables are only known at allocation time
+            let builtins = SETUP_BUILTIN_VARS
+                .iter()
+                .map(|v| (v.to_string(), String::new()))
+                .collect();
+            conf.setup_steps(sysconf, &builtins).map_err(|e| {
+                LxdError::ConfigInvalid(format!("system \"{}\" is invalid, {}", sysname, e))
+            })?;
+        }
+
+        self.cfg = conf;
+        Ok(self)
+    }
+
+    pub fn with_optional_user_config<R>(mut self, cfg: Option<R>) -> Result<Self, LxdError>
+    where
+        R: io::Read,
+    {
+        if let Some(cfg) = cfg {
+            let conf: LxdBackendUserConfig =
+                serde_yml::from_reader(cfg).map_err(LxdError::Config)?;
+            log::debug!("user config: {:?}", conf);
+
+            self.user_cfg = conf;
+        }
+        Ok(self)
+    }
+
+    pub fn build(self) -> LxdAllocator {
+        let mut cfg = self.cfg;
+        cfg.dir = self.config_dir;
+        let mut user_cfg = self.user_cfg;
+        if let Some(mode) = self.postmortem_mode {
+            user_cfg.postmortem.mode = mode;
+        }
+        LxdAllocator::new_with_config(cfg, user_cfg, self.state_dir.as_deref(), self.events)
+    }
+}
+
+/// Returns the file name of a LXD node allocator.
+pub fn config_file_name() -> &'static str {
+    "spread-lxd.yaml"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::{collections::VecDeque, str::FromStr};
+
+    use super::*;
+
+    const ONE_PROJECT_LIST: &str = r##"[{
+"name":"default","description":"Default LXD project","config":{"features.images":"true","features.networks":"true","features.networks.zones":"true","features.profiles":"true","features.storage.buckets":"true","features.storage.volumes":"true"},"used_by":["/1.0/profiles/default","/1.0/images/16c5963a3c55d17639f96099f8133d986601dbafc79c53d26ba384cbcfcd5bad","/1.0/networks/lxdbr0"]},{"name":"snapcraft","description":"","config":{"features.images":"true","features.profiles":"true","features.storage.buckets":"true","features.storage.volumes":"true"},"used_by":["/1.0/profiles/default?project=snapcraft"]},{"name":"spread-adhoc","description":"","config":{"features.images":"false","features.profiles":"false","features.storage.buckets":"true","features.storage.volumes":"true"},"used_by":["/1.0/storage-pools/default/volumes/virtual-machine/ubuntu-24-04-64-1744396627?project=spread-adhoc","/1.0/instances/ubuntu-24-04-64-1744396627?project=spread-adhoc"]
+}]"##;
+    const ONE_NODE_LIST: &str = r##"[{
+"name":"ubuntu-24-04-64-1744396627",
+"description":"","status":"Running","status_code":103,"created_at":"2025-01-26T14:33:11.319917616Z","last_used_at":"2025-01-26T14:33:19.637141509Z","location":"none","type":"virtual-machine","project":"spread-adhoc","architecture":"x86_64","ephemeral":true,"stateful":false,"profiles":["default"],"config":{"image.architecture":"amd64","image.description":"ubuntu 24.04 LTS amd64 (release) (20250115)","image.label":"release","image.os":"ubuntu","image.release":"noble","image.serial":"20250115","image.type":"disk1.img","image.version":"24.04","limits.cpu":"4","limits.memory":"4294967296","security.secureboot":"false","volatile.base_image":"16c5963a3c55d17639f96099f8133d986601dbafc79c53d26ba384cbcfcd5bad","volatile.cloud-init.instance-id":"0c428aad-043f-45e8-b4fe-edd762f72757","volatile.eth0.host_name":"tapc73ec1df","volatile.eth0.hwaddr":"00:16:3e:3d:1a:76","volatile.last_state.power":"RUNNING","volatile.uuid":"a3e00b40-df48-4939-b03e-bdaa962dd898","volatile.uuid.generation":"a3e00b40-df48-4939-b03e-bdaa962dd898","volatile.vsock_id":"721893514"},"devices":{"root":{"path":"/","pool":"default","size":"16106127360","type":"disk"}},"expanded_config":{"image.architecture":"amd64","image.description":"ubuntu 24.04 LTS amd64 (release) (20250115)","image.label":"release","image.os":"ubuntu","image.release":"noble","image.serial":"20250115","image.type":"disk1.img","image.version":"24.04","limits.cpu":"4","limits.memory":"4294967296","security.secureboot":"false","volatile.base_image":"16c5963a3c55d17639f96099f8133d986601dbafc79c53d26ba384cbcfcd5bad","volatile.cloud-init.instance-id":"0c428aad-043f-45e8-b4fe-edd762f72757","volatile.eth0.host_name":"tapc73ec1df","volatile.eth0.hwaddr":"00:16:3e:3d:1a:76","volatile.last_state.power":"RUNNING","volatile.uuid":"a3e00b40-df48-4939-b03e-bdaa962dd898","volatile.uuid.generation":"a3e00b40-df48-4939-b03e-bdaa962dd898","volatile.vsock_id":"721893514"},"expanded_devices":{"eth0":{"name":"eth0","network":"lxdbr0","type":"nic"},"root":{"path":"/","pool":"default","size":"16106127360","type":"disk"}},"backups":null,"state":{"status":"Running","status_code":103,"disk":null,"memory":{"usage":444915712,"usage_peak":0,"total":4097273856,"swap_usage":0,"swap_usage_peak":0},"network":{"enp5s0":{"addresses":[{"family":"inet","address":"10.22.100.75","netmask":"24","scope":"global"},{"family":"inet6","address":"fd42:2245:81ae:90da:216:3eff:fe3d:1a76","netmask":"64","scope":"global"},{"family":"inet6","address":"fe80::216:3eff:fe3d:1a76","netmask":"64","scope":"link"}],"counters":{"bytes_received":316098,"bytes_sent":13324,"packets_received":220,"packets_sent":148,"errors_received":0,"errors_sent":0,"packets_dropped_outbound":0,"packets_dropped_inbound":0},"hwaddr":"00:16:3e:3d:1a:76","host_name":"tapc73ec1df","mtu":1500,"state":"up","type":"broadcast"},"lo":{"addresses":[{"family":"inet","address":"127.0.0.1","netmask":"8","scope":"local"},{"family":"inet6","address":"::1","netmask":"128","scope":"local"}],"counters":{"bytes_received":7652,"bytes_sent":7652,"packets_received":96,"packets_sent":96,"errors_received":0,"errors_sent":0,"packets_dropped_outbound":0,"packets_dropped_inbound":0},"hwaddr":"","host_name":"","mtu":65536,"state":"up","type":"loopback"}},"pid":134121,"processes":21,"cpu":{"usage":12200111000}},"snapshots":null
+}]"##;
+
+    struct MockLxcRunner {
+        seen_calls: VecDeque<Vec<String>>,
+        outputs: VecDeque<Result<Vec<u8>, LxcRunnerError>>,
+    }
+
+    impl MockLxcRunner {
+        fn new(calls: Vec<Result<Vec<u8>, LxcRunnerError>>) -> Self {
+            Self {
+                seen_calls: VecDeque::new(),
+                outputs: VecDeque::from(calls),
+            }
+        }
+    }
+
+    impl LxcRunner for MockLxcRunner {
+        fn run(&mut self, cmd: LxcCommand) -> Result<Vec<u8>, LxcRunnerError> {
+            let LxcCommand(cmd) = cmd;
+            let call = cmd
+                .get_args()
+                .by_ref()
+                .map(|v| v.to_string_lossy().to_string())
+                .collect();
+
+            let out = self
+                .outputs
+                .pop_front()
+                .unwrap_or_else(|| panic!("expected mock result for call {:?}", call));
+
+            match out.as_ref() {
+                Ok(out) => eprintln!("call {:?} output: {}", call, String::from_utf8_lossy(out)),
+                Err(err) => eprintln!("call {:?} error: {}", call, err),
+            }
+            self.seen_calls.push_back(call);
+            out
+        }
+    }
+
+    #[test]
+    fn test_cli_alloc_emsure_project_exists() {
+        let r = MockLxcRunner::new(vec![Ok(ONE_PROJECT_LIST.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        let res = a.ensure_project(None, LXD_PROJECT_NAME);
+        assert!(res.is_ok());
+
+        let mut r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), 1);
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["project", "list", "--format=json",]
+        );
+    }
+
+    #[test]
+    fn test_cli_alloc_ensure_project_add() {
+        let r = MockLxcRunner::new(vec![
+            Ok("[]".as_bytes().to_vec()),
+            Ok("".as_bytes().to_vec()),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        let res = a.ensure_project(None, LXD_PROJECT_NAME);
+        assert!(res.is_ok());
+
+        let mut r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), 2);
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
             vec!["project", "list", "--format=json",]
         );
         assert_eq!(
-            r.seen_calls.pop_front().expect("expected a call"),
-            vec![
-                "project",
-                "create",
-                "spread-adhoc",
-                "-c",
-                "features.images=false",
-                "-c",
-                "features.profiles=false"
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "project",
+                "create",
+                "spread-adhoc",
+                "-c",
+                "features.images=false",
+                "-c",
+                "features.profiles=false"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loggable_args() {
+        let LxcCommand(cmd) = LxcCommandBuilder::new()
+            .with_scope(LxcCommandScope::Project(LXD_PROJECT_NAME))
+            .args(&[
+                "exec",
+                "node",
+                "--",
+                "/bin/bash",
+                "-c",
+                "echo ubuntu:secret | chpasswd",
+            ])
+            .build();
+        assert_eq!(
+            loggable_args(&cmd),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "exec",
+                "node",
+                "--",
+                "/bin/bash",
+                "-c",
+                "<script>"
+            ]
+        );
+
+        // options of lxc are kept
+        let LxcCommand(cmd) = LxcCommandBuilder::new()
+            .args(&[
+                "project",
+                "create",
+                "spread-adhoc",
+                "-c",
+                "features.images=false",
+            ])
+            .build();
+        assert_eq!(
+            loggable_args(&cmd),
+            vec![
+                "project",
+                "create",
+                "spread-adhoc",
+                "-c",
+                "features.images=false"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_ensure_project_already_exists() {
+        let r = MockLxcRunner::new(vec![
+            Ok("[]".as_bytes().to_vec()),
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: Project \"spread-adhoc\" already exists".to_string(),
+                exit_code: 1,
+            }),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(a.ensure_project(None, LXD_PROJECT_NAME), Ok(()));
+    }
+
+    #[test]
+    fn test_cli_ensure_project_error() {
+        let r = MockLxcRunner::new(vec![
+            Ok("[]".as_bytes().to_vec()),
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: permission denied".to_string(),
+                exit_code: 1,
+            }),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        assert!(a.ensure_project(None, LXD_PROJECT_NAME).is_err());
+    }
+
+    #[test]
+    fn test_cli_ensure_project_malformed() {
+        let r = MockLxcRunner::new(vec![Ok("WARNING: cgroup v2 is not fully supported\n[]"
+            .as_bytes()
+            .to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            a.ensure_project(None, LXD_PROJECT_NAME)
+                .expect_err("unexpected success")
+                .to_string(),
+            "cannot execute operation: cannot parse project list: expected value at line 1 column 1, output: 'WARNING: cgroup v2 is not fully supported\n[]'"
+        );
+    }
+
+    /// Mock runner handling project commands, where all instances share the
+    /// projects like a single LXD server would.
+    #[derive(Clone)]
+    struct SharedProjectsLxcRunner {
+        projects: Arc<Mutex<Vec<String>>>,
+        creates: Arc<AtomicUsize>,
+    }
+
+    impl LxcRunner for SharedProjectsLxcRunner {
+        fn run(&mut self, cmd: LxcCommand) -> Result<Vec<u8>, LxcRunnerError> {
+            let LxcCommand(cmd) = cmd;
+            let call: Vec<String> = cmd
+                .get_args()
+                .map(|v| v.to_string_lossy().to_string())
+                .collect();
+
+            match call.get(1).map(String::as_str) {
+                Some("list") => {
+                    let projects = self.projects.lock().unwrap().clone();
+                    // widen the window for racing with other callers
+                    thread::sleep(time::Duration::from_millis(20));
+                    Ok(format!(
+                        "[{}]",
+                        projects
+                            .iter()
+                            .map(|p| format!("{{\"name\":\"{}\"}}", p))
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    )
+                    .into_bytes())
+                }
+                Some("create") => {
+                    let mut projects = self.projects.lock().unwrap();
+                    if projects.contains(&call[2]) {
+                        return Err(LxcRunnerError::Execution {
+                            stderr: format!("Error: Project \"{}\" already exists", call[2]),
+                            exit_code: 1,
+                        });
+                    }
+                    projects.push(call[2].clone());
+                    self.creates.fetch_add(1, Ordering::SeqCst);
+                    Ok(vec![])
+                }
+                _ => panic!("unexpected call {:?}", call),
+            }
+        }
+    }
+
+    fn run_concurrent_ensure_project(lock: Option<&Path>) -> SharedProjectsLxcRunner {
+        let runner = SharedProjectsLxcRunner {
+            projects: Arc::new(Mutex::new(vec!["default".to_string()])),
+            creates: Arc::new(AtomicUsize::new(0)),
+        };
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let mut a = LxdCliAllocator::new(runner.clone());
+                if let Some(lock) = lock {
+                    a = a.with_project_lock(lock);
+                }
+                thread::spawn(move || a.ensure_project(None, LXD_PROJECT_NAME))
+            })
+            .collect();
+
+        for w in workers {
+            assert_eq!(w.join().expect("worker failed"), Ok(()));
+        }
+        runner
+    }
+
+    #[test]
+    fn test_cli_ensure_project_concurrent_locked() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-project-lock-{}",
+            std::process::id()
+        ));
+
+        let runner = run_concurrent_ensure_project(Some(&dir.join("project.lock")));
+        // project was created exactly once, others found it
+        assert_eq!(runner.creates.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            *runner.projects.lock().unwrap(),
+            vec!["default".to_string(), "spread-adhoc".to_string()]
+        );
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_cli_ensure_project_concurrent_unlocked() {
+        // racing creation is not an error
+        let runner = run_concurrent_ensure_project(None);
+        assert_eq!(runner.creates.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_cli_list_nodes_none() {
+        let r = MockLxcRunner::new(vec![Ok("[]".as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        let res = a.list_nodes(None);
+        assert!(res.is_ok());
+        let nodes = res.expect("unexpected error");
+        assert_eq!(nodes.len(), 0);
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), 1);
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["--project", "spread-adhoc", "list", "--format=json",]
+        );
+    }
+
+    #[test]
+    fn test_cli_list_nodes_malformed() {
+        let r = MockLxcRunner::new(vec![Ok(r#"[{"name": "foo""#.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        let err = a.list_nodes(None).expect_err("unexpected success");
+        assert!(
+            matches!(err, LxcCliAllocatorError::Parse { what: "instance list", ref output, .. } if output == r#"[{"name": "foo""#),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_cli_list_node_by_name_malformed() {
+        // long output is truncated
+        let output = format!("[{}", "x".repeat(1000));
+        let r = MockLxcRunner::new(vec![Ok(output.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        let err = a
+            .list_node_by_name(None, "foo")
+            .expect_err("unexpected success");
+        match err {
+            LxcCliAllocatorError::Parse { what, output, .. } => {
+                assert_eq!(what, "instance");
+                assert_eq!(
+                    output,
+                    format!("[{}...", "x".repeat(PARSE_ERROR_OUTPUT_MAX_CHARS - 1))
+                );
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_cli_list_nodes_some() {
+        let r = MockLxcRunner::new(vec![Ok(ONE_NODE_LIST.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        let res = a.list_nodes(None);
+        assert!(res.is_ok());
+        let mut nodes = res.expect("unexpected error");
+        assert_eq!(
+            nodes.pop().expect("expected an instance"),
+            lxc::types::Instance {
+                name: "ubuntu-24-04-64-1744396627".to_string(),
+                status: "Running".to_string(),
+                state: lxc::types::InstanceState {
+                    network: Some(HashMap::from([
+                        (
+                            "lo".to_string(),
+                            lxc::types::NetworkState {
+                                addresses: vec![
+                                    lxc::types::NetworkAddress {
+                                        family: "inet".to_string(),
+                                        address: "127.0.0.1".to_string(),
+                                    },
+                                    lxc::types::NetworkAddress {
+                                        family: "inet6".to_string(),
+                                        address: "::1".to_string(),
+                                    }
+                                ],
+                            }
+                        ),
+                        (
+                            "enp5s0".to_string(),
+                            lxc::types::NetworkState {
+                                addresses: vec![
+                                    lxc::types::NetworkAddress {
+                                        family: "inet".to_string(),
+                                        address: "10.22.100.75".to_string(),
+                                    },
+                                    lxc::types::NetworkAddress {
+                                        family: "inet6".to_string(),
+                                        address: "fd42:2245:81ae:90da:216:3eff:fe3d:1a76"
+                                            .to_string(),
+                                    },
+                                    lxc::types::NetworkAddress {
+                                        family: "inet6".to_string(),
+                                        address: "fe80::216:3eff:fe3d:1a76".to_string(),
+                                    },
+                                ],
+                            }
+                        ),
+                    ]),),
+                }
+            }
+        );
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), 1);
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["--project", "spread-adhoc", "list", "--format=json"]
+        );
+    }
+
+    #[test]
+    fn test_cli_discard_by_addr() {
+        let mock_results = vec![
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()),
+            Ok("".as_bytes().to_vec()),
+        ];
+        let mock_results_len = mock_results.len();
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        a.discard_by_addr(None, "10.22.100.75")
+            .expect("unexpected error");
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), mock_results_len);
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["--project", "spread-adhoc", "list", "--format=json"]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "delete",
+                "--force",
+                "ubuntu-24-04-64-1744396627"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_discard_by_name_not_found() {
+        let r = MockLxcRunner::new(vec![Err(LxcRunnerError::Execution {
+            stderr: "Error: Failed checking instance exists \"local:foo\": Instance not found"
+                .to_string(),
+            exit_code: 1,
+        })]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            a.discard_by_name(Some("local"), "foo"),
+            Err(LxdError::NotFound("node local:foo not found".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_cli_discard_by_name() {
+        let mock_results = vec![Ok("".as_bytes().to_vec())];
+        let mock_results_len = mock_results.len();
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        a.discard_by_name(None, "ubuntu-24-04-64-1744396627")
+            .expect("unexpected error");
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), mock_results_len);
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "delete",
+                "--force",
+                "ubuntu-24-04-64-1744396627"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_allocate() {
+        let mock_results = vec![
+            Ok("".as_bytes().to_vec()),            // lxc launch
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+            Ok("".as_bytes().to_vec()),            // lxc exec
+        ];
+        let mock_results_len = mock_results.len();
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        let res = a.allocate(&LxdNodeDetails {
+            image: "ubuntu:24.04",
+            name: "ubuntu-24-04-64-1744396627",
+            cpu: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+            root_size: 16 * 1024 * 1024 * 1024,
+            vm: true,
+            secure_boot: false,
+            provision_steps: &[("common".to_string(), LxdSetupStep::run("echo foo"))],
+            ..Default::default()
+        });
+        let node = res.expect("allocation failed");
+        assert_eq!(node.name, "ubuntu-24-04-64-1744396627");
+        assert_eq!(node.addr, net::IpAddr::from_str("10.22.100.75").unwrap());
+        assert_eq!(node.ssh_port, 22);
+        assert_eq!(
+            node.phases
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "launch",
+                "address",
+                "provision",
+                "provision-step-1",
+                "ready"
+            ]
+        );
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), mock_results_len);
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "launch",
+                "--ephemeral",
+                "--vm",
+                "--config",
+                "limits.memory=8589934592",
+                "--config",
+                "limits.cpu=4",
+                "--config",
+                "security.secureboot=false",
+                "--device",
+                "root,size=17179869184",
+                "ubuntu:24.04",
+                "ubuntu-24-04-64-1744396627",
+            ],
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "list",
+                "--format=json",
+                "ubuntu-24-04-64-1744396627",
+            ]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "exec",
+                "ubuntu-24-04-64-1744396627",
+                "--",
+                "/bin/bash",
+                "-c",
+                "echo foo",
+            ]
+        );
+    }
+
+    fn family(family: LxdAddressFamily) -> LxdAddressFilter<'static> {
+        LxdAddressFilter {
+            family,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_select_address() {
+        let nodes: Vec<lxc::types::Instance> =
+            serde_json::from_str(ONE_NODE_LIST).expect("cannot parse");
+        let network = nodes[0].state.network.clone().expect("no network state");
+
+        assert_eq!(
+            select_address(&network, &family(LxdAddressFamily::Ipv4)),
+            Ok("10.22.100.75".parse().unwrap())
+        );
+        // link-local address is skipped
+        assert_eq!(
+            select_address(&network, &family(LxdAddressFamily::Ipv6)),
+            Ok("fd42:2245:81ae:90da:216:3eff:fe3d:1a76".parse().unwrap())
+        );
+        assert_eq!(
+            select_address(&network, &family(LxdAddressFamily::Any)),
+            Ok("10.22.100.75".parse().unwrap())
+        );
+
+        let v6_only: HashMap<String, lxc::types::NetworkState> = serde_json::from_str(
+            r#"{
+"enp5s0":{"addresses":[{"family":"inet6","address":"fe80::216:3eff:fe3d:1a76"},{"family":"inet6","address":"fd42::75"}]},
+"lo":{"addresses":[{"family":"inet","address":"127.0.0.1"},{"family":"inet6","address":"::1"}]}
+}"#,
+        )
+        .expect("cannot parse");
+        assert_eq!(
+            select_address(&v6_only, &family(LxdAddressFamily::Ipv4)),
+            Err("no IPv4 address among interfaces: enp5s0 (fe80::216:3eff:fe3d:1a76, fd42::75), lo (127.0.0.1, ::1)".to_string())
+        );
+        assert_eq!(
+            select_address(&v6_only, &family(LxdAddressFamily::Any)),
+            Ok("fd42::75".parse().unwrap())
+        );
+
+        let link_local_only: HashMap<String, lxc::types::NetworkState> = serde_json::from_str(
+            r#"{"eth0":{"addresses":[{"family":"inet","address":"169.254.10.1"},{"family":"inet6","address":"fe80::1"}]}}"#,
+        )
+        .expect("cannot parse");
+        assert!(select_address(&link_local_only, &family(LxdAddressFamily::Any)).is_err());
+
+        assert_eq!(
+            select_address(&HashMap::new(), &family(LxdAddressFamily::Any)),
+            Err("no network interfaces seen".to_string())
+        );
+    }
+
+    #[test]
+    fn test_select_address_multiple_interfaces() {
+        let network: HashMap<String, lxc::types::NetworkState> = serde_json::from_str(
+            r#"{
+"enp6s0":{"addresses":[{"family":"inet","address":"192.168.1.20"}]},
+"docker0":{"addresses":[{"family":"inet","address":"172.17.0.1"}]},
+"enp5s0":{"addresses":[{"family":"inet","address":"10.22.100.75"}]},
+"lo":{"addresses":[{"family":"inet","address":"127.0.0.1"}]}
+}"#,
+        )
+        .expect("cannot parse");
+
+        // first interface by name
+        assert_eq!(
+            select_address(&network, &family(LxdAddressFamily::Ipv4)),
+            Ok("172.17.0.1".parse().unwrap())
+        );
+        assert_eq!(
+            select_address(
+                &network,
+                &LxdAddressFilter {
+                    interface: Some("enp*"),
+                    ..Default::default()
+                }
+            ),
+            Ok("10.22.100.75".parse().unwrap())
+        );
+        assert_eq!(
+            select_address(
+                &network,
+                &LxdAddressFilter {
+                    interface: Some("enp6s0"),
+                    ..Default::default()
+                }
+            ),
+            Ok("192.168.1.20".parse().unwrap())
+        );
+        assert_eq!(
+            select_address(
+                &network,
+                &LxdAddressFilter {
+                    subnet: Some("192.168.0.0/16".parse().unwrap()),
+                    ..Default::default()
+                }
+            ),
+            Ok("192.168.1.20".parse().unwrap())
+        );
+        assert_eq!(
+            select_address(
+                &network,
+                &LxdAddressFilter {
+                    interface: Some("enp?s0"),
+                    subnet: Some("10.0.0.0/8".parse().unwrap()),
+                    ..Default::default()
+                }
+            ),
+            Ok("10.22.100.75".parse().unwrap())
+        );
+        assert_eq!(
+            select_address(
+                &network,
+                &LxdAddressFilter {
+                    interface: Some("eth*"),
+                    subnet: Some("10.0.0.0/8".parse().unwrap()),
+                    ..Default::default()
+                }
+            ),
+            Err("no IPv4 address on interface eth* in subnet 10.0.0.0/8 among interfaces: docker0 (172.17.0.1), enp5s0 (10.22.100.75), enp6s0 (192.168.1.20), lo (127.0.0.1)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("enp5s0", "enp5s0"));
+        assert!(!glob_match("enp5s0", "enp5s01"));
+        assert!(glob_match("enp*", "enp5s0"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("e*s?", "enp5s0"));
+        assert!(!glob_match("e*s?", "enp5s"));
+        assert!(!glob_match("eth*", "enp5s0"));
+    }
+
+    #[test]
+    fn test_subnet() {
+        let subnet: LxdSubnet = "10.22.100.0/24".parse().expect("cannot parse");
+        assert!(subnet.contains(&"10.22.100.75".parse().unwrap()));
+        assert!(!subnet.contains(&"10.22.101.75".parse().unwrap()));
+        assert!(!subnet.contains(&"fd42::75".parse().unwrap()));
+        assert_eq!(subnet.to_string(), "10.22.100.0/24");
+
+        let subnet: LxdSubnet = "fd42:2245:81ae:90da::/64".parse().expect("cannot parse");
+        assert!(subnet.contains(&"fd42:2245:81ae:90da:216:3eff:fe3d:1a76".parse().unwrap()));
+        assert!(!subnet.contains(&"fd42:2245:81ae:90db::1".parse().unwrap()));
+
+        let any: LxdSubnet = "0.0.0.0/0".parse().expect("cannot parse");
+        assert!(any.contains(&"192.168.1.1".parse().unwrap()));
+
+        assert!("10.22.100.0".parse::<LxdSubnet>().is_err());
+        assert!("10.22.100.0/33".parse::<LxdSubnet>().is_err());
+        assert!("foo/24".parse::<LxdSubnet>().is_err());
+    }
+
+    #[test]
+    fn test_cli_discard_by_addr_ipv6() {
+        let mock_results = vec![
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+            Ok("".as_bytes().to_vec()),            // lxc delete
+        ];
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        // non-canonical notation of the same address
+        a.discard_by_addr(None, "fd42:2245:81ae:90da:0216:3eff:fe3d:1a76")
+            .expect("discard failed");
+        let r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), 2);
+    }
+
+    #[test]
+    fn test_cli_provision_steps() {
+        let mock_results = vec![
+            Ok("".as_bytes().to_vec()), // lxc exec
+            Ok("".as_bytes().to_vec()), // lxc file push
+            Ok("".as_bytes().to_vec()), // lxc file push
+            Ok("".as_bytes().to_vec()), // lxc file pull
+        ];
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        let durations = a
+            .provision(
+                Some("cluster"),
+                "ubuntu-24-04-64-1744396627",
+                &[
+                    LxdSetupStep::Run {
+                        command: "systemctl restart ssh".to_string(),
+                        user: Some(1000),
+                        env: BTreeMap::from([
+                            ("LANG".to_string(), "C".to_string()),
+                            ("FOO".to_string(), "bar baz".to_string()),
+                        ]),
+                        cwd: Some("/home/ubuntu".to_string()),
+                        timeout: Some(60),
+                    },
+                    LxdSetupStep::Push {
+                        path: "/etc/foo.conf".to_string(),
+                        source: LxdPushSource::File(PathBuf::from("foo.conf")),
+                        mode: Some("0600".to_string()),
+                        uid: Some(0),
+                        gid: Some(0),
+                    },
+                    LxdSetupStep::Push {
+                        path: "/etc/bar.conf".to_string(),
+                        source: LxdPushSource::Content("bar\n".to_string()),
+                        mode: None,
+                        uid: None,
+                        gid: None,
+                    },
+                    LxdSetupStep::Pull {
+                        path: "/var/log/syslog".to_string(),
+                        destination: PathBuf::from("syslog"),
+                    },
+                ]
+                .map(|step| ("common".to_string(), step)),
+                None,
+            )
+            .expect("unexpected error");
+        assert_eq!(durations.len(), 4);
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "exec",
+                "--user=1000",
+                "--cwd=/home/ubuntu",
+                "--env=FOO=bar baz",
+                "--env=LANG=C",
+                "cluster:ubuntu-24-04-64-1744396627",
+                "--",
+                "timeout",
+                "60",
+                "/bin/bash",
+                "-c",
+                "systemctl restart ssh",
+            ]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "file",
+                "push",
+                "--create-dirs",
+                "--mode=0600",
+                "--uid=0",
+                "--gid=0",
+                "foo.conf",
+                "cluster:ubuntu-24-04-64-1744396627/etc/foo.conf",
+            ]
+        );
+        let call = r.seen_calls.pop_front().expect("expected a call");
+        assert_eq!(
+            call[..5],
+            ["--project", "spread-adhoc", "file", "push", "--create-dirs"]
+        );
+        assert_eq!(call[6], "cluster:ubuntu-24-04-64-1744396627/etc/bar.conf");
+        // temporary file with the content is gone
+        assert!(!Path::new(&call[5]).exists());
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "file",
+                "pull",
+                "cluster:ubuntu-24-04-64-1744396627/var/log/syslog",
+                "syslog",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_provision_step_error() {
+        let r = MockLxcRunner::new(vec![Err(LxcRunnerError::Execution {
+            stderr: "Error: not found".to_string(),
+            exit_code: 1,
+        })]);
+        let mut a = LxdCliAllocator::new(r);
+        let err = a
+            .provision(
+                None,
+                "ubuntu-24-04-64-1744396627",
+                &[(
+                    "debug".to_string(),
+                    LxdSetupStep::Pull {
+                        path: "/var/log/syslog".to_string(),
+                        destination: PathBuf::from("syslog"),
+                    },
+                )],
+                None,
+            )
+            .expect_err("unexpected success");
+        assert_eq!(
+            err.to_string(),
+            "cannot provision node: debug step pull: /var/log/syslog: exited with status 1"
+        );
+    }
+
+    #[test]
+    fn test_cli_provision_transcript() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-transcript-{}",
+            std::process::id()
+        ));
+        let path = dir.join("logs/node.transcript");
+        let output: String = (1..=40).map(|i| format!("line {}\n", i)).collect();
+        let r = MockLxcRunner::new(vec![
+            Ok("hello\n".as_bytes().to_vec()),
+            Err(LxcRunnerError::Execution {
+                stderr: format!("{}{}", output, "x".repeat(500)),
+                exit_code: 100,
+            }),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        let steps = [
+            ("common", "echo hello"),
+            (
+                "extra",
+                "apt update\napt install -y \\\n  a \\\n  b \\\n  c \\\n  d",
+            ),
+        ]
+        .map(|(set, cmd)| (set.to_string(), LxdSetupStep::run(cmd)));
+        let err = a
+            .provision(None, "node", &steps, Some(&path))
+            .expect_err("unexpected success");
+
+        // the error is a single line with the step clipped
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "cannot provision node: extra step run: apt update...: exited with status 100 (transcript: {})",
+                path.display()
+            )
+        );
+
+        // the transcript has the complete record of all steps
+        let transcript = std::fs::read_to_string(&path).expect("cannot read transcript");
+        let transcript: Vec<&str> = transcript.lines().collect();
+        assert_eq!(transcript[0], "--- common step 1/2: run: echo hello");
+        assert!(transcript[1].starts_with("--- exit status 0, took "));
+        assert_eq!(
+            transcript[2..5],
+            [
+                "--- stdout:",
+                "hello",
+                "--- extra step 2/2: run: apt update"
+            ]
+        );
+        assert!(transcript[10].starts_with("--- exit status 100, took "));
+        assert_eq!(transcript[11..14], ["--- stderr:", "line 1", "line 2"]);
+        assert_eq!(transcript[transcript.len() - 1], "x".repeat(500));
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_cli_provision_user_access_redacted() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-transcript-redacted-{}",
+            std::process::id()
+        ));
+        let path = dir.join("logs/node.transcript");
+        let r = MockLxcRunner::new(vec![Err(LxcRunnerError::Execution {
+            stderr: "chpasswd: cannot open /etc/shadow".to_string(),
+            exit_code: 1,
+        })]);
+        let mut a = LxdCliAllocator::new(r);
+        let steps = [(
+            USER_ACCESS_SETUP.to_string(),
+            LxdSetupStep::run("echo ubuntu:s3cret | chpasswd"),
+        )];
+        let err = a
+            .provision(None, "node", &steps, Some(&path))
+            .expect_err("unexpected success");
+
+        let msg = err.to_string();
+        assert!(!msg.contains("s3cret"), "{}", msg);
+        assert!(
+            msg.starts_with(
+                "cannot provision node: user-access step <set password for user>: exited with status 1"
+            ),
+            "{}",
+            msg
+        );
+        let transcript = std::fs::read_to_string(&path).expect("cannot read transcript");
+        assert!(!transcript.contains("s3cret"), "{}", transcript);
+        assert!(transcript.starts_with("--- user-access step 1/1: <set password for user>\n"));
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_cli_wait_for_address_not_running() {
+        let stopped = ONE_NODE_LIST.replace("\"Running\"", "\"Stopped\"");
+        let r = MockLxcRunner::new(vec![Ok(stopped.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        let err = a
+            .wait_for_address(
+                None,
+                "ubuntu-24-04-64-1744396627",
+                &Default::default(),
+                time::Duration::ZERO,
+            )
+            .expect_err("unexpected success");
+        assert_eq!(
+            err.to_string(),
+            "cannot obtain address, instance is Stopped"
+        );
+    }
+
+    #[test]
+    fn test_cli_address_timeout_console_log() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-console-{}",
+            std::process::id()
+        ));
+        let path = dir.join("logs/node.console");
+        let console: String = (1..=30)
+            .map(|i| format!("\x1b[0;32m[  OK  ]\x1b[0m step {}\r\n", i))
+            .collect();
+        let r = MockLxcRunner::new(vec![Ok(console.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        let err = a.address_timeout_error(
+            &LxdNodeDetails {
+                vm: true,
+                console_log: Some(&path),
+                ..Default::default()
+            },
+            "node",
+            time::Duration::from_secs(60),
+            "no IPv4 address among interfaces: enp5s0 (fe80::1)",
+        );
+        // the error is a single line naming the console log
+        assert_eq!(
+            err,
+            LxdError::Timeout(format!(
+                "cannot obtain address of node within 60s, no IPv4 address among interfaces: enp5s0 (fe80::1) (console log: {})",
+                path.display()
+            ))
+        );
+        let saved = std::fs::read_to_string(&path).expect("cannot read console log");
+        assert_eq!(saved.lines().count(), 30);
+        assert!(saved.starts_with("[  OK  ] step 1\n"));
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["--project", "spread-adhoc", "console", "--show-log", "node"]
+        );
+
+        // containers have their log shown instead, which may not be available
+        let r = MockLxcRunner::new(vec![Err(LxcRunnerError::Execution {
+            stderr: "Error: not found".to_string(),
+            exit_code: 1,
+        })]);
+        let mut a = LxdCliAllocator::new(r);
+        let err = a.address_timeout_error(
+            &LxdNodeDetails {
+                remote: Some("cluster"),
+                vm: false,
+                ..Default::default()
+            },
+            "node",
+            time::Duration::from_secs(60),
+            "instance is Stopped",
+        );
+        assert_eq!(
+            err,
+            LxdError::Timeout(
+                "cannot obtain address of node within 60s, instance is Stopped".to_string()
+            )
+        );
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "info",
+                "--show-log",
+                "cluster:node"
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_strip_terminal_codes() {
+        assert_eq!(strip_terminal_codes("plain\ttext\n"), "plain\ttext\n");
+        assert_eq!(
+            strip_terminal_codes("\x1b[2J\x1b[01;01H\x1b[=3hBooting\r\n\x1bcdone\x07"),
+            "Booting\ndone"
+        );
+    }
+
+    #[test]
+    fn test_cli_collect_diagnostics() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-diagnostics-{}",
+            std::process::id()
+        ));
+        let r = MockLxcRunner::new(vec![
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+            Err(LxcRunnerError::Execution {
+                stderr: "-- No entries --".to_string(),
+                exit_code: 1,
+            }), // lxc exec
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: not found".to_string(),
+                exit_code: 1,
+            }), // lxc file pull
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        let diagnostics = [
+            LxdDiagnostic {
+                source: LxdDiagnosticSource::Command("journalctl -b".to_string()),
+                name: "journal.txt".to_string(),
+            },
+            LxdDiagnostic {
+                source: LxdDiagnosticSource::File("/var/log/cloud-init.log".to_string()),
+                name: "cloud-init.log".to_string(),
+            },
+        ];
+        let node_dir = collect_node_diagnostics(
+            &mut a,
+            Some(&dir),
+            Some("cluster"),
+            "ubuntu-24-04-64-1744396627",
+            &diagnostics,
+        )
+        .expect("unexpected error");
+        assert_eq!(node_dir, dir.join("ubuntu-24-04-64-1744396627"));
+        // output of failed commands is kept
+        assert_eq!(
+            std::fs::read_to_string(node_dir.join("journal.txt")).expect("cannot read"),
+            "-- No entries --"
+        );
+        assert!(!node_dir.join("cloud-init.log").exists());
+
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "list",
+                "--format=json",
+                "cluster:ubuntu-24-04-64-1744396627"
+            ]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "exec",
+                "cluster:ubuntu-24-04-64-1744396627",
+                "--",
+                "/bin/bash",
+                "-c",
+                "journalctl -b"
+            ]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project".to_string(),
+                "spread-adhoc".to_string(),
+                "file".to_string(),
+                "pull".to_string(),
+                "cluster:ubuntu-24-04-64-1744396627/var/log/cloud-init.log".to_string(),
+                node_dir
+                    .join("cloud-init.log")
+                    .to_string_lossy()
+                    .to_string(),
+            ]
+        );
+
+        // a node which is gone
+        let r = MockLxcRunner::new(vec![Ok("[]".as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            collect_node_diagnostics(&mut a, Some(&dir), None, "foo", &diagnostics),
+            Err(LxdError::NotFound("node foo not found".to_string()))
+        );
+        // nothing to collect
+        assert_eq!(
+            collect_node_diagnostics(&mut a, Some(&dir), None, "foo", &[]),
+            Err(LxdError::ConfigInvalid(
+                "no diagnostics declared in configuration".to_string()
+            ))
+        );
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_cli_allocate_cloud_init() {
+        let mock_results = vec![
+            Ok("".as_bytes().to_vec()),               // lxc launch
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()),    // lxc list
+            Ok("status: done\n".as_bytes().to_vec()), // lxc exec cloud-init status
+        ];
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        a.allocate(&LxdNodeDetails {
+            image: "ubuntu:24.04",
+            name: "ubuntu-24-04-64-1744396627",
+            cpu: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+            root_size: 16 * 1024 * 1024 * 1024,
+            vm: false,
+            cloud_init: &[
+                (
+                    "cloud-init.user-data".to_string(),
+                    "#cloud-config\npackages: [jq]\n".to_string(),
+                ),
+                (
+                    "cloud-init.vendor-data".to_string(),
+                    "#cloud-config\n".to_string(),
+                ),
+            ],
+            ..Default::default()
+        })
+        .expect("unexpected error");
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "launch",
+                "--ephemeral",
+                "--config",
+                "limits.memory=8589934592",
+                "--config",
+                "limits.cpu=4",
+                "--config",
+                "cloud-init.user-data=#cloud-config\npackages: [jq]\n",
+                "--config",
+                "cloud-init.vendor-data=#cloud-config\n",
+                "--device",
+                "root,size=17179869184",
+                "ubuntu:24.04",
+                "ubuntu-24-04-64-1744396627",
+            ],
+        );
+        // the node is ready once cloud-init has completed
+        assert_eq!(
+            r.seen_calls.pop_back().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "exec",
+                "ubuntu-24-04-64-1744396627",
+                "--",
+                "cloud-init",
+                "status",
+                "--wait",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_cli_allocate_cloud_init_status() {
+        for (exit_code, expected) in [
+            (2, Ok(())),
+            (
+                1,
+                Err("cannot allocate system: cloud-init did not complete: exited with status 1, status: error"
+                    .to_string()),
+            ),
+        ] {
+            let mock_results = vec![
+                Ok("".as_bytes().to_vec()),            // lxc launch
+                Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+                Err(LxcRunnerError::Execution {
+                    stderr: "status: error".to_string(),
+                    exit_code,
+                }), // lxc exec cloud-init status
+            ];
+            let r = MockLxcRunner::new(mock_results);
+            let mut a = LxdCliAllocator::new(r);
+            let res = a.allocate(&LxdNodeDetails {
+                image: "ubuntu:24.04",
+                name: "ubuntu-24-04-64-1744396627",
+                cloud_init: &[(
+                    "cloud-init.vendor-data".to_string(),
+                    "#cloud-config\n".to_string(),
+                )],
+                ..Default::default()
+            });
+            assert_eq!(res.map(|_| ()).map_err(|e| e.to_string()), expected);
+        }
+    }
+
+    #[test]
+    fn test_cli_allocate_vm_tpm_csm() {
+        let mock_results = vec![
+            Ok("".as_bytes().to_vec()),            // lxc init
+            Ok("".as_bytes().to_vec()),            // lxc config device add
+            Ok("".as_bytes().to_vec()),            // lxc start
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+        ];
+        let mock_results_len = mock_results.len();
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        a.allocate(&LxdNodeDetails {
+            image: "ubuntu:24.04",
+            name: "ubuntu-core-24-64-1744396627",
+            cpu: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+            root_size: 16 * 1024 * 1024 * 1024,
+            vm: true,
+            tpm: true,
+            firmware: LxdFirmware::Csm,
+            ..Default::default()
+        })
+        .expect("unexpected error");
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(r.seen_calls.len(), mock_results_len);
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "init",
+                "--ephemeral",
+                "--vm",
+                "--config",
+                "limits.memory=8589934592",
+                "--config",
+                "limits.cpu=4",
+                "--config",
+                "security.secureboot=false",
+                "--config",
+                "security.csm=true",
+                "--device",
+                "root,size=17179869184",
+                "ubuntu:24.04",
+                "ubuntu-core-24-64-1744396627",
+            ],
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "config",
+                "device",
+                "add",
+                "ubuntu-core-24-64-1744396627",
+                "vtpm",
+                "tpm",
+            ]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "start",
+                "ubuntu-core-24-64-1744396627",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_allocate_container_tpm() {
+        let mock_results = vec![
+            Ok("".as_bytes().to_vec()),            // lxc init
+            Ok("".as_bytes().to_vec()),            // lxc config device add
+            Ok("".as_bytes().to_vec()),            // lxc start
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+        ];
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        a.allocate(&LxdNodeDetails {
+            image: "ubuntu:24.04",
+            name: "ubuntu-24-04-64-1744396627",
+            cpu: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+            root_size: 16 * 1024 * 1024 * 1024,
+            vm: false,
+            tpm: true,
+            ..Default::default()
+        })
+        .expect("unexpected error");
+
+        // check commands
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "init",
+                "--ephemeral",
+                "--config",
+                "limits.memory=8589934592",
+                "--config",
+                "limits.cpu=4",
+                "--device",
+                "root,size=17179869184",
+                "ubuntu:24.04",
+                "ubuntu-24-04-64-1744396627",
+            ],
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "config",
+                "device",
+                "add",
+                "ubuntu-24-04-64-1744396627",
+                "vtpm",
+                "tpm",
+                "path=/dev/tpm0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_allocate_ssh_forward() {
+        for (vm, connect) in [
+            (false, vec!["connect=tcp:127.0.0.1:22"]),
+            (true, vec!["connect=tcp:10.22.100.75:22", "nat=true"]),
+        ] {
+            let mut mock_results = vec![
+                Ok("".as_bytes().to_vec()),            // lxc launch
+                Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+            ];
+            if vm {
+                mock_results.push(Ok("".as_bytes().to_vec())); // lxc config device override
+            }
+            mock_results.push(Ok("".as_bytes().to_vec())); // lxc config device add
+            let r = MockLxcRunner::new(mock_results);
+            let mut a = LxdCliAllocator::new(r);
+            let node = a
+                .allocate(&LxdNodeDetails {
+                    image: "ubuntu:24.04",
+                    name: "ubuntu-24-04-64-1744396627",
+                    vm,
+                    ssh_forward: Some(LxdSshForward {
+                        address: "192.168.1.10".parse().unwrap(),
+                        port: 20001,
+                    }),
+                    ..Default::default()
+                })
+                .expect("unexpected error");
+            assert_eq!(node.addr, net::IpAddr::from_str("192.168.1.10").unwrap());
+            assert_eq!(node.ssh_port, 20001);
+            assert!(node.phases.iter().any(|(name, _)| name == "ssh-forward"));
+
+            // check commands
+            let mut r = a.test_into_runner();
+            let mut expected = vec![
+                "--project",
+                "spread-adhoc",
+                "config",
+                "device",
+                "add",
+                "ubuntu-24-04-64-1744396627",
+                "ssh-forward",
+                "proxy",
+                "listen=tcp:192.168.1.10:20001",
+            ];
+            expected.extend(connect);
+            assert_eq!(r.seen_calls.pop_back().expect("expected a call"), expected);
+            // the address of a VM is pinned before the proxy connects to it
+            if vm {
+                assert_eq!(
+                    r.seen_calls.pop_back().expect("expected a call"),
+                    vec![
+                        "--project",
+                        "spread-adhoc",
+                        "config",
+                        "device",
+                        "override",
+                        "ubuntu-24-04-64-1744396627",
+                        "eth0",
+                        "ipv4.address=10.22.100.75",
+                    ]
+                );
+            }
+            assert_eq!(r.seen_calls.pop_back().expect("expected a call")[2], "list");
+        }
+    }
+
+    #[test]
+    fn test_cli_allocate_ssh_forward_vm_no_static_address() {
+        let mock_results = vec![
+            Ok("".as_bytes().to_vec()),            // lxc launch
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: Device \"eth0\" doesn't support static addresses".to_string(),
+                exit_code: 1,
+            }), // lxc config device override
+        ];
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        let err = a
+            .allocate(&LxdNodeDetails {
+                image: "ubuntu:24.04",
+                name: "ubuntu-24-04-64-1744396627",
+                vm: true,
+                ssh_forward: Some(LxdSshForward {
+                    address: "192.168.1.10".parse().unwrap(),
+                    port: 20001,
+                }),
+                ..Default::default()
+            })
+            .expect_err("unexpected success");
+        assert!(
+            matches!(&err, LxdError::Allocate(msg) if msg.starts_with(
+                "cannot forward SSH to a VM without a static address, cannot pin address:"
+            )),
+            "{:?}",
+            err
+        );
+
+        // no proxy was added
+        let r = a.test_into_runner();
+        assert!(r.seen_calls.iter().all(|c| !c.contains(&"add".to_string())));
+    }
+
+    #[test]
+    fn test_reserve_port() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-reserve-port-{}",
+            std::process::id()
+        ));
+        let state = StateStore::<LxdState>::new(&dir.join(LXD_STATE_FILE_NAME));
+        state
+            .update(|st| {
+                st.nodes.push(LxdNodeRecord {
+                    name: "ubuntu-24-04-64-1744396627".to_string(),
+                    system: "ubuntu-24.04-64".to_string(),
+                    remote: None,
+                    target: None,
+                    addr: "192.168.1.10".to_string(),
+                    ssh_port: 20000,
+                    allocated_at: None,
+                })
+            })
+            .expect("cannot update state");
+
+        let ports = LxdPortRange {
+            first: 20000,
+            last: 20002,
+        };
+        let r = MockLxcRunner::new(vec![
+            // lxc list, the node of a failed allocation is still around
+            Ok(r#"[{"name": "node-1", "config": {}}]"#.as_bytes().to_vec()),
+            // lxc list, the node is gone
+            Ok("[]".as_bytes().to_vec()),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            reserve_port(&mut a, Some(&state), None, "node-1", &ports),
+            Ok(20001)
+        );
+        assert_eq!(
+            reserve_port(&mut a, Some(&state), None, "node-2", &ports),
+            Ok(20002)
+        );
+        assert_eq!(
+            reserve_port(&mut a, Some(&state), None, "node-3", &ports),
+            Err(LxdError::Capacity(
+                "no free port in range 20000-20002 to forward SSH to".to_string()
+            ))
+        );
+        // reservations of processes which are gone are dropped, unless the
+        // allocation failed and the node may still be listening
+        state
+            .update(|st| {
+                st.ports.iter_mut().for_each(|r| {
+                    r.pid = u32::MAX;
+                    r.failed = r.name == "node-1";
+                })
+            })
+            .expect("cannot update state");
+        assert_eq!(
+            reserve_port(&mut a, Some(&state), None, "node-3", &ports),
+            Ok(20002)
+        );
+        // the node of the failed allocation was deleted
+        state
+            .update(|st| st.ports.retain(|r| r.failed))
+            .expect("cannot update state");
+        assert_eq!(
+            reserve_port(&mut a, Some(&state), None, "node-4", &ports),
+            Ok(20001)
+        );
+
+        let port = reserve_port(&mut a, None, None, "node-5", &ports).expect("unexpected error");
+        assert!((20000..=20002).contains(&port));
+        assert!(a.test_into_runner().outputs.is_empty());
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_admit_reservations() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-admit-{}",
+            std::process::id()
+        ));
+        let state = StateStore::<LxdState>::new(&dir.join(LXD_STATE_FILE_NAME));
+        let budget = LxdBudget {
+            max_memory: None,
+            max_cpu: Some(8),
+            max_nodes: None,
+            queue_timeout: 0,
+        };
+        let r = MockLxcRunner::new(vec![
+            Ok("[]".as_bytes().to_vec()), // lxc list
+            Ok("[]".as_bytes().to_vec()), // lxc list
+            Ok("[]".as_bytes().to_vec()), // lxc list
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        let cpus = |cpu| LxdNodeResources {
+            cpu,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            admit(
+                &mut a,
+                Some(&state),
+                Some(&budget),
+                None,
+                "node-1",
+                1,
+                &cpus(6)
+            ),
+            Ok(())
+        );
+        // requests of one process account for each other
+        assert_eq!(
+            admit(
+                &mut a,
+                Some(&state),
+                Some(&budget),
+                None,
+                "node-2",
+                2,
+                &cpus(4)
+            ),
+            Err(LxdError::Capacity(
+                "CPU limit exceeded, 4 requested with 6 in use of 8, gave up after 0s".to_string()
+            ))
+        );
+        // reservations left behind lapse at their deadline
+        state
+            .update(|st| {
+                assert_eq!(st.reservations.len(), 1);
+                assert_eq!(st.reservations[0].token, 1);
+                st.reservations[0].expires_at = unix_time(SystemTime::now()) - 1;
+            })
+            .expect("cannot update state");
+        assert_eq!(
+            admit(
+                &mut a,
+                Some(&state),
+                Some(&budget),
+                None,
+                "node-2",
+                2,
+                &cpus(4)
+            ),
+            Ok(())
+        );
+        let reservations = state.load().expect("cannot load state").reservations;
+        assert_eq!(reservations.len(), 1);
+        assert_eq!(reservations[0].token, 2);
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    const STANDALONE_SERVER_INFO: &str = r##"{"api_extensions":[],"api_status":"stable","api_version":"1.0","auth":"trusted","environment":{"architectures":["x86_64","i686"],"server_clustered":false,"server_name":"localhost"}}"##;
+    const CLUSTERED_SERVER_INFO: &str = r##"{"api_extensions":[],"api_status":"stable","api_version":"1.0","auth":"trusted","environment":{"architectures":["x86_64","i686"],"server_clustered":true,"server_name":"node1"}}"##;
+    const CLUSTER_MEMBERS: &str = r##"[
+{"server_name":"node1","url":"https://10.0.0.1:8443","roles":["database"],"architecture":"x86_64","failure_domain":"default","description":"","config":{},"groups":["default"],"status":"Online","message":"Fully operational"},
+{"server_name":"node2","url":"https://10.0.0.2:8443","roles":[],"architecture":"aarch64","failure_domain":"default","description":"","config":{},"groups":["default"],"status":"Offline","message":"No heartbeat"},
+{"server_name":"node3","url":"https://10.0.0.3:8443","roles":[],"architecture":"aarch64","failure_domain":"default","description":"","config":{},"groups":["default"],"status":"Online","message":"Fully operational"}
+]"##;
+
+    #[test]
+    fn test_select_target_standalone() {
+        let r = MockLxcRunner::new(vec![Ok(STANDALONE_SERVER_INFO.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            select_target(
+                &mut a,
+                None,
+                Some(LxdArchitecture::Amd64),
+                false,
+                None,
+                &mut |_| 0
+            ),
+            Ok(None)
+        );
+
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["query", "/1.0"]
+        );
+    }
+
+    #[test]
+    fn test_select_target_standalone_unsupported() {
+        let r = MockLxcRunner::new(vec![Ok(STANDALONE_SERVER_INFO.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            select_target(
+                &mut a,
+                None,
+                Some(LxdArchitecture::Arm64),
+                false,
+                None,
+                &mut |_| 0
+            ),
+            Err(LxdError::ConfigInvalid(
+                "architecture aarch64 is not supported by LXD server, supported: x86_64, i686"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_select_target_standalone_secondary() {
+        // aarch64 in place of i686, which the allocator does not know about
+        let info = STANDALONE_SERVER_INFO.replace("\"i686\"", "\"aarch64\"");
+        let r = MockLxcRunner::new(vec![
+            Ok(info.as_bytes().to_vec()),
+            Ok(info.as_bytes().to_vec()),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            select_target(&mut a, None, Some(LxdArchitecture::Arm64), false, None, &mut |_| 0),
+            Err(LxdError::ConfigInvalid(
+                "architecture aarch64 is not the primary one of LXD server, an image per architecture is needed"
+                    .to_string()
+            ))
+        );
+        assert_eq!(
+            select_target(
+                &mut a,
+                None,
+                Some(LxdArchitecture::Arm64),
+                true,
+                None,
+                &mut |_| 0
+            ),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_select_target_cluster() {
+        let r = MockLxcRunner::new(vec![
+            Ok(CLUSTERED_SERVER_INFO.as_bytes().to_vec()),
+            Ok(CLUSTER_MEMBERS.as_bytes().to_vec()),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            select_target(
+                &mut a,
+                None,
+                Some(LxdArchitecture::Arm64),
+                false,
+                None,
+                &mut |_| 0
+            ),
+            Ok(Some("node3".to_string()))
+        );
+
+        let mut r = a.test_into_runner();
+        r.seen_calls.pop_front().expect("expected a call");
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["cluster", "list", "--format=json"]
+        );
+    }
+
+    #[test]
+    fn test_select_target_cluster_unsupported() {
+        let r = MockLxcRunner::new(vec![
+            Ok(CLUSTERED_SERVER_INFO.as_bytes().to_vec()),
+            Ok(CLUSTER_MEMBERS.as_bytes().to_vec()),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            select_target(&mut a, None, Some(LxdArchitecture::S390x), false, None, &mut |_| 0),
+            Err(LxdError::ConfigInvalid(
+                "no online cluster member with architecture s390x, available: node1 (x86_64), node2 (aarch64), node3 (aarch64)"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_select_target_none() {
+        let r = MockLxcRunner::new(vec![]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            select_target(&mut a, None, None, false, None, &mut |_| 0),
+            Ok(None)
+        );
+        assert_eq!(a.test_into_runner().seen_calls.len(), 0);
+    }
+
+    #[test]
+    fn test_select_target_fixed() {
+        let r = MockLxcRunner::new(vec![
+            Ok(CLUSTERED_SERVER_INFO.as_bytes().to_vec()),
+            Ok(CLUSTER_MEMBERS.as_bytes().to_vec()),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        let placement = LxdPlacement {
+            strategy: LxdPlacementStrategy::Fixed,
+            target: Some("node1".to_string()),
+        };
+        assert_eq!(
+            select_target(
+                &mut a,
+                Some("cluster"),
+                None,
+                false,
+                Some(&placement),
+                &mut |_| 0
+            ),
+            Ok(Some("node1".to_string()))
+        );
+
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["query", "cluster:/1.0"]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["cluster", "list", "--format=json", "cluster:"]
+        );
+    }
+
+    #[test]
+    fn test_select_target_fixed_offline() {
+        let r = MockLxcRunner::new(vec![
+            Ok(CLUSTERED_SERVER_INFO.as_bytes().to_vec()),
+            Ok(CLUSTER_MEMBERS.as_bytes().to_vec()),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        let placement = LxdPlacement {
+            strategy: LxdPlacementStrategy::Fixed,
+            target: Some("node2".to_string()),
+        };
+        assert_eq!(
+            select_target(&mut a, None, None, false, Some(&placement), &mut |_| 0),
+            Err(LxdError::ConfigInvalid(
+                "cluster member node2 is not available for placement".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_select_target_round_robin() {
+        let placement = LxdPlacement {
+            strategy: LxdPlacementStrategy::RoundRobin,
+            target: None,
+        };
+        for (counter, expected) in [(0, "node1"), (1, "node3"), (2, "node1")] {
+            let r = MockLxcRunner::new(vec![
+                Ok(CLUSTERED_SERVER_INFO.as_bytes().to_vec()),
+                Ok(CLUSTER_MEMBERS.as_bytes().to_vec()),
+            ]);
+            let mut a = LxdCliAllocator::new(r);
+            assert_eq!(
+                select_target(
+                    &mut a,
+                    None,
+                    None,
+                    false,
+                    Some(&placement),
+                    &mut |candidates| {
+                        // only online members are candidates
+                        assert_eq!(candidates, 2);
+                        counter
+                    }
+                ),
+                Ok(Some(expected.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_select_target_least_allocated() {
+        const CLUSTER_NODES: &str = r##"[
+{"name":"a","location":"node1","config":{"limits.cpu":"4","limits.memory":"8589934592"}},
+{"name":"b","location":"node3","config":{"limits.cpu":"4","limits.memory":"4GiB"}}
+]"##;
+        let r = MockLxcRunner::new(vec![
+            Ok(CLUSTERED_SERVER_INFO.as_bytes().to_vec()),
+            Ok(CLUSTER_MEMBERS.as_bytes().to_vec()),
+            Ok(CLUSTER_NODES.as_bytes().to_vec()),
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        let placement = LxdPlacement {
+            strategy: LxdPlacementStrategy::LeastAllocated,
+            target: None,
+        };
+        assert_eq!(
+            select_target(&mut a, None, None, false, Some(&placement), &mut |_| 0),
+            Ok(Some("node3".to_string()))
+        );
+
+        let mut r = a.test_into_runner();
+        r.seen_calls.pop_front().expect("expected a call");
+        r.seen_calls.pop_front().expect("expected a call");
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec!["--project", "spread-adhoc", "list", "--format=json"]
+        );
+    }
+
+    #[test]
+    fn test_cli_list_allocations() {
+        let r = MockLxcRunner::new(vec![Ok(ONE_NODE_LIST.as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        assert_eq!(
+            a.list_allocations(Some("remote")),
+            Ok(vec![LxdNodeUsage {
+                name: "ubuntu-24-04-64-1744396627".to_string(),
+                location: "none".to_string(),
+                cpu: 4,
+                memory: 4294967296,
+            }])
+        );
+
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "list",
+                "--format=json",
+                "remote:"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_allocate_remote() {
+        let mock_results = vec![
+            Ok("".as_bytes().to_vec()),            // lxc launch
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+            Ok("".as_bytes().to_vec()),            // lxc exec
+        ];
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        a.allocate(&LxdNodeDetails {
+            image: "ubuntu:24.04",
+            name: "ubuntu-24-04-64-1744396627",
+            remote: Some("cluster"),
+            target: Some("node1"),
+            cpu: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+            root_size: 16 * 1024 * 1024 * 1024,
+            vm: true,
+            provision_steps: &[("common".to_string(), LxdSetupStep::run("echo foo"))],
+            ..Default::default()
+        })
+        .expect("unexpected error");
+
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "launch",
+                "--ephemeral",
+                "--vm",
+                "--config",
+                "limits.memory=8589934592",
+                "--config",
+                "limits.cpu=4",
+                "--config",
+                "security.secureboot=false",
+                "--device",
+                "root,size=17179869184",
+                "ubuntu:24.04",
+                "cluster:ubuntu-24-04-64-1744396627",
+                "--target",
+                "node1",
+            ],
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "list",
+                "--format=json",
+                "cluster:ubuntu-24-04-64-1744396627",
+            ]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "exec",
+                "cluster:ubuntu-24-04-64-1744396627",
+                "--",
+                "/bin/bash",
+                "-c",
+                "echo foo",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_discard_by_name_remote() {
+        let r = MockLxcRunner::new(vec![Ok("".as_bytes().to_vec())]);
+        let mut a = LxdCliAllocator::new(r);
+        a.discard_by_name(Some("cluster"), "ubuntu-24-04-64-1744396627")
+            .expect("unexpected error");
+
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "delete",
+                "--force",
+                "cluster:ubuntu-24-04-64-1744396627"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cli_allocate_with_target() {
+        let mock_results = vec![
+            Ok("".as_bytes().to_vec()),            // lxc launch
+            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
+        ];
+        let r = MockLxcRunner::new(mock_results);
+        let mut a = LxdCliAllocator::new(r);
+        a.allocate(&LxdNodeDetails {
+            image: "ubuntu:24.04",
+            name: "ubuntu-24-04-arm64-1744396627",
+            target: Some("node3"),
+            cpu: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+            root_size: 16 * 1024 * 1024 * 1024,
+            vm: true,
+            ..Default::default()
+        })
+        .expect("unexpected error");
+
+        let mut r = a.test_into_runner();
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project",
+                "spread-adhoc",
+                "launch",
+                "--ephemeral",
+                "--vm",
+                "--config",
+                "limits.memory=8589934592",
+                "--config",
+                "limits.cpu=4",
+                "--config",
+                "security.secureboot=false",
+                "--device",
+                "root,size=17179869184",
+                "ubuntu:24.04",
+                "ubuntu-24-04-arm64-1744396627",
+                "--target",
+                "node3",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        for (err, kind) in [
+            (LxdError::ConfigInvalid("bad".to_string()), "config"),
+            (LxdError::Executor("no lxc".to_string()), "backend"),
+            (LxdError::Allocate("failed".to_string()), "backend"),
+            (LxdError::NotFound("no system".to_string()), "not-found"),
+            (LxdError::Capacity("full".to_string()), "capacity"),
+            (LxdError::Timeout("no address".to_string()), "timeout"),
+        ] {
+            let msg = err.to_string();
+            let err = allocator::Error::from(err);
+            assert_eq!(err.kind(), kind);
+            assert_eq!(err.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn test_lxdify() {
+        assert_eq!(lxdfy_name("foo-bar"), "foo-bar");
+        assert_eq!(lxdfy_name("foo.bar"), "foo-bar");
+        assert_eq!(lxdfy_name("foo:bar"), "foo-bar");
+    }
+
+    #[test]
+    fn test_builder_config_empty() {
+        assert!(LxdAllocatorBuilder::new().with_config(io::empty()).is_ok());
+    }
+
+    #[test]
+    fn test_builder_config_valid() {
+        const VALID_CONFIG: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: foo
+    setup-steps: ubuntu-setup-steps
+
+setup:
+  ubuntu-setup-steps:
+    - echo hello
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(VALID_CONFIG.as_bytes())
+            .expect("unexpected error");
+        assert!(b.cfg.system.contains_key("ubuntu-24.04-64"));
+        assert!(b.cfg.setup.contains_key("ubuntu-setup-steps"));
+    }
+
+    #[test]
+    fn test_builder_config_setup_steps() {
+        const CONFIG: &str = r##"
+setup:
+  common:
+    - cloud-init status --wait
+    - run: systemctl restart ssh
+      user: 0
+      env:
+        LANG: C
+      cwd: /root
+      timeout: 60
+    - push: /etc/ssh/sshd_config.d/01-spread.conf
+      content: |
+        PermitRootLogin yes
+      mode: "0644"
+      uid: 0
+      gid: 0
+    - push: /root/.bashrc
+      source: tests/bashrc
+    - pull: /var/log/cloud-init.log
+      destination: cloud-init.log
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        assert_eq!(
+            b.cfg.setup.get("common").expect("no setup"),
+            &vec![
+                LxdSetupStep::run("cloud-init status --wait"),
+                LxdSetupStep::Run {
+                    command: "systemctl restart ssh".to_string(),
+                    user: Some(0),
+                    env: BTreeMap::from([("LANG".to_string(), "C".to_string())]),
+                    cwd: Some("/root".to_string()),
+                    timeout: Some(60),
+                },
+                LxdSetupStep::Push {
+                    path: "/etc/ssh/sshd_config.d/01-spread.conf".to_string(),
+                    source: LxdPushSource::Content("PermitRootLogin yes\n".to_string()),
+                    mode: Some("0644".to_string()),
+                    uid: Some(0),
+                    gid: Some(0),
+                },
+                LxdSetupStep::Push {
+                    path: "/root/.bashrc".to_string(),
+                    source: LxdPushSource::File(PathBuf::from("tests/bashrc")),
+                    mode: None,
+                    uid: None,
+                    gid: None,
+                },
+                LxdSetupStep::Pull {
+                    path: "/var/log/cloud-init.log".to_string(),
+                    destination: PathBuf::from("cloud-init.log"),
+                },
+            ]
+        );
+
+        for step in [
+            "{run: foo, push: /bar}",
+            "{user: 0}",
+            "{run: foo, mode: \"0644\"}",
+            "{push: /foo}",
+            "{push: /foo, source: foo, content: foo}",
+            "{push: /foo, content: foo, mode: rw}",
+            "{push: /foo, content: foo, timeout: 10}",
+            "{pull: /foo}",
+            "{pull: /foo, destination: foo, user: 0}",
+            "{run: foo, unknown: bar}",
+        ] {
+            let config = format!("setup:\n  common:\n    - {}\n", step);
+            assert!(
+                LxdAllocatorBuilder::new()
+                    .with_config(config.as_bytes())
+                    .is_err(),
+                "{}",
+                step
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_template() {
+        let vars = BTreeMap::from([
+            ("system".to_string(), "ubuntu-24.04-64".to_string()),
+            ("pkg".to_string(), "snapd".to_string()),
+        ]);
+        assert_eq!(
+            render_template("apt install -y {{pkg}} # on {{ system }}", &vars),
+            Ok("apt install -y snapd # on ubuntu-24.04-64".to_string())
+        );
+        assert_eq!(
+            render_template("echo ${HOME} {}", &vars),
+            Ok("echo ${HOME} {}".to_string())
+        );
+        assert_eq!(
+            render_template("apt install {{ other }}", &vars),
+            Err("undefined variable \"other\"".to_string())
+        );
+        assert_eq!(
+            render_template("apt install {{ pkg", &vars),
+            Ok("apt install {{ pkg".to_string())
+        );
+        // anything other than a name is kept as is
+        assert_eq!(
+            render_template("docker inspect -f '{{.State.Status}}' {{ pkg }}", &vars),
+            Ok("docker inspect -f '{{.State.Status}}' snapd".to_string())
+        );
+        assert_eq!(
+            render_template("docker ps --format '{{ json . }}'", &vars),
+            Ok("docker ps --format '{{ json . }}'".to_string())
+        );
+        assert_eq!(
+            render_template("jq '{{}}' && echo {{{{ pkg }}}}", &vars),
+            Ok("jq '{{}}' && echo {{snapd}}".to_string())
+        );
+        // escaped names
+        assert_eq!(
+            render_template("helm --set '\\{{ pkg }}' {{pkg}}", &vars),
+            Ok("helm --set '{{ pkg }}' snapd".to_string())
+        );
+        assert_eq!(
+            render_template("{{ if .Ok }}ok\\{{ end }}", &vars),
+            Ok("{{ if .Ok }}ok{{ end }}".to_string())
+        );
+    }
+
+    #[test]
+    fn test_builder_config_setup_vars() {
+        const CONFIG: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    setup-steps:
+      name: install
+      vars:
+        pkg: snapd
+        retries: 3
+  ubuntu-22.04-64:
+    image: ubuntu:22.04
+    setup-steps: plain
+setup:
+  install:
+    - run: apt install -y {{ pkg }} && echo {{ retries }} on {{ system }}
+      env:
+        IMAGE: "{{ image }}"
+    - push: /home/{{ user }}/.config/{{pkg}}
+      content: "{{ cpu }} {{ mem }} {{ size }}"
+    - pull: /var/log/{{ instance }}.log
+      destination: "{{ instance }}.log"
+  plain:
+    - echo {{ system }}
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        assert_eq!(
+            sysconf.setup_steps,
+            vec![LxdSetupRef {
+                name: "install".to_string(),
+                vars: BTreeMap::from([
+                    ("pkg".to_string(), "snapd".to_string()),
+                    ("retries".to_string(), "3".to_string()),
+                ]),
+            }]
+        );
+
+        let builtins = BTreeMap::from([
+            ("system".to_string(), "ubuntu-24.04-64".to_string()),
+            (
+                "instance".to_string(),
+                "ubuntu-24-04-64-1744396627".to_string(),
+            ),
+            ("image".to_string(), "ubuntu:24.04".to_string()),
+            ("user".to_string(), "ubuntu".to_string()),
+            ("cpu".to_string(), "4".to_string()),
+            ("mem".to_string(), "4294967296".to_string()),
+            ("size".to_string(), "16106127360".to_string()),
+        ]);
+        assert_eq!(
+            b.cfg.setup_steps(sysconf, &builtins),
+            Ok(vec![
+                LxdSetupStep::Run {
+                    command: "apt install -y snapd && echo 3 on ubuntu-24.04-64".to_string(),
+                    user: None,
+                    env: BTreeMap::from([("IMAGE".to_string(), "ubuntu:24.04".to_string())]),
+                    cwd: None,
+                    timeout: None,
+                },
+                LxdSetupStep::Push {
+                    path: "/home/ubuntu/.config/snapd".to_string(),
+                    source: LxdPushSource::Content("4 4294967296 16106127360".to_string()),
+                    mode: None,
+                    uid: None,
+                    gid: None,
+                },
+                LxdSetupStep::Pull {
+                    path: "/var/log/ubuntu-24-04-64-1744396627.log".to_string(),
+                    destination: PathBuf::from("ubuntu-24-04-64-1744396627.log"),
+                },
+            ]
+            .into_iter()
+            .map(|step| ("install".to_string(), step))
+            .collect())
+        );
+
+        let sysconf = b.cfg.system.get("ubuntu-22.04-64").expect("no system");
+        assert_eq!(
+            b.cfg.setup_steps(sysconf, &builtins),
+            Ok(vec![(
+                "plain".to_string(),
+                LxdSetupStep::run("echo ubuntu-24.04-64")
+            )])
+        );
+    }
+
+    #[test]
+    fn test_builder_config_dir() {
+        // the configuration is in a directory other than the current one
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-config-dir-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).expect("cannot create directory");
+        std::fs::write(dir.join("user-data.yaml"), "#cloud-config\n").expect("cannot write");
+
+        const CONFIG: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    setup-steps: common
+    cloud-init:
+      user-data:
+        file: user-data.yaml
+setup:
+  common:
+    - push: /root/.bashrc
+      source: tests/bashrc
+    - push: /etc/hosts
+      source: /etc/hosts
+    - pull: /var/log/cloud-init.log
+      destination: "{{ system }}.log"
+"##;
+        let a = LxdAllocatorBuilder::new()
+            .with_config_dir(Some(&dir))
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error")
+            .build();
+        let sysconf = a.conf.system.get("ubuntu-24.04-64").expect("no system");
+        let builtins = BTreeMap::from([("system".to_string(), "ubuntu-24.04-64".to_string())]);
+        assert_eq!(
+            a.conf.setup_steps(sysconf, &builtins),
+            Ok(vec![
+                LxdSetupStep::Push {
+                    path: "/root/.bashrc".to_string(),
+                    source: LxdPushSource::File(dir.join("tests/bashrc")),
+                    mode: None,
+                    uid: None,
+                    gid: None,
+                },
+                LxdSetupStep::Push {
+                    path: "/etc/hosts".to_string(),
+                    source: LxdPushSource::File(PathBuf::from("/etc/hosts")),
+                    mode: None,
+                    uid: None,
+                    gid: None,
+                },
+                LxdSetupStep::Pull {
+                    path: "/var/log/cloud-init.log".to_string(),
+                    destination: dir.join("ubuntu-24.04-64.log"),
+                },
+            ]
+            .into_iter()
+            .map(|step| ("common".to_string(), step))
+            .collect())
+        );
+        assert_eq!(
+            sysconf
+                .cloud_init
+                .as_ref()
+                .expect("no cloud-init")
+                .instance_config(
+                    &allocator::RemoteUserAccessConfig {
+                        user: "root",
+                        password: "password",
+                    },
+                    &|p| a.conf.resolve_path(p)
+                ),
+            Ok(vec![(
+                "cloud-init.user-data".to_string(),
+                "#cloud-config\n".to_string()
+            )])
+        );
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_builder_config_setup_vars_invalid() {
+        const CONFIG_UNDEFINED: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    setup-steps:
+      name: install
+      vars:
+        pkg: snapd
+setup:
+  install:
+    - apt install -y {{ pkg }} {{ other-pkg }}
+"##;
+        let res = LxdAllocatorBuilder::new().with_config(CONFIG_UNDEFINED.as_bytes());
+        assert_eq!(
+            res.err(),
+            Some(LxdError::ConfigInvalid(
+                "system \"ubuntu-24.04-64\" is invalid, setup steps \"install\": undefined variable \"other-pkg\"".to_string()
+            ))
+        );
+
+        for vars in ["{system: foo}", "{pkg: [snapd]}"] {
+            let config = format!(
+                "system:\n  ubuntu-24.04-64:\n    image: ubuntu:24.04\n    setup-steps: {{name: install, vars: {}}}\nsetup:\n  install: []\n",
+                vars
+            );
+            assert!(
+                LxdAllocatorBuilder::new()
+                    .with_config(config.as_bytes())
+                    .is_err(),
+                "{}",
+                vars
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder_config_multiple_setup_sets() {
+        const CONFIG: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    setup-steps:
+      - common
+      - name: snapd
+        vars:
+          channel: edge
+      - enable-debug
+setup:
+  common:
+    - cloud-init status --wait
+  snapd:
+    - snap refresh --{{ channel }} snapd
+  enable-debug:
+    - snap set system debug.snapd=true
+    - journalctl --rotate
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        assert_eq!(
+            b.cfg.setup_steps(sysconf, &BTreeMap::new()),
+            Ok(vec![
+                (
+                    "common".to_string(),
+                    LxdSetupStep::run("cloud-init status --wait")
+                ),
+                (
+                    "snapd".to_string(),
+                    LxdSetupStep::run("snap refresh --edge snapd")
+                ),
+                (
+                    "enable-debug".to_string(),
+                    LxdSetupStep::run("snap set system debug.snapd=true")
+                ),
+                (
+                    "enable-debug".to_string(),
+                    LxdSetupStep::run("journalctl --rotate")
+                ),
+            ])
+        );
+
+        const CONFIG_MISSING: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    setup-steps: [common, missing]
+setup:
+  common: []
+"##;
+        let res = LxdAllocatorBuilder::new().with_config(CONFIG_MISSING.as_bytes());
+        assert_eq!(
+            res.err(),
+            Some(LxdError::ConfigInvalid(
+                "system \"ubuntu-24.04-64\" is invalid, setup steps \"missing\" not found in configuration".to_string()
+            ))
+        );
+
+        // vars of one set are not visible to others
+        const CONFIG_VARS: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    setup-steps: [{name: snapd, vars: {channel: edge}}, other]
+setup:
+  snapd:
+    - snap refresh --{{ channel }} snapd
+  other:
+    - echo {{ channel }}
+"##;
+        let res = LxdAllocatorBuilder::new().with_config(CONFIG_VARS.as_bytes());
+        assert_eq!(
+            res.err(),
+            Some(LxdError::ConfigInvalid(
+                "system \"ubuntu-24.04-64\" is invalid, setup steps \"other\": undefined variable \"channel\"".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_builder_config_cloud_init() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-cloud-init-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).expect("cannot create directory");
+        let network_config = dir.join("network-config.yaml");
+        std::fs::write(&network_config, "version: 2\n").expect("cannot write");
+
+        let config = format!(
+            r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    cloud-init:
+      user-data: |
+        #cloud-config
+        packages: [jq]
+      network-config:
+        file: {}
+      user-access: true
+"##,
+            network_config.display()
+        );
+        let b = LxdAllocatorBuilder::new()
+            .with_config(config.as_bytes())
+            .expect("unexpected error");
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        let cloud_init = sysconf.cloud_init.as_ref().expect("no cloud-init");
+        assert_eq!(
+            cloud_init.instance_config(
+                &allocator::RemoteUserAccessConfig {
+                    user: "root",
+                    password: "pass\"word",
+                },
+                &Path::to_path_buf
+            ),
+            Ok(vec![
+                (
+                    "cloud-init.user-data".to_string(),
+                    "#cloud-config\npackages: [jq]\n".to_string()
+                ),
+                (
+                    "cloud-init.network-config".to_string(),
+                    "version: 2\n".to_string()
+                ),
+                (
+                    "cloud-init.vendor-data".to_string(),
+                    r#"#cloud-config
+ssh_pwauth: true
+disable_root: false
+chpasswd:
+  expire: false
+  users:
+    - name: "root"
+      password: "pass\"word"
+      type: text
+"#
+                    .to_string()
+                ),
+            ])
+        );
+
+        std::fs::remove_file(&network_config).expect("cannot clean up");
+        assert!(cloud_init
+            .instance_config(
+                &allocator::RemoteUserAccessConfig {
+                    user: "root",
+                    password: "password",
+                },
+                &Path::to_path_buf
+            )
+            .is_err());
+
+        const CONFIG_CONFLICT: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    cloud-init:
+      vendor-data: "#cloud-config"
+      user-access: true
+"##;
+        let res = LxdAllocatorBuilder::new().with_config(CONFIG_CONFLICT.as_bytes());
+        assert_eq!(
+            res.err(),
+            Some(LxdError::ConfigInvalid(
+                "system \"ubuntu-24.04-64\" is invalid, cloud-init user access cannot be used with vendor data".to_string()
+            ))
+        );
+
+        std::fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+
+    #[test]
+    fn test_builder_config_diagnostics() {
+        const CONFIG: &str = r##"
+diagnostics:
+  - journalctl -b --no-pager
+  - command: systemctl --failed
+  - file: /var/log/cloud-init.log
+  - file: /var/log/syslog
+    name: syslog
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+  fedora-41-64:
+    image: fedora:41
+    diagnostics:
+      - command: dnf history
+        name: history
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        let diagnostics: Vec<(String, String)> = b
+            .cfg
+            .diagnostics(Some(sysconf))
+            .iter()
+            .map(|d| (d.to_string(), d.name.clone()))
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                (
+                    "command: journalctl -b --no-pager",
+                    "journalctl-b-no-pager.txt"
+                ),
+                ("command: systemctl --failed", "systemctl-failed.txt"),
+                ("file: /var/log/cloud-init.log", "var-log-cloud-init.log"),
+                ("file: /var/log/syslog", "syslog"),
             ]
+            .map(|(d, n)| (d.to_string(), n.to_string()))
+        );
+        // nodes of unknown systems get the defaults
+        assert_eq!(b.cfg.diagnostics(None).len(), 4);
+        // systems override the defaults
+        let sysconf = b.cfg.system.get("fedora-41-64").expect("no system");
+        assert_eq!(
+            b.cfg.diagnostics(Some(sysconf)),
+            [LxdDiagnostic {
+                source: LxdDiagnosticSource::Command("dnf history".to_string()),
+                name: "history".to_string(),
+            }]
+        );
+
+        for invalid in [
+            "diagnostics:\n  - command: ls\n    file: /etc/hosts\n",
+            "diagnostics:\n  - name: foo\n",
+            "diagnostics:\n  - file: var/log/syslog\n",
+            "diagnostics:\n  - file: /var/log/syslog\n    name: ../syslog\n",
+            "diagnostics:\n  - \"|\"\n",
+            "diagnostics:\n  - command: ls\n    user: 1000\n",
+        ] {
+            assert!(
+                matches!(
+                    LxdAllocatorBuilder::new().with_config(invalid.as_bytes()),
+                    Err(LxdError::Config(_))
+                ),
+                "unexpected success for {:?}",
+                invalid
+            );
+        }
+
+        const CONFIG_DUPLICATE: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    diagnostics:
+      - file: /var/log/syslog
+        name: log
+      - command: journalctl -b
+        name: log
+"##;
+        let res = LxdAllocatorBuilder::new().with_config(CONFIG_DUPLICATE.as_bytes());
+        assert_eq!(
+            res.err(),
+            Some(LxdError::ConfigInvalid(
+                "system \"ubuntu-24.04-64\" is invalid, diagnostics with duplicate name \"log\""
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_builder_config_missing_steps() {
+        const INVALID_CONFIG: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: foo
+    setup-steps: steps-not-defined
+"##;
+        assert_eq!(
+            LxdAllocatorBuilder::new().with_config(INVALID_CONFIG.as_bytes()).err().expect("expected an error"),
+            LxdError::ConfigInvalid("system \"ubuntu-24.04-64\" is invalid, setup steps \"steps-not-defined\" not found in configuration".to_string())
+        )
+    }
+
+    #[test]
+    fn test_builder_config_vm_defaults() {
+        const CONFIG: &str = r##"
+system:
+  ubuntu-core-24-64:
+    image: foo
+    tpm: true
+    secure-boot: true
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        let sysconf = b.cfg.system.get("ubuntu-core-24-64").expect("no system");
+        assert!(sysconf.vm);
+        assert!(sysconf.tpm);
+        assert_eq!(sysconf.firmware, LxdFirmware::Uefi);
+    }
+
+    #[test]
+    fn test_builder_config_architecture() {
+        const CONFIG: &str = r##"
+system:
+  ubuntu-24.04-arm64:
+    image: ubuntu:24.04
+    architecture: aarch64
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    architecture: amd64
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        let sysconf = b.cfg.system.get("ubuntu-24.04-arm64").expect("no system");
+        assert_eq!(sysconf.architecture, Some(LxdArchitecture::Arm64));
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        assert_eq!(sysconf.architecture, Some(LxdArchitecture::Amd64));
+        // image aliases are used as given
+        assert_eq!(sysconf.image(), "ubuntu:24.04");
+    }
+
+    #[test]
+    fn test_builder_config_image_per_architecture() {
+        const CONFIG: &str = r##"
+system:
+  ubuntu-24.04-32:
+    image:
+      amd64: ubuntu:24.04
+      armhf: 5f3d2b9a1c8e
+    architecture: armv7l
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        let sysconf = b.cfg.system.get("ubuntu-24.04-32").expect("no system");
+        assert_eq!(sysconf.image(), "5f3d2b9a1c8e");
+
+        for (config, err) in [
+            (
+                "system:\n  foo:\n    image: {amd64: foo}\n",
+                "system \"foo\" is invalid, an image per architecture requires an architecture",
+            ),
+            (
+                "system:\n  foo:\n    image: {amd64: foo}\n    architecture: arm64\n",
+                "system \"foo\" is invalid, no image for architecture aarch64",
+            ),
+        ] {
+            assert_eq!(
+                LxdAllocatorBuilder::new()
+                    .with_config(config.as_bytes())
+                    .err()
+                    .expect("expected an error"),
+                LxdError::ConfigInvalid(err.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder_config_placement() {
+        const CONFIG: &str = r##"
+remote: cluster
+placement:
+  strategy: least-allocated
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    remote: other
+    placement:
+      strategy: fixed
+      target: node1
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        assert_eq!(b.cfg.remote.as_deref(), Some("cluster"));
+        assert_eq!(
+            b.cfg.placement,
+            Some(LxdPlacement {
+                strategy: LxdPlacementStrategy::LeastAllocated,
+                target: None
+            })
+        );
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        assert_eq!(sysconf.remote.as_deref(), Some("other"));
+        assert_eq!(
+            sysconf.placement,
+            Some(LxdPlacement {
+                strategy: LxdPlacementStrategy::Fixed,
+                target: Some("node1".to_string())
+            })
+        );
+    }
+
+    #[test]
+    fn test_builder_config_address_family() {
+        const CONFIG: &str = r##"
+address-family: ipv6
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    address-family: any
+  fedora-41-64:
+    image: fedora:41
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        assert_eq!(b.cfg.address_family, Some(LxdAddressFamily::Ipv6));
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        assert_eq!(sysconf.address_family, Some(LxdAddressFamily::Any));
+        let sysconf = b.cfg.system.get("fedora-41-64").expect("no system");
+        assert_eq!(sysconf.address_family, None);
+
+        assert!(LxdAllocatorBuilder::new()
+            .with_config("address-family: inet6".as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn test_builder_config_ssh_forward() {
+        const CONFIG: &str = r##"
+ssh-forward:
+  address: 192.168.1.10
+  ports: 20000-20999
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    ssh-forward:
+      address: fd42::1
+      ports: 30000-30009
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        assert_eq!(
+            b.cfg.ssh_forward,
+            Some(LxdSshForwardConfig {
+                address: "192.168.1.10".parse().unwrap(),
+                ports: LxdPortRange {
+                    first: 20000,
+                    last: 20999
+                },
+            })
+        );
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        assert_eq!(
+            sysconf.ssh_forward.as_ref().map(|f| f.ports.to_string()),
+            Some("30000-30009".to_string())
+        );
+
+        for config in [
+            "ssh-forward: {address: 192.168.1.10, ports: 20999-20000}",
+            "ssh-forward: {address: 192.168.1.10, ports: 0-10}",
+            "ssh-forward: {address: 192.168.1.10, ports: 20000}",
+            "ssh-forward: {address: 0.0.0.0, ports: 20000-20999}",
+        ] {
+            assert!(
+                LxdAllocatorBuilder::new()
+                    .with_config(config.as_bytes())
+                    .is_err(),
+                "{}",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder_config_network() {
+        const CONFIG: &str = r##"
+network:
+  interface: enp*
+  subnet: 10.22.100.0/24
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    network:
+      interface: eth1
+  fedora-41-64:
+    image: fedora:41
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_config(CONFIG.as_bytes())
+            .expect("unexpected error");
+        let sysconf = b.cfg.system.get("ubuntu-24.04-64").expect("no system");
+        assert_eq!(
+            b.cfg.address_filter(sysconf),
+            LxdAddressFilter {
+                family: LxdAddressFamily::Ipv4,
+                interface: Some("eth1"),
+                subnet: Some("10.22.100.0/24".parse().unwrap()),
+            }
+        );
+        let sysconf = b.cfg.system.get("fedora-41-64").expect("no system");
+        assert_eq!(b.cfg.address_filter(sysconf).interface, Some("enp*"));
+
+        const CONFIG_INVALID_SUBNET: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    network:
+      subnet: 10.22.100.0
+"##;
+        assert!(LxdAllocatorBuilder::new()
+            .with_config(CONFIG_INVALID_SUBNET.as_bytes())
+            .is_err());
+
+        const CONFIG_SUBNET_FAMILY: &str = r##"
+system:
+  ubuntu-24.04-64:
+    image: ubuntu:24.04
+    network:
+      subnet: fd42::/64
+"##;
+        let res = LxdAllocatorBuilder::new().with_config(CONFIG_SUBNET_FAMILY.as_bytes());
+        assert_eq!(
+            res.err().map(|e| e.to_string()),
+            Some(
+                LxdError::ConfigInvalid(
+                    "system \"ubuntu-24.04-64\" is invalid, subnet fd42::/64 does not match IPv4 address family".to_string()
+                )
+                .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_builder_config_placement_fixed_no_target() {
+        assert_eq!(
+            LxdAllocatorBuilder::new()
+                .with_config("placement:\n  strategy: fixed\n".as_bytes())
+                .err()
+                .expect("expected an error"),
+            LxdError::ConfigInvalid("fixed placement requires a target".to_string())
+        );
+    }
+
+    #[test]
+    fn test_builder_config_invalid_firmware() {
+        for (config, err) in [
+            (
+                "system:\n  foo:\n    image: foo\n    vm: false\n    secure-boot: true\n",
+                "system \"foo\" is invalid, secure boot is not supported for containers",
+            ),
+            (
+                "system:\n  foo:\n    image: foo\n    vm: false\n    firmware: csm\n",
+                "system \"foo\" is invalid, firmware selection is not supported for containers",
+            ),
+            (
+                "system:\n  foo:\n    image: foo\n    firmware: csm\n    secure-boot: true\n",
+                "system \"foo\" is invalid, secure boot cannot be used with CSM firmware",
+            ),
+        ] {
+            assert_eq!(
+                LxdAllocatorBuilder::new()
+                    .with_config(config.as_bytes())
+                    .err()
+                    .expect("expected an error"),
+                LxdError::ConfigInvalid(err.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_builder_config_with_trivial_data() {
+        LxdAllocatorBuilder::new()
+            .with_config("system:\nsetup:".as_bytes())
+            .expect("unexpected error");
+    }
+
+    #[test]
+    fn test_builder_user_config_empty() {
+        assert!(LxdAllocatorBuilder::new()
+            .with_optional_user_config::<io::Empty>(None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_builder_user_config_budget() {
+        const CONFIG: &str = r##"
+budget:
+  max-memory: 12GiB
+  max-cpu: 8
+  max-nodes: 3
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_optional_user_config(Some(CONFIG.as_bytes()))
+            .expect("unexpected error");
+        assert_eq!(
+            b.user_cfg.budget,
+            Some(LxdBudget {
+                max_memory: Some(bytesize::ByteSize::gib(12)),
+                max_cpu: Some(8),
+                max_nodes: Some(3),
+                queue_timeout: 600,
+            })
+        );
+    }
+
+    #[test]
+    fn test_budget_check() {
+        let budget = LxdBudget {
+            max_memory: Some(bytesize::ByteSize::gib(12)),
+            max_cpu: Some(8),
+            max_nodes: Some(3),
+            queue_timeout: 0,
+        };
+        let node = |cpu, memory| LxdNodeUsage {
+            name: "foo".to_string(),
+            location: "none".to_string(),
+            cpu,
+            memory,
+        };
+        let gib = 1024 * 1024 * 1024;
+
+        assert_eq!(budget.check(&[], 4, 4 * gib), Ok(()));
+        assert_eq!(budget.check(&[node(2, 4 * gib)], 4, 8 * gib), Ok(()));
+        assert_eq!(
+            budget.check(&[node(2, 4 * gib), node(2, 4 * gib)], 4, 8 * gib),
+            Err(
+                "memory limit exceeded, 8.0 GiB requested with 8.0 GiB in use of 12.0 GiB"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            budget.check(&[node(6, 2 * gib)], 4, 2 * gib),
+            Err("CPU limit exceeded, 4 requested with 6 in use of 8".to_string())
+        );
+        assert_eq!(
+            budget.check(&[node(1, gib), node(1, gib), node(1, gib)], 1, gib),
+            Err("nodes limit exceeded, 3 allocated of 3".to_string())
+        );
+        assert!(budget.fits(8, 12 * gib));
+        assert!(!budget.fits(9, 12 * gib));
+    }
+
+    #[test]
+    fn test_builder_user_config_with_data() {
+        assert!(LxdAllocatorBuilder::new()
+            .with_optional_user_config(Some("user-config:\n".as_bytes()))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_builder_user_config_postmortem() {
+        const CONFIG: &str = r##"
+postmortem:
+  mode: snapshot
+"##;
+        let b = LxdAllocatorBuilder::new()
+            .with_optional_user_config(Some(CONFIG.as_bytes()))
+            .expect("unexpected error");
+        assert_eq!(
+            b.user_cfg.postmortem,
+            LxdPostmortemConfig {
+                mode: LxdPostmortemMode::Snapshot,
+                retain: 3,
+            }
         );
+
+        let b = LxdAllocatorBuilder::new()
+            .with_optional_user_config(Some("postmortem:\n  mode: bogus\n".as_bytes()));
+        assert!(b.is_err());
     }
 
     #[test]
-    fn test_cli_list_nodes_none() {
-        let r = MockLxcRunner::new(vec![Ok("[]".as_bytes().to_vec())]);
-        let mut a = LxdCliAllocator::new(r);
-        let res = a.list_nodes();
-        assert!(res.is_ok());
-        let nodes = res.expect("unexpected error");
-        assert_eq!(nodes.len(), 0);
+    fn test_builder_postmortem_mode_env() {
+        let b = LxdAllocatorBuilder::new()
+            .with_postmortem_mode(Some("export"))
+            .expect("unexpected error");
+        assert_eq!(b.postmortem_mode, Some(LxdPostmortemMode::Export));
 
-        // check commands
-        let mut r = a.test_into_runner();
-        assert_eq!(r.seen_calls.len(), 1);
+        let b = LxdAllocatorBuilder::new()
+            .with_postmortem_mode(None)
+            .expect("unexpected error");
+        assert_eq!(b.postmortem_mode, None);
+
+        let res = LxdAllocatorBuilder::new().with_postmortem_mode(Some("keep"));
         assert_eq!(
-            r.seen_calls.pop_front().expect("expected a call"),
-            vec!["--project", "spread-adhoc", "list", "--format=json",]
+            res.err(),
+            Some(LxdError::ConfigInvalid(
+                "SPREAD_ADHOC_POSTMORTEM: invalid post-mortem mode \"keep\", expected off, snapshot or export"
+                    .to_string()
+            ))
         );
     }
 
     #[test]
-    fn test_cli_list_nodes_some() {
-        let r = MockLxcRunner::new(vec![Ok(ONE_NODE_LIST.as_bytes().to_vec())]);
-        let mut a = LxdCliAllocator::new(r);
-        let res = a.list_nodes();
-        assert!(res.is_ok());
-        let mut nodes = res.expect("unexpected error");
+    fn test_postmortem_name() {
         assert_eq!(
-            nodes.pop().expect("expected an instance"),
-            lxc::types::Instance {
-                name: "ubuntu-24-04-64-1744396627".to_string(),
-                status: "Running".to_string(),
-                state: lxc::types::InstanceState {
-                    network: Some(HashMap::from([
-                        (
-                            "lo".to_string(),
-                            lxc::types::NetworkState {
-                                addresses: vec![
-                                    lxc::types::NetworkAddress {
-                                        family: "inet".to_string(),
-                                        address: "127.0.0.1".to_string(),
-                                    },
-                                    lxc::types::NetworkAddress {
-                                        family: "inet6".to_string(),
-                                        address: "::1".to_string(),
-                                    }
-                                ],
-                            }
-                        ),
-                        (
-                            "enp5s0".to_string(),
-                            lxc::types::NetworkState {
-                                addresses: vec![
-                                    lxc::types::NetworkAddress {
-                                        family: "inet".to_string(),
-                                        address: "10.22.100.75".to_string(),
-                                    },
-                                    lxc::types::NetworkAddress {
-                                        family: "inet6".to_string(),
-                                        address: "fd42:2245:81ae:90da:216:3eff:fe3d:1a76"
-                                            .to_string(),
-                                    },
-                                    lxc::types::NetworkAddress {
-                                        family: "inet6".to_string(),
-                                        address: "fe80::216:3eff:fe3d:1a76".to_string(),
-                                    },
-                                ],
-                            }
-                        ),
-                    ]),),
-                }
-            }
+            postmortem_name("ubuntu-24-04-64-1744396627", "2025-04-11T18:37:07Z"),
+            "ubuntu-24-04-64-1744396627-failed-20250411183707"
         );
-
-        // check commands
-        let mut r = a.test_into_runner();
-        assert_eq!(r.seen_calls.len(), 1);
+        let long = "a".repeat(60);
+        let name = postmortem_name(&long, "2025-04-11T18:37:07Z");
+        assert_eq!(name.len(), LXD_INSTANCE_NAME_MAX_CHARS);
+        assert!(name.ends_with("-failed-20250411183707"));
+        // no double dash where the name was cut
         assert_eq!(
-            r.seen_calls.pop_front().expect("expected a call"),
-            vec!["--project", "spread-adhoc", "list", "--format=json"]
+            postmortem_name(&format!("{}-b", "a".repeat(40)), "2025-04-11T18:37:07Z"),
+            format!("{}-failed-20250411183707", "a".repeat(40))
         );
     }
 
     #[test]
-    fn test_cli_discard_by_addr() {
-        let mock_results = vec![
-            Ok(ONE_NODE_LIST.as_bytes().to_vec()),
-            Ok("".as_bytes().to_vec()),
-        ];
-        let mock_results_len = mock_results.len();
-        let r = MockLxcRunner::new(mock_results);
+    fn test_cli_keep_node_snapshot() {
+        let r = MockLxcRunner::new(vec![
+            Ok("".as_bytes().to_vec()),                    // lxc config set
+            Ok("root\nssh-forward\n".as_bytes().to_vec()), // lxc config device list
+            Ok("".as_bytes().to_vec()),                    // lxc config device remove
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: operation timed out".to_string(),
+                exit_code: 1,
+            }), // lxc stop
+            Ok("".as_bytes().to_vec()),                    // lxc stop --force
+            Ok("".as_bytes().to_vec()),                    // lxc snapshot
+            Ok("".as_bytes().to_vec()),                    // lxc move
+        ]);
         let mut a = LxdCliAllocator::new(r);
-        a.discard_by_addr("10.22.100.75").expect("unexpected error");
+        a.keep_node(
+            None,
+            "ubuntu-24-04-64-1744396627",
+            "ubuntu-24-04-64-1744396627-failed-20250411183707",
+            LxdPostmortemMode::Snapshot,
+            None,
+        )
+        .expect("unexpected error");
 
-        // check commands
         let mut r = a.test_into_runner();
-        assert_eq!(r.seen_calls.len(), mock_results_len);
+        let name = "ubuntu-24-04-64-1744396627";
+        for expected in [
+            vec!["config", "set", name, "ephemeral=false"],
+            vec!["config", "device", "list", name],
+            vec!["config", "device", "remove", name, "ssh-forward"],
+            vec!["stop", "--timeout=30", name],
+            vec!["stop", "--force", name],
+            vec!["snapshot", name, "postmortem"],
+            vec![
+                "move",
+                name,
+                "ubuntu-24-04-64-1744396627-failed-20250411183707",
+                "--target-project",
+                "spread-adhoc-postmortem",
+            ],
+        ] {
+            let mut call = vec!["--project", "spread-adhoc"];
+            call.extend(expected);
+            assert_eq!(r.seen_calls.pop_front().expect("expected a call"), call);
+        }
+        assert!(r.seen_calls.is_empty());
+    }
+
+    #[test]
+    fn test_cli_keep_node_export() {
+        let path = std::env::temp_dir()
+            .join(format!(
+                "spread-adhoc-allocator-test-export-{}",
+                std::process::id()
+            ))
+            .join("foo-failed-20250411183707.tar.gz");
+        let r = MockLxcRunner::new(vec![
+            Ok("".as_bytes().to_vec()),       // lxc config set
+            Ok("root\n".as_bytes().to_vec()), // lxc config device list
+            Ok("".as_bytes().to_vec()),       // lxc stop
+            Ok("".as_bytes().to_vec()),       // lxc export
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: project not found".to_string(),
+                exit_code: 1,
+            }), // lxc move
+        ]);
+        let mut a = LxdCliAllocator::new(r);
+        let res = a.keep_node(
+            Some("cluster"),
+            "foo",
+            "foo-failed-20250411183707",
+            LxdPostmortemMode::Export,
+            Some(&path),
+        );
         assert_eq!(
-            r.seen_calls.pop_front().expect("expected a call"),
-            vec!["--project", "spread-adhoc", "list", "--format=json"]
+            res,
+            Err(LxdError::Discard(
+                "cannot keep node for post-mortem: lxc command exited with status 1, stderr:\nError: project not found"
+                    .to_string()
+            ))
         );
+        assert!(path.parent().expect("no parent").is_dir());
+
+        let mut r = a.test_into_runner();
+        r.seen_calls.pop_front().expect("expected a call");
+        r.seen_calls.pop_front().expect("expected a call");
         assert_eq!(
             r.seen_calls.pop_front().expect("expected a call"),
             vec![
                 "--project",
                 "spread-adhoc",
-                "delete",
-                "--force",
-                "ubuntu-24-04-64-1744396627"
+                "stop",
+                "--timeout=30",
+                "cluster:foo"
+            ]
+        );
+        assert_eq!(
+            r.seen_calls.pop_front().expect("expected a call"),
+            vec![
+                "--project".to_string(),
+                "spread-adhoc".to_string(),
+                "export".to_string(),
+                "cluster:foo".to_string(),
+                path.to_string_lossy().to_string(),
             ]
         );
     }
 
     #[test]
-    fn test_cli_discard_by_name() {
-        let mock_results = vec![Ok("".as_bytes().to_vec())];
-        let mock_results_len = mock_results.len();
-        let r = MockLxcRunner::new(mock_results);
+    fn test_cli_delete_kept_node() {
+        let r = MockLxcRunner::new(vec![
+            Ok("".as_bytes().to_vec()), // lxc delete
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: Instance not found".to_string(),
+                exit_code: 1,
+            }), // lxc delete
+        ]);
         let mut a = LxdCliAllocator::new(r);
-        a.discard_by_name("ubuntu-24-04-64-1744396627")
+        a.delete_kept_node(None, "foo-failed-20250411183707")
             .expect("unexpected error");
+        assert_eq!(
+            a.delete_kept_node(Some("cluster"), "bar-failed-20250411183707"),
+            Err(LxdError::NotFound(
+                "node cluster:bar-failed-20250411183707 not found".to_string()
+            ))
+        );
 
-        // check commands
         let mut r = a.test_into_runner();
-        assert_eq!(r.seen_calls.len(), mock_results_len);
         assert_eq!(
             r.seen_calls.pop_front().expect("expected a call"),
             vec![
                 "--project",
-                "spread-adhoc",
+                "spread-adhoc-postmortem",
                 "delete",
                 "--force",
-                "ubuntu-24-04-64-1744396627"
+                "foo-failed-20250411183707"
             ]
         );
     }
 
     #[test]
-    fn test_cli_allocate() {
-        let mock_results = vec![
-            Ok("".as_bytes().to_vec()),            // lxc launch
-            Ok(ONE_NODE_LIST.as_bytes().to_vec()), // lxc list
-            Ok("".as_bytes().to_vec()),            // lxc exec
-        ];
-        let mock_results_len = mock_results.len();
-        let r = MockLxcRunner::new(mock_results);
+    fn test_cli_shell_kept_node() {
+        let r = MockLxcRunner::new(vec![
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: The instance is already running".to_string(),
+                exit_code: 1,
+            }), // lxc start
+            Ok("".as_bytes().to_vec()), // lxc exec
+            Err(LxcRunnerError::Execution {
+                stderr: "Error: Instance not found".to_string(),
+                exit_code: 1,
+            }), // lxc start
+        ]);
         let mut a = LxdCliAllocator::new(r);
-        let res = a.allocate(&LxdNodeDetails {
-            image: "ubuntu:24.04",
-            name: "ubuntu-24-04-64-1744396627",
-            cpu: 4,
-            memory: 8 * 1024 * 1024 * 1024,
-            root_size: 16 * 1024 * 1024 * 1024,
-            secure_boot: false,
-            provision_steps: &vec!["echo foo".to_string()],
-        });
+        a.shell_kept_node(None, "foo-failed-20250411183707")
+            .expect("unexpected error");
         assert_eq!(
-            res,
-            Ok(LxdNodeAllocation {
-                name: "ubuntu-24-04-64-1744396627".to_string(),
-                addr: net::Ipv4Addr::from_str("10.22.100.75").unwrap(),
-                ssh_port: 22,
-            })
+            a.shell_kept_node(None, "bar-failed-20250411183707"),
+            Err(LxdError::NotFound(
+                "node bar-failed-20250411183707 not found".to_string()
+            ))
         );
 
-        // check commands
         let mut r = a.test_into_runner();
-        assert_eq!(r.seen_calls.len(), mock_results_len);
-        assert_eq!(
-            r.seen_calls.pop_front().expect("expected a call"),
-            vec![
-                "--project",
-                "spread-adhoc",
-                "launch",
-                "--ephemeral",
-                "--vm",
-                "--config",
-                "limits.memory=8589934592",
-                "--config",
-                "limits.cpu=4",
-                "--config",
-                "security.secureboot=false",
-                "--device",
-                "root,size=17179869184",
-                "ubuntu:24.04",
-                "ubuntu-24-04-64-1744396627",
-            ],
-        );
-        assert_eq!(
-            r.seen_calls.pop_front().expect("expected a call"),
-            vec![
-                "--project",
-                "spread-adhoc",
-                "list",
-                "--format=json",
-                "ubuntu-24-04-64-1744396627",
-            ]
-        );
+        r.seen_calls.pop_front().expect("expected a call");
         assert_eq!(
             r.seen_calls.pop_front().expect("expected a call"),
             vec![
                 "--project",
-                "spread-adhoc",
+                "spread-adhoc-postmortem",
                 "exec",
-                "ubuntu-24-04-64-1744396627",
+                "foo-failed-20250411183707",
                 "--",
-                "/bin/bash",
-                "-c",
-                "echo foo",
+                "su",
+                "-l"
             ]
         );
     }
-
-    #[test]
-    fn test_lxdify() {
-        assert_eq!(lxdfy_name("foo-bar"), "foo-bar");
-        assert_eq!(lxdfy_name("foo.bar"), "foo-bar");
-        assert_eq!(lxdfy_name("foo:bar"), "foo-bar");
-    }
-
-    #[test]
-    fn test_builder_config_empty() {
-        assert!(LxdAllocatorBuilder::new().with_config(io::empty()).is_ok());
-    }
-
-    #[test]
-    fn test_builder_config_valid() {
-        const VALID_CONFIG: &str = r##"
-system:
-  ubuntu-24.04-64:
-    image: foo
-    setup-steps: ubuntu-setup-steps
-
-setup:
-  ubuntu-setup-steps:
-    - echo hello
-"##;
-        let b = LxdAllocatorBuilder::new()
-            .with_config(VALID_CONFIG.as_bytes())
-            .expect("unexpected error");
-        assert!(b.cfg.system.get("ubuntu-24.04-64").is_some());
-        assert!(b.cfg.setup.get("ubuntu-setup-steps").is_some());
-    }
-
-    #[test]
-    fn test_builder_config_missing_steps() {
-        const INVALID_CONFIG: &str = r##"
-system:
-  ubuntu-24.04-64:
-    image: foo
-    setup-steps: steps-not-defined
-"##;
-        assert_eq!(
-            LxdAllocatorBuilder::new().with_config(INVALID_CONFIG.as_bytes()).err().expect("expected an error"),
-            LxdError::ConfigInvalid("system \"ubuntu-24.04-64\" is invalid, setup steps \"steps-not-defined\" not found in configuration".to_string())
-        )
-    }
-
-    #[test]
-    fn test_builder_config_with_trivial_data() {
-        LxdAllocatorBuilder::new()
-            .with_config("system:\nsetup:".as_bytes())
-            .expect("unexpected error");
-    }
-
-    #[test]
-    fn test_builder_user_config_empty() {
-        assert!(LxdAllocatorBuilder::new()
-            .with_optional_user_config::<io::Empty>(None)
-            .is_ok());
-    }
-
-    #[test]
-    fn test_builder_user_config_with_data() {
-        assert!(LxdAllocatorBuilder::new()
-            .with_optional_user_config(Some("user-config:\n".as_bytes()))
-            .is_ok());
-    }
 }
@@ -3,17 +3,23 @@
 // SPDX-License-Identifier: MIT
 
 use std::fs::File;
-use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fs, io, net, thread};
 
 use anyhow::Context;
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand, ValueEnum};
-use log;
-use simple_logger;
 
 mod allocator;
+mod audit;
 mod config;
+mod daemon;
+mod lock;
+mod logging;
 mod lxd;
+mod metrics;
+mod state;
 
 const BUILD_GIT_VERSION: &str = env!["BUILD_GIT_VERSION"];
 const VERSION: &str = env!["CARGO_PKG_VERSION"];
@@ -23,12 +29,40 @@ enum Backend {
     Lxd,
 }
 
+/// Format of command output.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+enum OutputFormat {
+    /// Plain text, addresses in form of <ipv4>:<ssh-port> or
+    /// [<ipv6>]:<ssh-port>, as expected by spread.
+    Plain,
+    /// JSON.
+    Json,
+}
+
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES_HELP)]
 struct Cli {
     #[arg(value_enum, long, short, default_value_t = Backend::Lxd)]
     backend: Backend,
 
+    /// Increase logging verbosity, can be repeated. The level can also be set
+    /// with SPREAD_ADHOC_LOG environment variable.
+    #[arg(long, short, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only log errors.
+    #[arg(long, short, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Write a detailed log to a file.
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Socket of the allocator daemon, by default in the state directory.
+    /// Requests are forwarded to the daemon when it is running.
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -43,25 +77,79 @@ enum Command {
         user: String,
         /// Password for remote access.
         password: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+        output: OutputFormat,
     },
     /// Discard a system.
     Discard {
-        /// Addess, in form of <ip>:<ssh-port>, of a node to discard.
-        addr_port: String,
+        /// Node to discard, given as an instance name, <ip>, <ipv4>:<ssh-port>
+        /// or [<ipv6>]:<ssh-port>.
+        #[arg(required_unless_present = "name", conflicts_with = "name")]
+        node: Option<allocator::NodeRef>,
+        /// Name of the instance to discard, optionally qualified with a remote.
+        #[arg(long)]
+        name: Option<String>,
+        /// Collect diagnostics from the node before discarding it.
+        #[arg(long)]
+        collect: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+        output: OutputFormat,
     },
     /// Discard all allocated systems.
     Cleanup,
+    /// List allocated systems.
+    List {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+        output: OutputFormat,
+    },
+    /// Show percentiles of historical allocation times per system.
+    Stats,
+    /// Inspect nodes kept for post-mortem.
+    Postmortem {
+        #[command(subcommand)]
+        command: PostmortemCommand,
+    },
+    /// Run as a daemon handling requests over a unix socket, and exposing
+    /// metrics over HTTP.
+    Serve {
+        /// Address to serve metrics on, at /metrics.
+        #[arg(long, default_value = "127.0.0.1:9464")]
+        metrics_addr: String,
+        /// Maximum number of allocations handled at once, further requests
+        /// are queued in order of arrival.
+        #[arg(long)]
+        max_concurrent: Option<usize>,
+    },
     /// Show version information.
     Version,
 }
 
-fn mandatory_config(name: &str) -> Result<File> {
-    let cfg_path = config::locate(lxd::config_file_name())
-        .with_context(|| format!("cannot find config file {}", name))?;
-
-    log::debug!("loading config from {}", cfg_path.to_string_lossy());
+#[derive(Subcommand)]
+enum PostmortemCommand {
+    /// List nodes kept for post-mortem, oldest first.
+    List {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+        output: OutputFormat,
+    },
+    /// Open a shell in a kept node, starting it if needed.
+    Shell {
+        /// Name of the kept instance, optionally qualified with a remote.
+        name: String,
+    },
+    /// Delete a kept node.
+    Delete {
+        /// Name of the kept instance, optionally qualified with a remote.
+        name: String,
+    },
+}
 
-    fs::File::open(cfg_path).context("cannot open config file")
+/// Locates the backend configuration file.
+fn config_path(backend: &Backend) -> Result<PathBuf> {
+    match backend {
+        Backend::Lxd => config::locate(lxd::config_file_name())
+            .map_err(|err| allocator::Error::Config(err.to_string()))
+            .with_context(|| format!("cannot find config file {}", lxd::config_file_name())),
+    }
 }
 
 fn optional_config() -> Result<Option<File>> {
@@ -82,7 +170,8 @@ fn optional_config() -> Result<Option<File>> {
                     );
                     Ok(None)
                 }
-                _ => return Err(err).context("cannot open user config file"),
+                _ => Err(allocator::Error::Config(err.to_string()))
+                    .context("cannot open user config file"),
             },
         }
     } else {
@@ -90,81 +179,412 @@ fn optional_config() -> Result<Option<File>> {
     }
 }
 
+/// Returns an allocator for a backend, with the path and contents of the
+/// backend configuration file, optionally updating metrics with its events.
 fn initialize_backend(
     backend: &Backend,
-    command: Option<&Command>,
-) -> Result<Box<dyn allocator::NodeAllocator>> {
+    config: Option<(&Path, &[u8])>,
+    postmortem: Option<&str>,
+    metrics: Option<&Arc<Mutex<metrics::Metrics>>>,
+) -> Result<Box<dyn allocator::NodeAllocator + Send>> {
     match backend {
-        Backend::Lxd => {
-            let mut builder = lxd::LxdAllocatorBuilder::new();
-
-            if match command {
-                // only allocate needs full configuration
-                Some(Command::Allocate { .. }) => true,
-                _ => false,
-            } {
-                builder = builder
-                    .with_config(mandatory_config(lxd::config_file_name())?)
-                    .context("cannot apply configuration")?;
-            }
+        Backend::Lxd => Ok(Box::new(lxd_allocator(config, postmortem, metrics)?)),
+    }
+}
+
+/// Returns the post-mortem mode set in the environment.
+fn postmortem_mode() -> Option<String> {
+    std::env::var(lxd::POSTMORTEM_ENV).ok()
+}
+
+/// Returns an LXD allocator, with a post-mortem mode overriding the one set
+/// in the environment.
+fn lxd_allocator(
+    config: Option<(&Path, &[u8])>,
+    postmortem: Option<&str>,
+    metrics: Option<&Arc<Mutex<metrics::Metrics>>>,
+) -> Result<lxd::LxdAllocator> {
+    let mut builder = lxd::LxdAllocatorBuilder::new();
+
+    if let Some((path, config)) = config {
+        builder = builder
+            .with_config_dir(path.parent())
+            .with_config(config)
+            .map_err(allocator::Error::from)
+            .context("cannot apply configuration")?;
+    }
+
+    let state_dir = config::state_dir();
+    let mut events: Box<dyn audit::EventSink> = match state_dir.as_ref() {
+        Some(dir) => Box::new(audit::JsonlSink::new(&dir.join(audit::AUDIT_LOG_FILE_NAME))),
+        None => Box::new(audit::NullSink),
+    };
+    if let Some(metrics) = metrics {
+        events = Box::new(metrics::MetricsSink::new(metrics.clone(), events));
+    }
+    builder = builder.with_event_sink(events);
+
+    Ok(builder
+        .with_optional_user_config(optional_config()?)
+        .map_err(allocator::Error::from)
+        .context("cannot apply user configuration")?
+        .with_postmortem_mode(
+            postmortem
+                .map(str::to_string)
+                .or_else(postmortem_mode)
+                .as_deref(),
+        )
+        .map_err(allocator::Error::from)
+        .context("cannot apply environment")?
+        .with_state_dir(state_dir)
+        .build())
+}
+
+/// Converts an error to an allocator error of the same kind, keeping the
+/// whole context in the message.
+fn into_allocator_error(err: anyhow::Error) -> allocator::Error {
+    let msg = format!("{:#}", err);
+    match allocator_error(&err) {
+        Some(allocator::Error::Config(_)) => allocator::Error::Config(msg),
+        Some(allocator::Error::Backend(_)) => allocator::Error::Backend(msg),
+        Some(allocator::Error::Timeout(_)) => allocator::Error::Timeout(msg),
+        Some(allocator::Error::Capacity(_)) => allocator::Error::Capacity(msg),
+        Some(allocator::Error::NotFound(_)) => allocator::Error::NotFound(msg),
+        Some(allocator::Error::Operation(_)) | None => allocator::Error::Operation(msg),
+    }
+}
+
+/// Returns the allocator error which caused a given error, if any.
+fn allocator_error(err: &anyhow::Error) -> Option<&allocator::Error> {
+    err.chain()
+        .find_map(|e| e.downcast_ref::<allocator::Error>())
+}
+
+/// Exit codes, see EXIT_CODES_HELP.
+fn exit_code(err: &anyhow::Error) -> u8 {
+    match allocator_error(err) {
+        Some(allocator::Error::Config(_)) => 3,
+        Some(allocator::Error::NotFound(_)) => 4,
+        Some(allocator::Error::Backend(_)) => 5,
+        Some(allocator::Error::Timeout(_)) => 6,
+        Some(allocator::Error::Capacity(_)) => 7,
+        Some(allocator::Error::Operation(_)) | None => 1,
+    }
+}
 
-            let b = builder
-                .with_optional_user_config(optional_config()?)
-                .context("cannot apply user configuration")?
-                .build();
-            Ok(Box::new(b))
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  other error
+  2  invalid command line
+  3  configuration is missing or invalid
+  4  system or node not found
+  5  backend is unreachable or failed
+  6  operation timed out
+  7  resources are not available";
+
+fn socket_path(cli: &Cli) -> Option<PathBuf> {
+    cli.socket
+        .clone()
+        .or_else(|| config::state_dir().map(|d| d.join(daemon::SOCKET_FILE_NAME)))
+}
+
+/// Returns an allocator for a command, forwarding requests to the daemon when
+/// it is running.
+fn allocator_for(cli: &Cli, command: &Command) -> Result<Box<dyn allocator::NodeAllocator>> {
+    // only allocate and collecting diagnostics need full configuration
+    let config = match command {
+        Command::Allocate { .. } | Command::Discard { collect: true, .. } => {
+            Some(config_path(&cli.backend)?)
         }
-        _ => return Err(anyhow!("backend {:?} is not supported yet", backend)),
+        _ => None,
+    };
+
+    if let Some(client) = socket_path(cli)
+        .as_deref()
+        .and_then(daemon::Client::connect)
+    {
+        log::debug!("forwarding request to daemon");
+        return Ok(Box::new(
+            client
+                .with_config(config)
+                .with_postmortem_mode(postmortem_mode()),
+        ));
     }
+
+    let config = config
+        .map(|path| {
+            log::debug!("loading config from {}", path.display());
+            fs::read(&path)
+                .map(|data| (path, data))
+                .map_err(|err| allocator::Error::Config(err.to_string()))
+                .context("cannot read config file")
+        })
+        .transpose()?;
+    Ok(initialize_backend(
+        &cli.backend,
+        config.as_ref().map(|(p, c)| (p.as_path(), c.as_slice())),
+        None,
+        None,
+    )?)
 }
 
-fn try_main() -> Result<()> {
-    simple_logger::init_with_level(log::Level::Trace).unwrap();
+fn audit_log_path() -> Result<PathBuf> {
+    Ok(config::state_dir()
+        .ok_or_else(|| anyhow!("cannot determine state directory"))?
+        .join(audit::AUDIT_LOG_FILE_NAME))
+}
+
+/// Result of discarding a node.
+#[derive(serde::Serialize)]
+struct DiscardOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    addr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Details of the node, if it was known to the allocator.
+    node: Option<allocator::Node>,
+    /// Directory with diagnostics collected from the node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifacts: Option<PathBuf>,
+}
+
+fn print_json<T: serde::Serialize>(v: &T) -> Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(v).context("cannot serialize output")?
+    );
+    Ok(())
+}
+
+fn print_stats(stats: &[audit::SystemStats]) {
+    let secs = |ms: u64| format!("{:.1}s", ms as f64 / 1000.0);
+    for (i, st) in stats.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!(
+            "{}: {} allocations, {} failed",
+            st.system, st.allocations, st.failures
+        );
+        println!(
+            "  {:<20} {:>9} {:>9} {:>9} {:>9}",
+            "PHASE", "P50", "P90", "P99", "MAX"
+        );
+        for (name, p) in std::iter::once(("total", &st.total))
+            .chain(st.phases.iter().map(|(name, p)| (name.as_str(), p)))
+        {
+            println!(
+                "  {:<20} {:>9} {:>9} {:>9} {:>9}",
+                name,
+                secs(p.p50),
+                secs(p.p90),
+                secs(p.p99),
+                secs(p.max)
+            );
+        }
+    }
+}
 
+fn log_level(cli: &Cli) -> log::LevelFilter {
+    match (cli.quiet, cli.verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, 0) => logging::level_from_env().unwrap_or(log::LevelFilter::Warn),
+        (false, 1) => log::LevelFilter::Info,
+        (false, 2) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    }
+}
+
+fn try_main() -> Result<()> {
     let cli = Cli::parse();
 
-    let mut b = initialize_backend(&cli.backend, cli.command.as_ref())?;
+    logging::init(log_level(&cli), cli.log_file.as_deref()).context("cannot set up logging")?;
 
-    match cli.command {
-        Some(Command::Allocate {
+    let Some(command) = cli.command.as_ref() else {
+        return Err(anyhow!("no command provided, see --help"));
+    };
+
+    match command {
+        Command::Allocate {
             name: sysname,
             user,
             password,
-        }) => {
-            let res = b
+            output,
+        } => {
+            let instance = allocator_for(&cli, command)?
                 .allocate_by_name(
-                    &sysname,
-                    allocator::RemoteUserAccessConfig {
-                        user: &user,
-                        password: &password,
-                    },
+                    sysname,
+                    allocator::RemoteUserAccessConfig { user, password },
                 )
-                .context("cannot allocate");
-            match res {
-                Ok(instance) => {
-                    println!("{}:{}", instance.addr, instance.ssh_port);
-                    Ok(())
+                .context("cannot allocate")?;
+            match output {
+                OutputFormat::Plain => println!("{}", instance.ssh_address()),
+                OutputFormat::Json => print_json(&instance)?,
+            }
+            Ok(())
+        }
+        Command::Discard {
+            node,
+            name,
+            collect,
+            output,
+        } => {
+            let node_ref = match (node, name) {
+                (_, Some(name)) => allocator::NodeRef::Name(name.clone()),
+                (Some(node), None) => node.clone(),
+                (None, None) => return Err(anyhow!("no node to discard")),
+            };
+
+            let mut b = allocator_for(&cli, command)?;
+            // the node is discarded even if diagnostics cannot be collected
+            let artifacts = if *collect {
+                b.collect_diagnostics(&node_ref)
+                    .inspect(|dir| eprintln!("diagnostics collected in {}", dir.display()))
+                    .inspect_err(|err| log::warn!("cannot collect diagnostics: {}", err))
+                    .ok()
+            } else {
+                None
+            };
+            let (addr, name, node) = match node_ref {
+                allocator::NodeRef::Addr(..) => {
+                    let addr = node_ref.to_string();
+                    let node = b
+                        .discard_by_addr(&addr)
+                        .with_context(|| format!("cannot discard system with address {}", addr))?;
+                    (Some(addr), None, node)
+                }
+                allocator::NodeRef::Name(name) => {
+                    let node = b
+                        .discard_by_name(&name)
+                        .with_context(|| format!("cannot discard system {}", name))?;
+                    (None, Some(name), node)
                 }
-                Err(err) => Err(err),
+            };
+            if *output == OutputFormat::Json {
+                print_json(&DiscardOutput {
+                    addr,
+                    name,
+                    node,
+                    artifacts,
+                })?;
             }
+            Ok(())
         }
-        Some(Command::Discard { addr_port }) => {
-            let sp: Vec<&str> = addr_port.split(":").collect();
-            if sp.len() != 2 {
-                return Err(anyhow!("invalid address, expected <addr>:<port>"));
+        Command::Cleanup => allocator_for(&cli, command)?
+            .discard_all()
+            .context("cannot cleanup all nodes"),
+        Command::List { output } => {
+            let nodes = allocator_for(&cli, command)?
+                .list()
+                .context("cannot list nodes")?;
+            if *output == OutputFormat::Json {
+                return print_json(&nodes);
             }
+            for info in nodes {
+                let (system, addr) = match info.node {
+                    Some(n) => (n.system.clone(), n.ssh_address()),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                println!("{}\t{}\t{}", info.name, system, addr);
+            }
+            Ok(())
+        }
+        // kept nodes are handled locally, shell needs the terminal
+        Command::Postmortem { command } => {
+            let mut b = match cli.backend {
+                Backend::Lxd => lxd_allocator(None, None, None)?,
+            };
+            match command {
+                PostmortemCommand::List { output } => {
+                    let nodes = b.postmortem_list();
+                    if *output == OutputFormat::Json {
+                        return print_json(&nodes);
+                    }
+                    for n in nodes {
+                        println!(
+                            "{}\t{}\t{}\t{}",
+                            n.name, n.system, n.instance, n.discarded_at
+                        );
+                    }
+                    Ok(())
+                }
+                PostmortemCommand::Shell { name } => b
+                    .postmortem_shell(name)
+                    .with_context(|| format!("cannot open shell in {}", name)),
+                PostmortemCommand::Delete { name } => b
+                    .postmortem_delete(name)
+                    .with_context(|| format!("cannot delete {}", name)),
+            }
+        }
+        Command::Stats => {
+            let path = audit_log_path()?;
+            let events = audit::read_events(&path)
+                .with_context(|| format!("cannot read audit log {}", path.display()))?;
+            print_stats(&audit::allocation_stats(&events));
+            Ok(())
+        }
+        Command::Serve {
+            metrics_addr,
+            max_concurrent,
+        } => {
+            let socket =
+                socket_path(&cli).ok_or_else(|| anyhow!("cannot determine socket path"))?;
+            let listener = daemon::bind(&socket)
+                .with_context(|| format!("cannot listen on {}", socket.display()))?;
+            let metrics_listener = net::TcpListener::bind(metrics_addr)
+                .with_context(|| format!("cannot listen on {}", metrics_addr))?;
+            let path = audit_log_path()?;
+            let backend = cli.backend;
 
-            let addr = sp.get(0).unwrap();
+            // counters start from the history in the audit log, and are then
+            // updated with events of the allocators of the daemon
+            let metrics = {
+                let events = audit::read_events(&path).unwrap_or_else(|err| {
+                    log::warn!("cannot read audit log {}: {}", path.display(), err);
+                    vec![]
+                });
+                Arc::new(Mutex::new(metrics::Metrics::new(&events, &[])))
+            };
+
+            log::info!("serving metrics on http://{}/metrics", metrics_addr);
+            let served = metrics.clone();
+            thread::spawn(move || {
+                let res = initialize_backend(&backend, None, None, None).and_then(|mut b| {
+                    metrics::serve(metrics_listener, || {
+                        let nodes = b.list().unwrap_or_else(|err| {
+                            log::warn!("cannot list nodes: {}", err);
+                            vec![]
+                        });
+                        let mut metrics = served.lock().unwrap_or_else(|e| e.into_inner());
+                        metrics.set_nodes(&nodes);
+                        metrics.render()
+                    })
+                    .context("cannot serve metrics")
+                });
+                if let Err(err) = res {
+                    log::error!("{:#}", err);
+                }
+            });
 
-            b.discard_by_addr(&addr)
-                .with_context(|| format!("cannot discard system with address {}", addr))
+            log::info!("serving requests on {}", socket.display());
+            // allocators pick up the user configuration when created
+            let watched = config::user_config().into_iter().collect();
+            daemon::serve(
+                listener,
+                *max_concurrent,
+                watched,
+                move |config, postmortem| {
+                    initialize_backend(&backend, config, postmortem, Some(&metrics))
+                        .map_err(into_allocator_error)
+                },
+            )
+            .context("cannot serve requests")
         }
-        Some(Command::Cleanup) => b.discard_all().context("cannot cleanup all nodes"),
-        Some(Command::Version) => {
+        Command::Version => {
             println!("{} (git {})", VERSION, BUILD_GIT_VERSION);
             Ok(())
         }
-        None => Err(anyhow!("no command provided, see --help")),
     }
 }
 
@@ -172,8 +592,16 @@ use std::process;
 
 fn main() -> process::ExitCode {
     if let Err(err) = try_main() {
-        println!("{:#}", err);
-        process::ExitCode::FAILURE
+        // stdout carries the output captured by spread, while the error is
+        // passed from stderr to FATAL, which takes a single line
+        let msg = format!("{:#}", err);
+        let lines: Vec<&str> = msg
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        eprintln!("{}", lines.join(" "));
+        process::ExitCode::from(exit_code(&err))
     } else {
         process::ExitCode::SUCCESS
     }
@@ -0,0 +1,469 @@
+// SPDX-FileCopyrightText: 2025 Maciej Borzecki <maciek.borzecki@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use crate::allocator;
+use crate::audit::{Event, EventSink, Operation, Outcome};
+
+/// Upper bounds of allocation latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: &[u64] = &[5, 10, 20, 30, 60, 120, 300, 600];
+
+/// Allocation latency histogram.
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative count of observations in each of the buckets.
+    buckets: Vec<u64>,
+    count: u64,
+    sum_ms: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, ms: u64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if ms <= le * 1000 {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum_ms += ms;
+    }
+}
+
+/// Metrics derived from the events of operations and the currently allocated
+/// nodes, rendered in the Prometheus text exposition format. Counters are
+/// updated as events are recorded, gauges are set from the list of nodes.
+#[derive(Default)]
+pub struct Metrics {
+    /// Count of operations by operation and outcome.
+    operations: BTreeMap<(String, String), u64>,
+    /// Count of allocations by system and outcome.
+    allocations: BTreeMap<(String, String), u64>,
+    /// Count of failures by operation and error kind.
+    failures: BTreeMap<(String, String), u64>,
+    /// Latency of successful allocations by system.
+    latency: BTreeMap<String, Histogram>,
+    /// Count of nodes by system.
+    nodes: BTreeMap<String, u64>,
+    cpu: u64,
+    memory: u64,
+}
+
+fn operation_name(op: Operation) -> &'static str {
+    match op {
+        Operation::Allocate => "allocate",
+        Operation::Discard => "discard",
+        Operation::Cleanup => "cleanup",
+        Operation::Reap => "reap",
+    }
+}
+
+fn outcome_name(outcome: Outcome) -> &'static str {
+    match outcome {
+        Outcome::Success => "success",
+        Outcome::Failure => "failure",
+    }
+}
+
+impl Metrics {
+    pub fn new(events: &[Event], nodes: &[allocator::NodeInfo]) -> Self {
+        let mut m = Metrics::default();
+        for event in events {
+            m.record(event);
+        }
+        m.set_nodes(nodes);
+        m
+    }
+
+    /// Update the counters with an event.
+    pub fn record(&mut self, event: &Event) {
+        let op = operation_name(event.operation).to_string();
+        let outcome = outcome_name(event.outcome).to_string();
+
+        *self
+            .operations
+            .entry((op.clone(), outcome.clone()))
+            .or_default() += 1;
+
+        if event.outcome == Outcome::Failure {
+            let kind = event.error_kind.as_deref().unwrap_or("unknown");
+            *self.failures.entry((op, kind.to_string())).or_default() += 1;
+        }
+
+        if event.operation == Operation::Allocate {
+            let system = event.system.as_deref().unwrap_or_default().to_string();
+            if event.outcome == Outcome::Success {
+                self.latency
+                    .entry(system.clone())
+                    .or_default()
+                    .observe(event.duration_ms);
+            }
+            *self.allocations.entry((system, outcome)).or_default() += 1;
+        }
+    }
+
+    /// Set the gauges from the currently allocated nodes.
+    pub fn set_nodes(&mut self, nodes: &[allocator::NodeInfo]) {
+        self.nodes.clear();
+        self.cpu = 0;
+        self.memory = 0;
+        for node in nodes {
+            let system = node
+                .node
+                .as_ref()
+                .map(|n| n.system.clone())
+                .unwrap_or_else(|| "unknown".to_string());
+            *self.nodes.entry(system).or_default() += 1;
+            self.cpu += u64::from(node.cpu);
+            self.memory += node.memory;
+        }
+    }
+
+    /// Render the metrics in Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "spread_adhoc_operations_total",
+            "counter",
+            "Operations on nodes by outcome.",
+        );
+        for ((op, outcome), count) in &self.operations {
+            let _ = writeln!(
+                out,
+                "spread_adhoc_operations_total{{operation=\"{}\",outcome=\"{}\"}} {}",
+                escape(op),
+                escape(outcome),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "spread_adhoc_allocations_total",
+            "counter",
+            "Allocations by system and outcome.",
+        );
+        for ((system, outcome), count) in &self.allocations {
+            let _ = writeln!(
+                out,
+                "spread_adhoc_allocations_total{{system=\"{}\",outcome=\"{}\"}} {}",
+                escape(system),
+                escape(outcome),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "spread_adhoc_failures_total",
+            "counter",
+            "Failed operations by error kind.",
+        );
+        for ((op, kind), count) in &self.failures {
+            let _ = writeln!(
+                out,
+                "spread_adhoc_failures_total{{operation=\"{}\",kind=\"{}\"}} {}",
+                escape(op),
+                escape(kind),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "spread_adhoc_allocation_duration_seconds",
+            "histogram",
+            "Duration of successful allocations by system.",
+        );
+        for (system, h) in &self.latency {
+            let system = escape(system);
+            for (le, count) in LATENCY_BUCKETS.iter().zip(&h.buckets) {
+                let _ = writeln!(
+                    out,
+                    "spread_adhoc_allocation_duration_seconds_bucket{{system=\"{}\",le=\"{}\"}} {}",
+                    system, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "spread_adhoc_allocation_duration_seconds_bucket{{system=\"{}\",le=\"+Inf\"}} {}",
+                system, h.count
+            );
+            let _ = writeln!(
+                out,
+                "spread_adhoc_allocation_duration_seconds_sum{{system=\"{}\"}} {}",
+                system,
+                h.sum_ms as f64 / 1000.0
+            );
+            let _ = writeln!(
+                out,
+                "spread_adhoc_allocation_duration_seconds_count{{system=\"{}\"}} {}",
+                system, h.count
+            );
+        }
+
+        header(
+            &mut out,
+            "spread_adhoc_nodes",
+            "gauge",
+            "Currently allocated nodes by system.",
+        );
+        for (system, count) in &self.nodes {
+            let _ = writeln!(
+                out,
+                "spread_adhoc_nodes{{system=\"{}\"}} {}",
+                escape(system),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "spread_adhoc_cpu_in_use",
+            "gauge",
+            "CPUs assigned to allocated nodes.",
+        );
+        let _ = writeln!(out, "spread_adhoc_cpu_in_use {}", self.cpu);
+
+        header(
+            &mut out,
+            "spread_adhoc_memory_in_use_bytes",
+            "gauge",
+            "Memory assigned to allocated nodes.",
+        );
+        let _ = writeln!(out, "spread_adhoc_memory_in_use_bytes {}", self.memory);
+
+        out
+    }
+}
+
+/// Sink updating metrics with events, before passing them on to another sink.
+pub struct MetricsSink {
+    metrics: Arc<Mutex<Metrics>>,
+    inner: Box<dyn EventSink>,
+}
+
+impl MetricsSink {
+    pub fn new(metrics: Arc<Mutex<Metrics>>, inner: Box<dyn EventSink>) -> Self {
+        MetricsSink { metrics, inner }
+    }
+}
+
+impl EventSink for MetricsSink {
+    fn record(&mut self, event: &Event) {
+        self.metrics
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(event);
+        self.inner.record(event);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value.
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve metrics over HTTP, one connection at a time. Metrics are obtained
+/// through a callback for each request.
+pub fn serve<F>(listener: TcpListener, mut metrics: F) -> io::Result<()>
+where
+    F: FnMut() -> String,
+{
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = handle_connection(stream, &mut metrics) {
+                    log::warn!("cannot handle metrics request: {}", err);
+                }
+            }
+            Err(err) => log::warn!("cannot accept connection: {}", err),
+        }
+    }
+    Ok(())
+}
+
+fn handle_connection<F>(mut stream: TcpStream, metrics: &mut F) -> io::Result<()>
+where
+    F: FnMut() -> String,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // skip the headers
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    log::debug!("metrics request: {}", request.trim());
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::thread;
+
+    use super::*;
+
+    fn allocation(system: &str, duration_ms: u64, error_kind: Option<&str>) -> Event {
+        Event {
+            operation: Operation::Allocate,
+            started: "2025-01-12T16:55:38.000Z".to_string(),
+            finished: "2025-01-12T16:55:40.000Z".to_string(),
+            duration_ms,
+            system: Some(system.to_string()),
+            instance: None,
+            address: None,
+            phases: vec![],
+            outcome: if error_kind.is_some() {
+                Outcome::Failure
+            } else {
+                Outcome::Success
+            },
+            error: error_kind.map(|_| "boom".to_string()),
+            error_kind: error_kind.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_metrics_sink() {
+        let metrics = Arc::new(Mutex::new(Metrics::new(
+            &[allocation("ubuntu", 8000, None)],
+            &[],
+        )));
+        let mut sink = MetricsSink::new(metrics.clone(), Box::new(crate::audit::NullSink));
+        sink.record(&allocation("ubuntu", 25000, None));
+        sink.record(&allocation("fedora", 0, Some("timeout")));
+
+        let mut m = metrics.lock().unwrap();
+        assert_eq!(
+            m.allocations,
+            BTreeMap::from([
+                (("fedora".to_string(), "failure".to_string()), 1),
+                (("ubuntu".to_string(), "success".to_string()), 2),
+            ])
+        );
+        assert_eq!(
+            m.failures,
+            BTreeMap::from([(("allocate".to_string(), "timeout".to_string()), 1)])
+        );
+        assert_eq!(m.latency["ubuntu"].sum_ms, 33000);
+
+        // gauges are replaced, not accumulated
+        let node = allocator::NodeInfo {
+            name: "ubuntu-1234".to_string(),
+            node: None,
+            cpu: 2,
+            memory: 1024,
+        };
+        m.set_nodes(&[node.clone(), node.clone()]);
+        m.set_nodes(&[node]);
+        assert_eq!(m.nodes, BTreeMap::from([("unknown".to_string(), 1)]));
+        assert_eq!((m.cpu, m.memory), (2, 1024));
+    }
+
+    #[test]
+    fn test_metrics_over_http() {
+        let events = vec![
+            allocation("ubuntu", 8000, None),
+            allocation("ubuntu", 25000, None),
+            allocation("ubuntu", 0, Some("capacity")),
+        ];
+        let nodes = vec![allocator::NodeInfo {
+            name: "ubuntu-1234".to_string(),
+            node: Some(allocator::Node {
+                name: "ubuntu-1234".to_string(),
+                system: "ubuntu".to_string(),
+                addr: [10, 0, 0, 1].into(),
+                ssh_port: 22,
+                allocated_at: None,
+            }),
+            cpu: 4,
+            memory: 8 * 1024 * 1024 * 1024,
+        }];
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind");
+        let addr = listener.local_addr().expect("cannot get address");
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().expect("cannot accept");
+                handle_connection(stream, &mut || Metrics::new(&events, &nodes).render())
+                    .expect("cannot handle");
+            }
+        });
+
+        let get = |path: &str| {
+            let mut client = TcpStream::connect(addr).expect("cannot connect");
+            write!(client, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)
+                .expect("cannot write");
+            let mut response = String::new();
+            client
+                .read_to_string(&mut response)
+                .expect("cannot read response");
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        for line in [
+            "spread_adhoc_operations_total{operation=\"allocate\",outcome=\"success\"} 2",
+            "spread_adhoc_allocations_total{system=\"ubuntu\",outcome=\"failure\"} 1",
+            "spread_adhoc_failures_total{operation=\"allocate\",kind=\"capacity\"} 1",
+            "spread_adhoc_allocation_duration_seconds_bucket{system=\"ubuntu\",le=\"5\"} 0",
+            "spread_adhoc_allocation_duration_seconds_bucket{system=\"ubuntu\",le=\"10\"} 1",
+            "spread_adhoc_allocation_duration_seconds_bucket{system=\"ubuntu\",le=\"30\"} 2",
+            "spread_adhoc_allocation_duration_seconds_bucket{system=\"ubuntu\",le=\"+Inf\"} 2",
+            "spread_adhoc_allocation_duration_seconds_sum{system=\"ubuntu\"} 33",
+            "spread_adhoc_nodes{system=\"ubuntu\"} 1",
+            "spread_adhoc_cpu_in_use 4",
+            "spread_adhoc_memory_in_use_bytes 8589934592",
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "missing {} in:\n{}",
+                line,
+                response
+            );
+        }
+
+        let response = get("/other");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        server.join().expect("server failed");
+    }
+}
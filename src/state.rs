@@ -0,0 +1,113 @@
+// SPDX-FileCopyrightText: 2025 Maciej Borzecki <maciek.borzecki@gmail.com>
+//
+// SPDX-License-Identifier: MIT
+
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::lock::FileLock;
+
+/// Persistent state kept in a JSON file.
+pub struct StateStore<T> {
+    path: PathBuf,
+    _state: PhantomData<T>,
+}
+
+impl<T> StateStore<T>
+where
+    T: Serialize + DeserializeOwned + Default,
+{
+    pub fn new(path: &Path) -> Self {
+        StateStore {
+            path: path.to_path_buf(),
+            _state: PhantomData,
+        }
+    }
+
+    /// Load the state. Returns the default state if the file does not exist
+    /// yet.
+    pub fn load(&self) -> io::Result<T> {
+        match fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+                io::Error::other(format!(
+                    "cannot parse state file {}: {}",
+                    self.path.display(),
+                    e
+                ))
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Load the state, apply a modification and write it back. Updates are
+    /// serialized across processes.
+    pub fn update<F, V>(&self, f: F) -> io::Result<V>
+    where
+        F: FnOnce(&mut T) -> V,
+    {
+        let _lock = FileLock::acquire(&self.path.with_extension("lock"))?;
+        let mut state = self.load()?;
+        let res = f(&mut state);
+        self.store(&state)?;
+        Ok(res)
+    }
+
+    fn store(&self, state: &T) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // write to a temporary file first, so that the state is replaced
+        // atomically
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_concurrent_updates() {
+        let dir = std::env::temp_dir().join(format!(
+            "spread-adhoc-allocator-test-state-{}",
+            std::process::id()
+        ));
+        let path = dir.join("state.json");
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let store = StateStore::<HashMap<String, u32>>::new(&path);
+                    for _ in 0..10 {
+                        store
+                            .update(|st| *st.entry("counter".to_string()).or_default() += 1)
+                            .expect("cannot update");
+                    }
+                })
+            })
+            .collect();
+        for w in workers {
+            w.join().expect("worker failed");
+        }
+
+        let state = StateStore::<HashMap<String, u32>>::new(&path)
+            .load()
+            .expect("cannot load");
+        assert_eq!(state.get("counter"), Some(&80));
+
+        fs::remove_dir_all(&dir).expect("cannot clean up");
+    }
+}